@@ -129,7 +129,7 @@ void segments_init(void) {
     // System calls get special handling.
     // Note that the last argument is '3'.  This means that unprivileged
     // (level-3) applications may generate these interrupts.
-    for (unsigned i = INT_SYS; i < INT_SYS + 16; ++i) {
+    for (unsigned i = INT_SYS; i < INT_SYS + 24; ++i) {
         set_gate(&interrupt_descriptors[i], X86GATE_INTERRUPT, 3,
                  (uint64_t) sys_int_handlers[i - INT_SYS]);
     }
@@ -272,6 +272,30 @@ int physical_memory_isreserved(uintptr_t pa) {
 }
 
 
+// cmos_memory_size()
+//    Returns the size of physical memory in bytes. The BIOS records the
+//    amount of extended memory in CMOS, both in KB above 1MB (registers
+//    0x30-0x31, at most 64MB) and in 64KB blocks above 16MB (registers
+//    0x34-0x35).
+
+#define IO_CMOS_INDEX   0x70
+#define IO_CMOS_DATA    0x71
+
+static unsigned cmos_read(int reg) {
+    outb(IO_CMOS_INDEX, reg);
+    return inb(IO_CMOS_DATA);
+}
+
+size_t cmos_memory_size(void) {
+    size_t blocks_above_16mb = cmos_read(0x34) | (cmos_read(0x35) << 8);
+    if (blocks_above_16mb != 0) {
+        return 0x1000000 + (blocks_above_16mb << 16);
+    }
+    size_t kb_above_1mb = cmos_read(0x30) | (cmos_read(0x31) << 8);
+    return EXTPHYSMEM + (kb_above_1mb << 10);
+}
+
+
 // pci_make_configaddr(bus, slot, func)
 //    Construct a PCI configuration space address from parts.
 
@@ -343,6 +367,17 @@ void reboot(void) {
 }
 
 
+// qemu_exit(status)
+//    Exit QEMU through the `isa-debug-exit` device. Writes to an I/O port
+//    nobody claims are ignored, so without the device this returns.
+
+#define IO_QEMU_EXIT    0xF4
+
+void qemu_exit(int status) {
+    outl(IO_QEMU_EXIT, status);
+}
+
+
 // process_init(p, flags)
 //    Initialize special-purpose registers for process `p`.
 
@@ -614,10 +649,12 @@ int check_keyboard(void) {
 
 
 // fail
-//    Loop until user presses Control-C, then poweroff.
+//    Report the failure to QEMU's exit device, if any. Otherwise loop until
+//    user presses Control-C, then poweroff.
 
 static void fail(void) __attribute__((noreturn));
 static void fail(void) {
+    qemu_exit(QEMU_EXIT_FAILURE);
     while (1) {
         check_keyboard();
     }
@@ -25,6 +25,11 @@ typedef struct proc {
     procstate_t p_state;                // process state (see above)
     x86_64_pagetable* p_pagetable;      // process's page table
     uint8_t display_status;             // process's display status for memviewer
+    pid_t p_parent;                     // parent's process ID (0 if none)
+    uintptr_t p_heap_start;             // first heap address (set by loader)
+    uintptr_t p_brk;                    // current program break
+    uintptr_t p_stack_bottom;           // lowest stack address (a guard page is below)
+    rlimits p_rlimits;                  // resource limits
 } proc;
 
 #define NPROC 16                // maximum number of processes
@@ -34,6 +39,8 @@ typedef struct proc {
 #define KERNEL_START_ADDR       0x40000
 // Top of the kernel stack
 #define KERNEL_STACK_TOP        0x80000
+// Size of the kernel stack, which ends at KERNEL_STACK_TOP
+#define KERNEL_STACK_SIZE       0x4000
 
 #define CONSOLE_ADDR		((uintptr_t)console)
 
@@ -78,7 +85,8 @@ extern x86_64_pagetable* kernel_pagetable;
 //
 //    Typically `perm` is a combination of `PTE_P` (the memory is Present),
 //    `PTE_W` (the memory is Writable), and `PTE_U` (the memory may be
-//    accessed by User applications). If `!(perm & PTE_P)`, `pa` is ignored.
+//    accessed by User applications), plus `PTE_NX` if the memory must not
+//    be executed. If `!(perm & PTE_P)`, `pa` is ignored.
 //
 //    Sometimes mapping memory will require allocating new page tables. The
 //    `allocator` function should return a newly allocated page, or NULL
@@ -87,7 +95,7 @@ extern x86_64_pagetable* kernel_pagetable;
 //    Returns 0 if the map succeeds, -1 if it fails because a required
 //    page table was not allocated.
 int virtual_memory_map(x86_64_pagetable* pagetable, uintptr_t va,
-                       uintptr_t pa, size_t sz, int perm);
+                       uintptr_t pa, size_t sz, x86_64_pageentry_t perm);
 
 // virtual_memory_lookup(pagetable, va)
 //    Returns information about the mapping of the virtual address `va` in
@@ -106,6 +114,12 @@ int assign_physical_page(uintptr_t addr, int8_t owner);
 //    Returns non-zero iff `pa` is a reserved physical address.
 int physical_memory_isreserved(uintptr_t pa);
 
+
+// cmos_memory_size()
+//    Returns the size of physical memory in bytes, as recorded in CMOS by
+//    the BIOS. Used when the boot loader passes no memory map.
+size_t cmos_memory_size(void);
+
 // set_pagetable
 //    Change page table. lcr3() is the hardware instruction;
 //    set_pagetable() additionally checks that important kernel procedures are
@@ -125,6 +139,15 @@ void poweroff(void) __attribute__((noreturn));
 //    Reboot the virtual machine.
 void reboot(void) __attribute__((noreturn));
 
+// qemu_exit(status)
+//    Exit QEMU through the `isa-debug-exit` device (see `make check`).
+//    QEMU's exit status becomes `(status << 1) | 1`. Returns if the device
+//    is not present, which is the case for interactive runs.
+void qemu_exit(int status);
+
+#define QEMU_EXIT_SUCCESS       0x10    // QEMU exit status 33
+#define QEMU_EXIT_FAILURE       0x11    // QEMU exit status 35
+
 // exception_return
 //    Return from an exception to user mode: load the registers in `reg`
 //    and start the process back up. Defined in k-exception.S.
@@ -173,11 +196,18 @@ void process_init(proc* p, int flags);
 #define PROCINIT_DISABLE_INTERRUPTS     0x02
 
 
+// program_lookup(name, len)
+//    Return the number of the program called `[name, name + len)`, for
+//    `program_load`, or -1 if there is none. A program is named after its
+//    source without `p-`, such as "allocator" or "fork".
+int program_lookup(const char* name, size_t len);
+
 // program_load(p, programnumber)
-//    Load the code corresponding to program `programnumber` into the process
-//    `p` and set `p->p_registers.reg_eip` to its entry point. Calls
-//    `assign_physical_page` as required. Returns 0 on success and
-//    -1 on failure (e.g. out-of-memory). `allocator` is passed to
+//    Load the code corresponding to program `programnumber` (see
+//    `program_lookup`) into the process `p` and set `p->p_registers.reg_eip`
+//    to its entry point. Calls `assign_physical_page` as required. Returns
+//    0 on success, -EINVAL for an unknown program, -ENOEXEC for a malformed
+//    ELF image, and -ENOMEM if out of memory. `allocator` is passed to
 //    `virtual_memory_map`.
 int program_load(proc* p, int programnumber,
                  x86_64_pagetable* (*allocator)(void));
@@ -61,7 +61,9 @@ INFERRED_QEMU := $(shell if which qemu-system-x86_64 2>/dev/null | grep ^/ >/dev
 	elif grep 16 /etc/fedora-release >/dev/null 2>&1; \
 	then echo qemu; else echo qemu-system-x86_64; fi)
 QEMU ?= $(INFERRED_QEMU)
-QEMUOPT	= -net none -parallel file:log.txt
+QEMUOPT	= -net none -parallel file:log.txt $(if $(QEMUMEM),-m $(QEMUMEM))
+# isa-debug-exit lets the kernel end QEMU with a status (see `qemu_exit`)
+QEMUEXIT = -device isa-debug-exit,iobase=0xf4,iosize=0x04
 QEMUCONSOLE ?= $(if $(DISPLAY),,1)
 QEMUDISPLAY = $(if $(QEMUCONSOLE),console,graphic)
 
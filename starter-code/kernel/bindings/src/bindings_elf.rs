@@ -1,6 +1,19 @@
 pub const ELF_MAGIC: u32 = 1179403647;
 pub const ELF_PTYPE_LOAD: u32 = 1;
 
+// ElfHeader::e_elf (the ELF identification after the magic number)
+pub const ELF_CLASS_64: u8 = 2;         // e_elf[0]: 64-bit objects
+pub const ELF_DATA_LSB: u8 = 1;         // e_elf[1]: little endian
+
+// ElfHeader::e_type and ElfHeader::e_machine
+pub const ELF_TYPE_EXEC: u16 = 2;
+pub const ELF_MACHINE_X86_64: u16 = 62;
+
+// ElfProgram::p_flags
+pub const ELF_PFLAG_EXEC: u32 = 1;
+pub const ELF_PFLAG_WRITE: u32 = 2;
+pub const ELF_PFLAG_READ: u32 = 4;
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 pub struct ElfProgram {
@@ -23,6 +23,8 @@ pub const NPROC: usize = 16;
 pub const KERNEL_START_ADDR: u64 = 0x40000;
 // Top of the kernel stack
 pub const KERNEL_STACK_TOP: u64 = 0x80000;
+// Size of the kernel stack, which ends at KERNEL_STACK_TOP
+pub const KERNEL_STACK_SIZE: u64 = 0x4000;
 
 // First application-accessible address
 pub const PROC_START_ADDR: u64 = 0x100000;
@@ -35,6 +37,10 @@ pub const NPAGES: u64 = MEMSIZE_PHYSICAL / PAGESIZE;
 // Virtual memory size
 pub const MEMSIZE_VIRTUAL: u64 = 0x300000;
 
+// QEMU isa-debug-exit codes for `qemu_exit`; QEMU exits with `(code << 1) | 1`
+pub const QEMU_EXIT_SUCCESS: i32 = 0x10;    // QEMU exit status 33
+pub const QEMU_EXIT_FAILURE: i32 = 0x11;    // QEMU exit status 35
+
 // Hardware interrupt numbers
 pub const INT_HARDWARE: u32 = 32;
-pub const INT_TIMER: u32 = INT_HARDWARE + 0;
+pub const INT_TIMER: u32 = INT_HARDWARE;
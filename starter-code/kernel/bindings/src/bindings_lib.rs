@@ -1,7 +1,11 @@
-
 // Console printing
 
-// current position of the cursor (80 * ROW + COL)
+pub const CONSOLE_COLUMNS: usize = 80;
+pub const CONSOLE_ROWS: usize = 25;
+
 extern "C" {
+    // current position of the cursor (80 * ROW + COL)
     pub static mut cursorpos: i32;
+    // the CGA console memory: one character and color per position
+    pub static mut console: [u16; CONSOLE_ROWS * CONSOLE_COLUMNS];
 }
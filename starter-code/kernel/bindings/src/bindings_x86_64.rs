@@ -2,6 +2,7 @@
 //
 //   Contents:
 //   - Memory and interrupt constants.
+//   - Address types: PhysAddr, VirtAddr, PageNumber and PageTableEntry.
 //   - x86_registers: Used in process descriptors to store x86 registers.
 //   - x86 functions: C function wrappers for useful x86 instructions.
 //   - Hardware structures: C structures and constants for initializing
@@ -18,30 +19,326 @@ pub type PidT = ::core::ffi::c_int;
 
 pub const PAGESIZE: u64 = 4096;
 pub const PAGE_OFF_MASK: usize = PAGESIZE as usize - 1;
-const PAGEOFFBITS: usize = 12;  // # bits in page offset
+pub const PAGEOFFBITS: usize = 12;  // # bits in page offset
+pub const PAGEINDEXBITS: usize = 9; // # bits in a page index level
 
 pub fn page_offset(addr: *const u8) -> usize {
     (addr as usize) & PAGE_OFF_MASK
 }
 
-pub fn page_number(ptr: *const u8) -> usize {
-    (ptr as usize) >> PAGEOFFBITS
+// ADDRESS TYPES
+//
+//    Physical addresses, virtual addresses and page numbers are all
+//    integers, and page table entries hold a physical address next to flag
+//    bits, so it is easy to use one where another was meant. These wrappers
+//    keep them apart. Each is `#[repr(transparent)]`, so it has the layout
+//    of the integer it wraps and can cross into C unchanged.
+
+// Largest physical address width the x86-64 architecture allows.
+pub const PHYSADDR_BITS: usize = 52;
+
+// Virtual address width with 4-level paging; bits 47..63 must be equal.
+pub const VIRTADDR_BITS: usize = 48;
+
+// PhysAddr
+//    A physical address below 2^52, the most an entry can hold.
+
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysAddr(usize);
+
+impl PhysAddr {
+    pub const ZERO: PhysAddr = PhysAddr(0);
+
+    // PhysAddr::new(addr)
+    //    Returns `addr` as a physical address, or None if it is too wide.
+
+    pub const fn new(addr: usize) -> Option<Self> {
+        if addr >> PHYSADDR_BITS == 0 { Some(PhysAddr(addr)) } else { None }
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+
+    // PhysAddr::from_ptr(ptr), as_ptr()
+    //    Convert between physical addresses and pointers where physical
+    //    memory is identity-mapped, as it is in the kernel.
+
+    pub fn from_ptr<T>(ptr: *const T) -> Option<Self> {
+        PhysAddr::new(ptr as usize)
+    }
+
+    pub const fn as_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    pub const fn page_offset(self) -> usize {
+        self.0 & PAGE_OFF_MASK
+    }
+
+    pub const fn is_page_aligned(self) -> bool {
+        self.page_offset() == 0
+    }
+
+    // page_number()
+    //    Returns the number of the page containing this address.
+
+    pub const fn page_number(self) -> PageNumber {
+        PageNumber(self.0 >> PAGEOFFBITS)
+    }
+
+    // add(offset)
+    //    Returns the address `offset` bytes further on, or None if that is
+    //    not a physical address.
+
+    pub const fn add(self, offset: usize) -> Option<Self> {
+        match self.0.checked_add(offset) {
+            Some(addr) => PhysAddr::new(addr),
+            None => None,
+        }
+    }
 }
 
-pub fn pte_addr(pageentry: usize) -> usize {
-    pageentry & !0xFFF
+impl From<PhysAddr> for usize {
+    fn from(pa: PhysAddr) -> usize {
+        pa.0
+    }
+}
+
+// VirtAddr
+//    A canonical virtual address: bits 47..63 are all equal.
+
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtAddr(usize);
+
+impl VirtAddr {
+    // VirtAddr::new(addr)
+    //    Returns `addr` as a virtual address, or None if it is not
+    //    canonical.
+
+    pub const fn new(addr: usize) -> Option<Self> {
+        let high = (addr as isize) >> (VIRTADDR_BITS - 1);
+        if high == 0 || high == -1 { Some(VirtAddr(addr)) } else { None }
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+
+    pub const fn page_offset(self) -> usize {
+        self.0 & PAGE_OFF_MASK
+    }
+
+    pub const fn is_page_aligned(self) -> bool {
+        self.page_offset() == 0
+    }
+
+    // index(level)
+    //    Returns the index of this address in the page table at `level`,
+    //    where level 0 is the top-level (L4) page table and level 3 is the
+    //    L1 page table.
+
+    pub const fn index(self, level: usize) -> usize {
+        (self.0 >> (PAGEOFFBITS + (3 - level) * PAGEINDEXBITS)) & 0x1FF
+    }
+
+    // add(offset)
+    //    Returns the address `offset` bytes further on, or None if that is
+    //    not a canonical address.
+
+    pub const fn add(self, offset: usize) -> Option<Self> {
+        match self.0.checked_add(offset) {
+            Some(addr) => VirtAddr::new(addr),
+            None => None,
+        }
+    }
+
+    // page_down(), page_up()
+    //    Round down or up to a page boundary. page_up() returns None if the
+    //    boundary is not a canonical address.
+
+    pub const fn page_down(self) -> Self {
+        VirtAddr(self.0 & !PAGE_OFF_MASK)
+    }
+
+    pub const fn page_up(self) -> Option<Self> {
+        match self.0.checked_add(PAGE_OFF_MASK) {
+            Some(addr) => VirtAddr::new(addr & !PAGE_OFF_MASK),
+            None => None,
+        }
+    }
+}
+
+impl From<VirtAddr> for usize {
+    fn from(va: VirtAddr) -> usize {
+        va.0
+    }
+}
+
+// l4_index(va), l3_index(va), l2_index(va), l1_index(va)
+//    Return the index of `va` in the page table at each level.
+
+pub const fn l4_index(va: VirtAddr) -> usize {
+    va.index(0)
+}
+
+pub const fn l3_index(va: VirtAddr) -> usize {
+    va.index(1)
+}
+
+pub const fn l2_index(va: VirtAddr) -> usize {
+    va.index(2)
+}
+
+pub const fn l1_index(va: VirtAddr) -> usize {
+    va.index(3)
+}
+
+// PageNumber
+//    The number of a physical page: its address divided by PAGESIZE.
+
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageNumber(usize);
+
+impl PageNumber {
+    // PageNumber::new(pn)
+    //    Returns `pn` as a page number, or None if its page would not have a
+    //    physical address.
+
+    pub const fn new(pn: usize) -> Option<Self> {
+        if pn >> (PHYSADDR_BITS - PAGEOFFBITS) == 0 { Some(PageNumber(pn)) } else { None }
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+
+    // address()
+    //    Returns the physical address of the start of the page.
+
+    pub const fn address(self) -> PhysAddr {
+        PhysAddr(self.0 << PAGEOFFBITS)
+    }
+}
+
+impl From<PageNumber> for usize {
+    fn from(pn: PageNumber) -> usize {
+        pn.0
+    }
+}
+
+// PageTableEntry
+//    An entry of an x86_64_pagetable: the physical address of a page or of
+//    the next level's table, and flag bits (PTE_P, PTE_W...) in bits 0..11
+//    and 63 (PTE_NX).
+//    The kernel may keep other information in entries that are not present
+//    (see swap.rs), so `from_bits` accepts any value.
+
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PageTableEntry(X86_64PageentryT);
+
+// Bits of an entry that hold a physical address.
+pub const PTE_ADDR_MASK: X86_64PageentryT = ((1 << PHYSADDR_BITS) - 1) & !PTE_FLAGS;
+
+impl PageTableEntry {
+    pub const EMPTY: PageTableEntry = PageTableEntry(0);
+
+    // PageTableEntry::new(pa, flags)
+    //    Returns an entry for the page at `pa` with `flags`, or None if `pa`
+    //    is not page-aligned or `flags` has bits outside PTE_FLAGS.
+
+    pub const fn new(pa: PhysAddr, flags: X86_64PageentryT) -> Option<Self> {
+        if !pa.is_page_aligned() || flags & !PTE_FLAGS != 0 {
+            return None;
+        }
+        Some(PageTableEntry(pa.0 as X86_64PageentryT | flags))
+    }
+
+    pub const fn from_bits(bits: X86_64PageentryT) -> Self {
+        PageTableEntry(bits)
+    }
+
+    pub const fn bits(self) -> X86_64PageentryT {
+        self.0
+    }
+
+    // addr()
+    //    Returns the physical address held in the entry.
+
+    pub const fn addr(self) -> PhysAddr {
+        PhysAddr((self.0 & PTE_ADDR_MASK) as usize)
+    }
+
+    pub const fn flags(self) -> X86_64PageentryT {
+        self.0 & PTE_FLAGS
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn is_present(self) -> bool {
+        self.0 & PTE_P != 0
+    }
+
+    pub const fn is_writable(self) -> bool {
+        self.0 & PTE_W != 0
+    }
+
+    pub const fn is_user(self) -> bool {
+        self.0 & PTE_U != 0
+    }
+
+    pub const fn is_huge(self) -> bool {
+        self.0 & PTE_PS != 0
+    }
+
+    // has(flags)
+    //    Returns true iff every bit of `flags` is set in the entry.
+
+    pub const fn has(self, flags: X86_64PageentryT) -> bool {
+        self.0 & flags == flags
+    }
+
+    // with_flags(set, clear)
+    //    Returns the entry with the bits of `clear` cleared and then those
+    //    of `set` set; the address is kept.
+
+    pub const fn with_flags(self, set: X86_64PageentryT, clear: X86_64PageentryT) -> Self {
+        PageTableEntry((self.0 & !clear) | set)
+    }
+
+    // next_table()
+    //    Returns the next level's page table if the entry is present and
+    //    does not map a large page. Only meaningful where physical memory
+    //    is identity-mapped, as it is in the kernel.
+
+    pub const fn next_table(self) -> Option<*mut x86_64_pagetable> {
+        if self.is_present() && !self.is_huge() {
+            Some(self.addr().as_ptr())
+        } else {
+            None
+        }
+    }
 }
 
 // Page table entry flags
-pub const PTE_FLAGS: X86_64PageentryT = 0xFFF;
+pub const PTE_FLAGS: X86_64PageentryT = 0xFFF | PTE_NX;
 // - Permission flags: define whether page is accessible
 pub const PTE_P: X86_64PageentryT = 1;      // entry is Present
 pub const PTE_W: X86_64PageentryT = 2;      // entry is Writeable
 pub const PTE_U: X86_64PageentryT = 4;      // entry is User-accessible
+pub const PTE_NX: X86_64PageentryT = 1 << 63; // entry is Not eXecutable (needs EFER.NXE)
 // - Accessed flags: automatically turned on by processor
 pub const PTE_A: X86_64PageentryT = 32;     // entry was Accessed (read/written)
 pub const PTE_D: X86_64PageentryT = 64;     // entry was Dirtied (written)
 pub const PTE_PS: X86_64PageentryT = 128;   // entry has a large Page Size
+// - Available to software: ignored by the processor
+pub const PTE_COW: X86_64PageentryT = 0x400; // copy-on-write page, mapped read-only
 // - There are other flags too!
 
 // Page fault error flags
@@ -49,6 +346,11 @@ pub const PTE_PS: X86_64PageentryT = 128;   // entry has a large Page Size
 pub const PFERR_PRESENT: u8 = 0x1;   // Fault happened due to a protection violation (rather than due to a missing page)
 pub const PFERR_WRITE: u8 = 0x2;     // Fault happened on a write
 pub const PFERR_USER: u8 = 0x4;      // Fault happened in an application (user mode) (rather than kernel)
+pub const PFERR_FETCH: u8 = 0x10;    // Fault happened on an instruction fetch (from a PTE_NX page)
+
+// Extended feature enable register, and its no-execute enable bit
+pub const MSR_IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_EFER_NXE: u64 = 0x800;
 
 extern "C" {
     pub fn c_panic(format: *const core::ffi::c_char, ...) -> !;
@@ -58,13 +360,13 @@ extern "C" {
 #[repr(align(4096))]
 #[derive(Debug, Copy, Clone)]
 pub struct x86_64_pagetable {
-    pub entry: [X86_64PageentryT; 512usize],
+    pub entry: [PageTableEntry; 512usize],
 }
 
 impl x86_64_pagetable {
     pub fn new() -> Self {
         x86_64_pagetable {
-            entry: [0; 512],
+            entry: [PageTableEntry::EMPTY; 512],
         }
     }
 
@@ -73,17 +375,16 @@ impl x86_64_pagetable {
     /// # Arguments
     /// * `index` - Index of the entry to set (0-511).
     /// * `value` - Value to set for the page table entry.
-    #[no_mangle]
     pub fn set_entry(
         &mut self, 
         index: usize, 
-        value: X86_64PageentryT,
+        value: PageTableEntry,
     ) {
         if index < 512 {
             self.entry[index] = value;
         } else {
             unsafe {
-                c_panic("Index out of bounds for x86_64_pagetable".as_ptr() as *const i8);
+                c_panic(c"Index out of bounds for x86_64_pagetable".as_ptr());
             }
         }
     }
@@ -92,8 +393,14 @@ impl x86_64_pagetable {
 unsafe impl Send for x86_64_pagetable {}
 unsafe impl Sync for x86_64_pagetable {}
 
+impl Default for x86_64_pagetable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[repr(C, packed)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default)]
 pub struct x86_64_registers {
     pub reg_rax: u64,
     pub reg_rcx: u64,
@@ -123,39 +430,6 @@ pub struct x86_64_registers {
     pub reg_padding3: [u16; 3usize],
 }
 
-impl Default for x86_64_registers {
-    fn default() -> Self {
-        x86_64_registers {
-            reg_rax: 0,
-            reg_rcx: 0,
-            reg_rdx: 0,
-            reg_rbx: 0,
-            reg_rbp: 0,
-            reg_rsi: 0,
-            reg_rdi: 0,
-            reg_r8: 0,
-            reg_r9: 0,
-            reg_r10: 0,
-            reg_r11: 0,
-            reg_r12: 0,
-            reg_r13: 0,
-            reg_r14: 0,
-            reg_r15: 0,
-            reg_fs: 0,
-            reg_gs: 0,
-            reg_intno: 0,
-            reg_err: 0,
-            reg_rip: 0,
-            reg_cs: 0,
-            reg_padding2: [0; 3],
-            reg_rflags: 0,
-            reg_rsp: 0,
-            reg_ss: 0,
-            reg_padding3: [0; 3],
-        }
-    }
-}
-
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct Proc {
@@ -164,6 +438,11 @@ pub struct Proc {
     pub p_state: ProcstateT,
     pub p_pagetable: *mut x86_64_pagetable,
     pub display_status: u8,
+    pub p_parent: PidT,             // parent's process ID (0 if none)
+    pub p_heap_start: usize,        // first heap address (set by loader)
+    pub p_brk: usize,               // current program break
+    pub p_stack_bottom: usize,      // lowest stack address (a guard page is below)
+    pub p_rlimits: Rlimits,         // resource limits
 }
 
 unsafe impl Send for Proc {}
@@ -177,19 +456,89 @@ impl Default for Proc {
             p_state: P_FREE,
             p_pagetable: core::ptr::null_mut(),
             display_status: 0,
+            p_parent: 0,
+            p_heap_start: 0,
+            p_brk: 0,
+            p_stack_bottom: 0,
+            p_rlimits: Rlimits::UNLIMITED,
         }
     }
 }
 
 impl Proc {
     pub fn new(pid: PidT, state: Procstate) -> Self {
-        let mut proc = Proc::default();
-        proc.p_pid = pid;
-        proc.p_state = state;
-        proc
+        Proc {
+            p_pid: pid,
+            p_state: state,
+            ..Proc::default()
+        }
     }
 }
 
+// Per-process resource limits (`struct rlimits` in lib.h)
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rlimits {
+    pub resident_pages: usize,  // RLIMIT_RSS: physical pages owned
+    pub heap_bytes: usize,      // RLIMIT_HEAP: size of the heap
+    pub children: usize,        // RLIMIT_NPROC: live child processes
+    pub stack_bytes: usize,     // RLIMIT_STACK: size of the stack
+}
+
+pub const RLIMIT_RSS: u64 = 0;
+pub const RLIMIT_HEAP: u64 = 1;
+pub const RLIMIT_NPROC: u64 = 2;
+pub const RLIMIT_STACK: u64 = 3;
+pub const RLIM_INFINITY: usize = usize::MAX;
+
+impl Rlimits {
+    pub const UNLIMITED: Rlimits = Rlimits {
+        resident_pages: RLIM_INFINITY,
+        heap_bytes: RLIM_INFINITY,
+        children: RLIM_INFINITY,
+        stack_bytes: RLIM_INFINITY,
+    };
+
+    // get(resource), get_mut(resource)
+    //    Return the limit for RLIMIT_* `resource`, or None if there is no
+    //    such resource.
+
+    pub fn get(&self, resource: u64) -> Option<usize> {
+        match resource {
+            RLIMIT_RSS => Some(self.resident_pages),
+            RLIMIT_HEAP => Some(self.heap_bytes),
+            RLIMIT_NPROC => Some(self.children),
+            RLIMIT_STACK => Some(self.stack_bytes),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, resource: u64) -> Option<&mut usize> {
+        match resource {
+            RLIMIT_RSS => Some(&mut self.resident_pages),
+            RLIMIT_HEAP => Some(&mut self.heap_bytes),
+            RLIMIT_NPROC => Some(&mut self.children),
+            RLIMIT_STACK => Some(&mut self.stack_bytes),
+            _ => None,
+        }
+    }
+}
+
+// A process's memory usage, as returned by `sys_memstats`
+// (`struct memstats` in lib.h)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct MemStats {
+    pub resident_pages: usize,
+    pub heap_bytes: usize,
+    pub children: usize,
+    pub limits: Rlimits,
+    pub free_pages: usize,
+    pub total_pages: usize,
+    pub swap_ins: usize,
+    pub swap_outs: usize,
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 pub struct VAMapping {
@@ -198,6 +547,50 @@ pub struct VAMapping {
     pub perm: core::ffi::c_int,
 }
 
+impl VAMapping {
+    // page()
+    //    Returns the physical address of the mapped page (without the
+    //    offset in `pa`), or None if the address was not mapped.
+
+    pub fn page(&self) -> Option<PhysAddr> {
+        let pa = self.pa;
+        if self.pn < 0 {
+            return None;
+        }
+        PhysAddr::new(pa & !PAGE_OFF_MASK)
+    }
+}
+
+// What `copy_pagetable` does with the pages mapped in a region
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CopyAction {
+    Share = 0,  // map the same physical page
+    Copy = 1,   // map a new page with the same contents
+    Cow = 2,    // share the page read-only and copy-on-write
+}
+
+// A region of virtual memory `[start, end)` and what `copy_pagetable`
+// does with its pages
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CopyRegion {
+    pub start: usize,
+    pub end: usize,
+    pub action: CopyAction,
+}
+
+// A run of pages mapped at `[start, end)` to physical addresses from `pa`
+// with permissions `perm`, as `virtual_memory_mappings` reports it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MappingRun {
+    pub start: usize,
+    pub end: usize,
+    pub pa: usize,
+    pub perm: X86_64PageentryT,
+}
+
 // Interrupt numbers
 pub const INT_DIVIDE: u32 = 0x0;        // Divide error
 pub const INT_DEBUG: u32 = 0x1;         // Debug exception
@@ -223,16 +616,114 @@ pub const INT_SYS_MAPPING: u32 = 54;
 pub const INT_SYS_MEM_TOG: u32 = 56;
 pub const INT_SYS_BRK: u32 = 57;
 pub const INT_SYS_SBRK: u32 = 58;
+pub const INT_SYS_TEST_EXIT: u32 = 59;
+pub const INT_SYS_SETRLIMIT: u32 = 60;
+pub const INT_SYS_MEMSTATS: u32 = 61;
+pub const INT_SYS_MMAP: u32 = 62;
+pub const INT_SYS_MUNMAP: u32 = 63;
+pub const INT_SYS_MPROTECT: u32 = 64;
+pub const INT_SYS_SHM_CREATE: u32 = 65;
+pub const INT_SYS_SHM_ATTACH: u32 = 66;
+pub const INT_SYS_SHM_DETACH: u32 = 67;
+pub const INT_SYS_MAPS: u32 = 68;
+
+// sys_mmap and sys_mprotect protections, sys_mmap flags
+pub const PROT_NONE: u64 = 0x0;
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+// sys_shm_create key that always makes a new segment
+pub const SHM_PRIVATE: u64 = 0;
+
+// System call error numbers (returned negated)
+pub const EPERM: i32 = 1;       // not allowed
+pub const ENOEXEC: i32 = 8;     // not a valid program
+pub const EAGAIN: i32 = 11;     // no free process slot
+pub const ENOMEM: i32 = 12;     // out of memory
+pub const EFAULT: i32 = 14;     // bad user address
+pub const EINVAL: i32 = 22;     // invalid argument
 
 pub const NPAGETABLEENTRIES: u32 = 512;
 
+/// Returns the address whose access caused the last page fault.
+///
+/// # Safety
+///
+/// Must run in ring 0; reading CR2 from user mode faults.
 #[inline(always)]
 pub unsafe fn rcr2() -> u64 {
     let mut val: u64;
-    asm!("movq %%cr2, {0}", out(reg) val);
+    asm!(
+        "movq %cr2, {0}",
+        out(reg) val,
+        options(att_syntax, nostack, preserves_flags)
+    );
     val
 }
 
+/// Returns the physical address of the active top-level page table.
+///
+/// # Safety
+///
+/// Must run in ring 0; reading CR3 from user mode faults.
+#[inline(always)]
+pub unsafe fn rcr3() -> usize {
+    let mut val: usize;
+    asm!(
+        "movq %cr3, {0}",
+        out(reg) val,
+        options(att_syntax, nostack, preserves_flags)
+    );
+    val
+}
+
+/// Reads model-specific register `msr`.
+///
+/// # Safety
+///
+/// Must run in ring 0, and `msr` must name a register this CPU has;
+/// otherwise the CPU raises a general protection fault.
+#[inline(always)]
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!(
+        "rdmsr",
+        in("ecx") msr,
+        out("eax") low,
+        out("edx") high,
+        options(nostack, preserves_flags)
+    );
+    ((high as u64) << 32) | low as u64
+}
+
+/// Writes `val` to model-specific register `msr`.
+///
+/// # Safety
+///
+/// Must run in ring 0, and `val` must be valid for `msr`. Registers such
+/// as EFER change how the CPU translates and executes code, so the caller
+/// must make sure the rest of the kernel still works afterwards.
+#[inline(always)]
+pub unsafe fn wrmsr(msr: u32, val: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") val as u32,
+        in("edx") (val >> 32) as u32,
+        options(nostack, preserves_flags)
+    );
+}
+
+/// Makes the page table at physical address `val` active and flushes the
+/// TLB of every non-global translation.
+///
+/// # Safety
+///
+/// Must run in ring 0. `val` must be the page-aligned physical address of
+/// an L4 page table that maps the running kernel code, stack and data.
 #[inline(always)]
 pub unsafe fn lcr3(val: usize) {
     // Prevent compiler reordering
@@ -241,6 +732,68 @@ pub unsafe fn lcr3(val: usize) {
     core::arch::asm!(
         "movq {0}, %cr3",
         in(reg) val,
-        options(nostack, preserves_flags)
+        options(att_syntax, nostack, preserves_flags)
+    );
+}
+
+// invlpg(va)
+//    Drop the TLB entry for the page containing `va` in the active page
+//    table, and any paging-structure caches that translate it. Needed after
+//    changing or removing a present mapping; lcr3() drops every entry.
+/// # Safety
+///
+/// Must run in ring 0; `invlpg` faults in user mode. Any `va` is
+/// accepted, mapped or not.
+#[inline(always)]
+pub unsafe fn invlpg(va: usize) {
+    core::arch::asm!(
+        "invlpg ({0})",
+        in(reg) va,
+        options(att_syntax, nostack, preserves_flags)
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_checked() {
+        assert!(VirtAddr::new(0x7FFF_FFFF_FFFF).is_some());
+        assert!(VirtAddr::new(0xFFFF_8000_0000_0000).is_some());
+        assert!(VirtAddr::new(0x8000_0000_0000).is_none());
+        assert!(VirtAddr::new(0x7FFF_FFFF_F000).unwrap().add(0x1000).is_none());
+        assert!(PhysAddr::new((1 << PHYSADDR_BITS) - 1).is_some());
+        assert!(PhysAddr::new(1 << PHYSADDR_BITS).is_none());
+        assert_eq!(PhysAddr::new(0x7123).unwrap().page_number(), PageNumber::new(7).unwrap());
+        assert_eq!(PageNumber::new(7).unwrap().address().as_usize(), 0x7000);
+
+        let va = VirtAddr::new(0x0000_7F80_4020_1000 | 0x123).unwrap();
+        assert_eq!([l4_index(va), l3_index(va), l2_index(va), l1_index(va)], [0xFF, 1, 1, 1]);
+        assert_eq!(va.page_offset(), 0x123);
+        assert_eq!(va.page_down().as_usize(), 0x0000_7F80_4020_1000);
+        assert_eq!(va.page_up().unwrap().as_usize(), 0x0000_7F80_4020_2000);
+        assert_eq!(va.page_down().page_up(), Some(va.page_down()));
+        assert!(VirtAddr::new(0x7FFF_FFFF_F001).unwrap().page_up().is_none());
+    }
+
+    #[test]
+    fn entries_keep_address_and_flags_apart() {
+        let pa = PhysAddr::new(0x1234_5000).unwrap();
+        let entry = PageTableEntry::new(pa, PTE_P | PTE_W | PTE_U).unwrap();
+        assert_eq!(entry.addr(), pa);
+        assert!(entry.is_present() && entry.is_writable() && entry.is_user() && !entry.is_huge());
+        assert!(entry.has(PTE_P | PTE_U));
+
+        let cow = entry.with_flags(PTE_COW, PTE_W);
+        assert_eq!((cow.addr(), cow.flags()), (pa, PTE_P | PTE_U | PTE_COW));
+        let nx = PageTableEntry::new(pa, PTE_P | PTE_NX).unwrap();
+        assert_eq!((nx.addr(), nx.flags()), (pa, PTE_P | PTE_NX));
+        assert_eq!(cow.next_table(), Some(pa.as_ptr()));
+        assert_eq!(PageTableEntry::from_bits(PTE_P | PTE_PS).next_table(), None);
+
+        assert!(PageTableEntry::new(PhysAddr::new(0x1234_5008).unwrap(), PTE_P).is_none());
+        assert!(PageTableEntry::new(pa, 0x1000).is_none());
+        assert!(PageTableEntry::EMPTY.is_empty() && !PageTableEntry::EMPTY.is_present());
+    }
+}
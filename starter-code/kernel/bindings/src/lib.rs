@@ -1,3 +1,4 @@
+#![cfg_attr(not(test), no_std)]
 pub mod bindings_kernel;
 pub mod bindings_x86_64;
 pub mod bindings_elf;
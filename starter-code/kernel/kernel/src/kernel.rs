@@ -2,12 +2,18 @@ use bindings::bindings_x86_64::*;
 use bindings::bindings_kernel::*;
 use bindings::bindings_lib::*;
 
+use crate::memshow::memshow_fragmentation;
 use crate::memshow::memshow_physical;
 use crate::memshow::memshow_virtual_animate;
 
 use crate::process::ProcessTable;
-use crate::ph_page_info::PhysicalPageInfoTable;
+use crate::multiboot::MemoryMap;
+use crate::ph_page_info::{metadata_words, page_address, MemoryLayout, PhysicalPageInfoTable, MAX_RESERVED_REGIONS, PF_COW, PF_PAGETABLE, PF_PINNED, PF_SHARED};
 use crate::ph_page_info::PageOwner;
+use crate::selftest::{kernel_test, run_kernel_tests, test_assert};
+use crate::shm::{ShmTable, MAX_SHM_SEGMENTS};
+use crate::swap::{swap_entry, swap_slot, SwapArea, SWAP_PAGES};
+use crate::vma::VmaList;
 
 use stdlib::*;
 
@@ -23,9 +29,17 @@ use core::sync::atomic::{
 
 unsafe extern "C" {
     fn set_pagetable(pagetable: *mut x86_64_pagetable);
-    fn virtual_memory_map(pagetable: *mut x86_64_pagetable, vaddr: usize, paddr: usize, size: usize, flags: u32) -> core::ffi::c_int;
+    fn virtual_memory_map(pagetable: *mut x86_64_pagetable, vaddr: usize, paddr: usize, size: usize, flags: X86_64PageentryT) -> core::ffi::c_int;
     fn virtual_memory_lookup(pagetable: *mut x86_64_pagetable, va: usize) -> VAMapping;
+    fn virtual_memory_protect(pagetable: *mut x86_64_pagetable, va: usize, size: usize, perm: X86_64PageentryT) -> core::ffi::c_int;
+    fn virtual_memory_unmap(pagetable: *mut x86_64_pagetable, va: usize, size: usize, released: *mut PhysAddr) -> core::ffi::c_int;
+    fn pagetable_free(pagetable: *mut x86_64_pagetable) -> usize;
+    fn copy_pagetable(src: *mut x86_64_pagetable, regions: *const CopyRegion, nregions: usize, default: CopyAction, allocated: *mut PhysAddr, max: usize, nallocated: *mut usize) -> *mut x86_64_pagetable;
+    fn virtual_memory_mappings(pagetable: *mut x86_64_pagetable, va: usize, runs: *mut MappingRun, max: usize) -> usize;
     fn c_panic(format: *const core::ffi::c_char, ...) -> !;
+    fn qemu_exit(status: core::ffi::c_int);
+    fn log_printf(format: *const core::ffi::c_char, ...);
+    fn program_lookup(name: *const u8, len: usize) -> core::ffi::c_int;
     static kernel_pagetable: *mut x86_64_pagetable;
 }
 
@@ -44,6 +58,10 @@ unsafe extern "C" {
 
 const PROC_SIZE: usize = 0x40000; // initial state only
 
+// Room below the stack that sys_mmap leaves for the stack to grow into,
+// unless a mapping is placed there on request.
+pub const MMAP_STACK_GAP: usize = 16 * PAGESIZE as usize;
+
 const HZ: u32 = 100;                // timer interrupt frequency (interrupts/sec)
 static TICKS: AtomicU32 =           // # timer interrupts so far
     AtomicU32::new(0);              // AtomicU32 for thread-safe mutable static
@@ -51,9 +69,30 @@ static TICKS: AtomicU32 =           // # timer interrupts so far
 static DISP_GLOBAL: AtomicU8 =      // global flag to display memviewer
     AtomicU8::new(1);               // AtomicU8 for thread-safe mutable static
 
+// Boot command used when the boot loader passes none. `make check` builds
+// with WEENSYOS_COMMAND set to each test's name to run the tests in turn.
+const DEFAULT_COMMAND: Option<&str> = option_env!("WEENSYOS_COMMAND");
+
+// Most pages fork can allocate: a copy of every user page, plus the page
+// table process_pagetable_alloc gives every process.
+const FORK_MAX_PAGES: usize = ((MEMSIZE_VIRTUAL - PROC_START_ADDR) / PAGESIZE) as usize + 5;
+
+// Most runs of read-only user pages fork shares; pages in further runs are
+// copied.
+const FORK_MAX_SHARED_RUNS: usize = 16;
+
+// Build with WEENSYOS_OOM_KILLER set to kill the largest process when the
+// kernel itself runs out of memory, instead of failing the allocation.
+const OOM_KILLER: bool = option_env!("WEENSYOS_OOM_KILLER").is_some();
+
 pub struct Kernel {
-    proc_table: ProcessTable,
-    pageinfo_table: PhysicalPageInfoTable,
+    pub(crate) proc_table: ProcessTable,
+    pub(crate) pageinfo_table: PhysicalPageInfoTable,
+    pub(crate) oom_killer: bool,    // see alloc_kernel_page
+    pub(crate) vmas: [VmaList; NPROC], // reserved areas of each process
+    pub(crate) swap: SwapArea,
+    pub(crate) shm: ShmTable,       // shared memory segments
+    fork_pages: [PhysAddr; FORK_MAX_PAGES], // pages copy_pagetable allocates in fork
 }
 
 impl Kernel {
@@ -61,44 +100,59 @@ impl Kernel {
         Kernel {
             proc_table: ProcessTable::new(),
             pageinfo_table: PhysicalPageInfoTable::new(),
+            oom_killer: OOM_KILLER,
+            vmas: [VmaList::new(); NPROC],
+            swap: SwapArea::new(),
+            shm: ShmTable::new(),
+            fork_pages: [PhysAddr::ZERO; FORK_MAX_PAGES],
         }
     }
 
-    // kernel(command)
+    // kernel(command, multiboot_info)
     //    Initialize the hardware and processes and start running. The `command`
-    //    string is an optional string passed from the boot loader.
+    //    string is an optional string passed from the boot loader, as is the
+    //    physical address of the multiboot information (0 if there is none).
 
-    pub fn kernel(&mut self, command: *const u8) {
+    pub fn kernel(&mut self, command: *const u8, multiboot_info: usize) {
         unsafe extern "C" {
             fn hardware_init();
             fn console_clear();
             fn timer_init(hz: u32);
-            fn strcmp(
-                a: *const core::ffi::c_char,
-                b: *const core::ffi::c_char,
-            ) -> core::ffi::c_int;
         }
 
         unsafe{
+            // pageinfo must be ready before virtual_memory_init allocates
+            // page tables for the identity map
+            self.memory_init(multiboot_info);
             hardware_init();
-            // Borrows PhysicalPageInfoTable
-            self.pageinfo_table.pageinfo_init();
+            // PTE_NX is a reserved bit, and faults, unless EFER.NXE is on
+            wrmsr(MSR_IA32_EFER, rdmsr(MSR_IA32_EFER) | IA32_EFER_NXE);
+            self.mark_pagetable_pages(kernel_pagetable, 0);
             console_clear();
             timer_init(HZ);
 
-            if !command.is_null() && strcmp(command as *const i8, "fork".as_ptr() as *const i8) == 0 {
-                self.process_setup(1, 4);
-            } else if !command.is_null() && strcmp(command as *const i8, "forkexit".as_ptr() as *const i8) == 0 {
-                self.process_setup(1, 5);
-            } else if !command.is_null() && strcmp(command as *const i8, "test".as_ptr() as *const i8) == 0 {
-                self.process_setup(1, 6);
-            } else if !command.is_null() && strcmp(command as *const i8, "test2".as_ptr() as *const i8) == 0 {
-                for i in 1..=2 {
-                    self.process_setup(i, 6);
-                }
+            let command = if command.is_null() {
+                DEFAULT_COMMAND.unwrap_or("").as_bytes()
             } else {
-                for i in 1..=4 {
-                    self.process_setup(i, i - 1);
+                core::ffi::CStr::from_ptr(command as *const core::ffi::c_char).to_bytes()
+            };
+
+            match command {
+                b"test2" => {
+                    for i in 1..=2 {
+                        self.process_setup(i, b"test");
+                    }
+                }
+                b"selftest" => run_kernel_tests(self),
+                // any other program by name, such as "fork" or "test"
+                name if program_lookup(name.as_ptr(), name.len()) >= 0 => {
+                    self.process_setup(1, name);
+                }
+                _ => {
+                    let allocators: [&[u8]; 4] = [b"allocator", b"allocator2", b"allocator3", b"allocator4"];
+                    for (i, name) in (1..).zip(allocators) {
+                        self.process_setup(i, name);
+                    }
                 }
             }
 
@@ -107,31 +161,1074 @@ impl Kernel {
         }
     }
 
-    // process_setup(pid, program_number)
-    //    Load application program `program_number` as process number `pid`.
-    //    This loads the application's code and data into memory, sets its
-    //    %rip and %rsp, gives it a stack page, and marks it as runnable.
+    // memory_init(multiboot_info)
+    //    Size physical memory from the multiboot information at physical
+    //    address `multiboot_info`, or from CMOS if the boot loader passed no
+    //    memory information. Then place the page metadata: above the
+    //    processes' fixed load addresses if there is room, otherwise between
+    //    the kernel image and the kernel stack. Initialize `pageinfo` there.
 
-    pub fn process_setup(&mut self, pid: usize, program_number: usize) {
-        let mut p = self.proc_table.process_setup(pid, program_number);
-        unsafe { // increase refcount since kernel_pagetable was used
-            let pn = page_number(kernel_pagetable as *const u8);
-            let page_info = self.pageinfo_table.get_page_info_ref(pn);
-            page_info.refcount += 1;
+    fn memory_init(&mut self, multiboot_info: usize) {
+        unsafe extern "C" {
+            fn cmos_memory_size() -> usize;
+            static end: u8;
         }
-        p.p_registers.reg_rsp = PROC_START_ADDR + (PROC_SIZE * pid) as u64;
-        let stack_page = p.p_registers.reg_rsp - PAGESIZE;
-        self.assign_physical_page(stack_page as usize, pid);
-        unsafe {
-            virtual_memory_map(
-                p.p_pagetable, 
-                stack_page as usize,
-                stack_page as usize,
-                PAGESIZE as usize,
-                (PTE_P | PTE_W | PTE_U) as u32,
-            );
+
+        let map = unsafe { MemoryMap::from_multiboot(multiboot_info as *const u8) }
+            .unwrap_or_else(|| MemoryMap::from_size(unsafe { cmos_memory_size() }));
+        let npages = map.memsize() / PAGESIZE as usize;
+        let size = (metadata_words(npages) * 8 + PAGE_OFF_MASK) & !PAGE_OFF_MASK;
+
+        // the swap area follows the metadata if there is room for both
+        let swap_size = SWAP_PAGES * PAGESIZE as usize;
+        let end_addr = unsafe { (&end as *const u8 as usize + PAGE_OFF_MASK) & !PAGE_OFF_MASK };
+        let (start, swap_pages) = match map.find_free_range(size + swap_size, MEMSIZE_VIRTUAL as usize) {
+            Some(start) => (start, SWAP_PAGES),
+            None => match map.find_free_range(size, MEMSIZE_VIRTUAL as usize) {
+                Some(start) => (start, 0),
+                None if end_addr + size <= (KERNEL_STACK_TOP - KERNEL_STACK_SIZE) as usize => (end_addr, 0),
+                None => unsafe { c_panic(c"(memory_init) no room for page metadata".as_ptr()) },
+            },
+        };
+        let metadata = unsafe { core::slice::from_raw_parts_mut(start as *mut u64, size / 8) };
+        self.swap.init(start + size, swap_pages);
+        let reserved_end = start + size + swap_pages * PAGESIZE as usize;
+
+        let mut reserved = [const { 0..0 }; MAX_RESERVED_REGIONS];
+        let mut kernel_regions = [const { 0..0 }; 3];
+        let layout = MemoryLayout::hardware(&map, start..reserved_end, &mut reserved, &mut kernel_regions);
+        self.pageinfo_table.pageinfo_init(&layout, metadata);
+    }
+
+    // alloc_kernel_pagetable()
+    //    Allocates a zeroed page for a kernel page table. Returns its physical
+    //    address, or None if memory is exhausted.
+
+    pub fn alloc_kernel_pagetable(&mut self) -> Option<PhysAddr> {
+        let pa = self.alloc_kernel_page()?;
+        self.pageinfo_table.set_flags(pa, PF_PAGETABLE);
+        Some(pa)
+    }
+
+    // process_setup(pid, name)
+    //    Load the application program called `name` as process number `pid`.
+    //    This gives the process its own page table, loads the application's
+    //    code and data into memory, sets its %rip and %rsp, gives it a stack
+    //    page at the top of virtual memory, and marks it as runnable.
+    //    Returns 0 on success and -EINVAL if there is no such program. If
+    //    memory runs out, releases whatever the process was given, leaves it
+    //    free, and returns -ENOMEM.
+
+    pub fn process_setup(&mut self, pid: usize, name: &[u8]) -> i32 {
+        let program_number = unsafe { program_lookup(name.as_ptr(), name.len()) };
+        if program_number < 0 {
+            unsafe {
+                log_printf(c"process %d: no program named %.*s\n".as_ptr(),
+                    pid as i32, name.len() as i32, name.as_ptr());
+            }
+            return -EINVAL;
         }
+
+        self.vmas[pid].clear();
+        let stack_page = MEMSIZE_VIRTUAL as usize - PAGESIZE as usize;
+        let ok = match self.process_pagetable_alloc(pid) {
+            Some(pt) => {
+                self.map_kernel_memory(pt);
+                self.proc_table.process_setup(pid, program_number as usize, pt).is_ok()
+                    && self.vmas[pid].insert(stack_page, MEMSIZE_VIRTUAL as usize, PTE_P | PTE_W | PTE_U | PTE_NX).is_ok()
+                    && self.handle_page_fault(pid, stack_page, (PFERR_USER | PFERR_WRITE) as u64)
+            }
+            None => false,
+        };
+        if !ok {
+            self.process_free(pid);
+            unsafe {
+                log_printf(c"process %d: out of memory loading %.*s\n".as_ptr(),
+                    pid as i32, name.len() as i32, name.as_ptr());
+            }
+            return -ENOMEM;
+        }
+
+        let p = self.proc_table.get_process_by_pid_mut(pid);
+        p.p_registers.reg_rsp = MEMSIZE_VIRTUAL;
+        p.p_stack_bottom = stack_page;
         p.p_state = P_RUNNABLE;
+        0
+    }
+
+    // map_kernel_memory(pt)
+    //    Map the kernel's memory below PROC_START_ADDR into page table `pt`
+    //    as `kernel_pagetable` maps it, so the kernel keeps running while
+    //    `pt` is installed. `pt` must come from process_pagetable_alloc.
+
+    fn map_kernel_memory(&mut self, pt: *mut x86_64_pagetable) {
+        for va in (0..PROC_START_ADDR as usize).step_by(PAGESIZE as usize) {
+            let vam = unsafe { virtual_memory_lookup(kernel_pagetable, va) };
+            if vam.pn >= 0 {
+                // cannot fail: the page table already covers MEMSIZE_VIRTUAL
+                unsafe { virtual_memory_map(pt, va, vam.pa, PAGESIZE as usize, vam.perm as X86_64PageentryT); }
+            }
+        }
+    }
+
+    // process_pagetable_alloc(pid)
+    //    Allocates a page table for process `pid` shaped like the kernel's
+    //    initial one: L4, L3 and L2 tables and two L1 tables covering the
+    //    first 4MB of virtual memory, so that mapping below MEMSIZE_VIRTUAL
+    //    never needs another page (virtual_memory_unmap keeps these tables
+    //    even when they empty). The pages belong to `pid`. Returns None
+    //    (having allocated nothing) if memory runs out.
+
+    fn process_pagetable_alloc(&mut self, pid: usize) -> Option<*mut x86_64_pagetable> {
+        let mut pages = [PhysAddr::default(); 5];
+        for i in 0..pages.len() {
+            match self.pageinfo_table.alloc_page(pid as PidT) {
+                Some(pa) => pages[i] = pa,
+                None => {
+                    for &pa in &pages[..i] {
+                        self.pageinfo_table.free_page(pa);
+                    }
+                    return None;
+                }
+            }
+            self.pageinfo_table.set_flags(pages[i], PF_PAGETABLE);
+        }
+
+        let link = |pt: PhysAddr, index: usize, next: PhysAddr| unsafe {
+            let table: *mut x86_64_pagetable = pt.as_ptr();
+            (*table).entry[index] = PageTableEntry::new(next, PTE_P | PTE_W | PTE_U)?;
+            Some(())
+        };
+        link(pages[0], 0, pages[1])?;
+        link(pages[1], 0, pages[2])?;
+        link(pages[2], 0, pages[3])?;
+        link(pages[2], 1, pages[4])?;
+        Some(pages[0].as_ptr())
+    }
+
+    // process_free(pid)
+    //    Release everything process `pid` holds and mark it free: its user
+    //    pages are unmapped (see unmap_range) and its page table is freed.
+    //    Pages still owned by `pid` afterwards (assigned by a failed load but
+    //    never mapped) are freed too.
+
+    pub fn process_free(&mut self, pid: usize) {
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+
+        for id in 0..MAX_SHM_SEGMENTS {
+            if self.shm.get(id).is_some_and(|segment| segment.attached_at[pid] != 0) {
+                self.detach_segment(pid, id);
+            }
+        }
+        if !pt.is_null() {
+            self.unmap_range(pid, PROC_START_ADDR as usize, MEMSIZE_VIRTUAL as usize);
+            unsafe { pagetable_free(pt); }
+        }
+
+        for pn in 0..self.pageinfo_table.pageinfo.len() {
+            let page = &self.pageinfo_table.pageinfo[pn];
+            if page.refcount == 1 && page.owner == pid as PidT {
+                self.pageinfo_table.free_page(page_address(pn));
+            }
+        }
+
+        self.vmas[pid].clear();
+        for p in self.proc_table.processes.iter_mut() {
+            if p.p_parent == pid as PidT {
+                p.p_parent = 0;
+            }
+        }
+        let p = self.proc_table.get_process_by_pid_mut(pid);
+        p.p_state = P_FREE;
+        p.p_pagetable = core::ptr::null_mut();
+    }
+
+    // within_rss_limit(pid, npages)
+    //    Returns true iff process `pid` may own `npages` more pages under its
+    //    RLIMIT_RSS.
+
+    fn within_rss_limit(&self, pid: usize, npages: usize) -> bool {
+        let limit = self.proc_table.get_process_by_pid(pid).p_rlimits.resident_pages;
+        self.pageinfo_table.pages_owned_by(pid as PidT).saturating_add(npages) <= limit
+    }
+
+    // reservable(pid, va)
+    //    Returns true iff process `pid` may add the page at `va` to its
+    //    areas: nothing is mapped there and it is not the stack's guard page.
+
+    fn reservable(&self, pid: usize, va: usize) -> bool {
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+        unsafe { virtual_memory_lookup(pt, va) }.pn < 0 && !self.in_stack_guard(pid, va)
+    }
+
+    // page_reserve(pid, addr)
+    //    Reserve the page at `addr` for process `pid` (`sys_page_alloc`).
+    //    The page is backed by a zeroed physical page when first touched.
+    //    Returns 0 on success (or if it is already reserved), -ENOMEM if the
+    //    process is at its RLIMIT_RSS or has too many areas, and -1 if `addr`
+    //    is not a free, page-aligned user address.
+
+    pub fn page_reserve(&mut self, pid: usize, addr: usize) -> i32 {
+        if !addr.is_multiple_of(PAGESIZE as usize)
+            || addr < PROC_START_ADDR as usize
+            || addr >= MEMSIZE_VIRTUAL as usize {
+            return -1;
+        }
+        if self.vmas[pid].find(addr).is_some() {
+            return 0;
+        }
+        if !self.within_rss_limit(pid, 1) {
+            return -ENOMEM;
+        }
+        if !self.reservable(pid, addr) {
+            return -1;
+        }
+        match self.vmas[pid].insert(addr, addr + PAGESIZE as usize, PTE_P | PTE_W | PTE_U | PTE_NX) {
+            Ok(()) => 0,
+            Err(error) => error,
+        }
+    }
+
+    // handle_page_fault(pid, addr, err)
+    //    Back the page containing `addr` for process `pid` after a fault
+    //    with error code `err`: a swapped-out page is read back in, a write
+    //    to a copy-on-write page gets the process its own copy (see
+    //    copy_on_write), and a missing page inside one of the process's
+    //    areas gets a new zeroed page, mapped with the area's permissions.
+    //    Returns false if the access is not allowed (no area contains
+    //    `addr`, the page is present, the area is PROT_NONE, a write hits a
+    //    read-only area, or an instruction fetch hits a no-execute area) or
+    //    no page can be had within RLIMIT_RSS.
+
+    pub fn handle_page_fault(&mut self, pid: usize, addr: usize, err: u64) -> bool {
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+        if err & PFERR_PRESENT as u64 == 0 && self.swapped_slot(pt, addr).is_some() {
+            return self.swap_in(pid, addr);
+        }
+        let write = (PFERR_PRESENT | PFERR_WRITE) as u64;
+        if err & write == write && pte_entry(pt, addr).is_some_and(|pte| unsafe { *pte }.has(PTE_P | PTE_U | PTE_COW)) {
+            return self.copy_on_write(pid, addr);
+        }
+        let Some(area) = self.vmas[pid].find(addr) else {
+            return false;
+        };
+        if err & PFERR_PRESENT as u64 != 0
+            || area.perm & PTE_P == 0
+            || (err & PFERR_WRITE as u64 != 0 && area.perm & PTE_W == 0)
+            || (err & PFERR_FETCH as u64 != 0 && area.perm & PTE_NX != 0)
+            || !self.within_rss_limit(pid, 1) {
+            return false;
+        }
+
+        let va = addr & !PAGE_OFF_MASK;
+        let Some(pa) = self.alloc_user_page(pid) else {
+            return false;
+        };
+        if unsafe { virtual_memory_map(pt, va, pa.as_usize(), PAGESIZE as usize, area.perm) } < 0 {
+            self.pageinfo_table.free_page(pa);
+            return false;
+        }
+        true
+    }
+
+    // copy_on_write(pid, addr)
+    //    Make the copy-on-write page containing `addr` writable for process
+    //    `pid`. If no other mapping references the page it is simply made
+    //    writable. Otherwise the process gets a copy in a new page, within
+    //    RLIMIT_RSS, and drops its reference to the old one (see
+    //    release_page). Returns false if no page can be had.
+
+    fn copy_on_write(&mut self, pid: usize, addr: usize) -> bool {
+        let va = addr & !PAGE_OFF_MASK;
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+        let Some(pte) = pte_entry(pt, va) else {
+            return false;
+        };
+        let entry = unsafe { *pte };
+        let old = entry.addr();
+        if self.pageinfo_table.pageinfo[old.page_number().as_usize()].refcount == 1 {
+            self.pageinfo_table.clear_flags(old, PF_COW);
+            unsafe { *pte = entry.with_flags(PTE_W, PTE_COW); }
+            return true;
+        }
+
+        if !self.within_rss_limit(pid, 1) {
+            return false;
+        }
+        // the old page is shared, so allocating cannot swap it out
+        let Some(pa) = self.alloc_user_page(pid) else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(old.as_ptr::<u8>(), pa.as_ptr::<u8>(), PAGESIZE as usize);
+            // cannot fail: `pa` is a page address
+            *pte = PageTableEntry::new(pa, entry.with_flags(PTE_W, PTE_COW).flags()).unwrap_or_default();
+        }
+        self.release_page(pid, va, old);
+        true
+    }
+
+    // mark_copy_on_write(pa)
+    //    Called as a mapping of page `pa` is made writable. If other
+    //    mappings still reference the page (since fork shared it) it gets
+    //    PF_COW and true is returned, so the mapping becomes copy-on-write;
+    //    otherwise PF_COW is cleared and the mapping may be writable.
+    //    Shared memory segment pages are never copied.
+
+    pub fn mark_copy_on_write(&mut self, pa: PhysAddr) -> bool {
+        let Some(&page) = self.pageinfo_table.pageinfo.get(pa.page_number().as_usize()) else {
+            return false;
+        };
+        if page.refcount > 1 && page.flags & PF_SHARED == 0 {
+            self.pageinfo_table.set_flags(pa, PF_COW);
+            true
+        } else {
+            self.pageinfo_table.clear_flags(pa, PF_COW);
+            false
+        }
+    }
+
+    // release_page(pid, va, pa)
+    //    Drop the reference process `pid` held to physical page `pa`, which
+    //    it mapped at `va` and no longer does. If the process owned the page
+    //    and others still reference it, ownership passes to a live process
+    //    mapping it at the same address (where fork puts shared pages).
+
+    fn release_page(&mut self, pid: usize, va: usize, pa: PhysAddr) {
+        if self.pageinfo_table.free_page(pa).is_none_or(|refcount| refcount == 0) {
+            return;
+        }
+        let pn = pa.page_number().as_usize();
+        if self.pageinfo_table.pageinfo[pn].owner != pid as PidT {
+            return;
+        }
+        let heir = (1..NPROC).find(|&other| {
+            let p = self.proc_table.get_process_by_pid(other);
+            other != pid && p.p_state != P_FREE && !p.p_pagetable.is_null()
+                && unsafe { virtual_memory_lookup(p.p_pagetable, va) }.page() == Some(pa)
+        });
+        if let Some(heir) = heir {
+            self.pageinfo_table.get_page_info_ref(pn).owner = heir as PidT;
+        }
+    }
+
+    // alloc_user_page(pid)
+    //    Allocates a zeroed page for process `pid`, swapping other pages out
+    //    while memory is full. Returns None if memory and swap both run out.
+
+    fn alloc_user_page(&mut self, pid: usize) -> Option<PhysAddr> {
+        loop {
+            if let Some(pa) = self.pageinfo_table.alloc_page(pid as PidT) {
+                return Some(pa);
+            }
+            if !self.swap_out() {
+                return None;
+            }
+        }
+    }
+
+    // swapped_slot(pt, va)
+    //    Returns the swap slot holding the page at `va` in page table `pt`,
+    //    if that page is swapped out.
+
+    pub fn swapped_slot(&self, pt: *mut x86_64_pagetable, va: usize) -> Option<usize> {
+        pte_entry(pt, va).and_then(|pte| swap_slot(unsafe { *pte }))
+    }
+
+    // swap_out()
+    //    Evict one user page to swap, chosen by the clock algorithm: the
+    //    clock hand sweeps every process's user pages, clearing PTE_A on
+    //    pages used since it last passed and evicting the first page that
+    //    was not. Only unshared, unpinned pages a process owns, in its own
+    //    page table, are evicted. Writable pages go first: read-only pages
+    //    (program text, mostly) are only evicted if no writable page can be.
+    //    Returns false if swap is full or no page is eligible.
+
+    pub fn swap_out(&mut self) -> bool {
+        unsafe extern "C" {
+            fn memcpy(
+                dst: *mut core::ffi::c_void,
+                src: *const core::ffi::c_void,
+                n: usize,
+            ) -> *mut core::ffi::c_void;
+        }
+
+        let Some(slot) = self.swap.alloc_slot() else {
+            return false;
+        };
+        let npages = ((MEMSIZE_VIRTUAL - PROC_START_ADDR) / PAGESIZE) as usize;
+        // two sweeps over writable pages, then two that take read-only
+        // pages too: the first of each may only clear accessed bits
+        for step in 0..4 * NPROC * npages {
+            let read_only = step >= 2 * NPROC * npages;
+            let hand = self.swap.clock_hand;
+            self.swap.clock_hand = (hand + 1) % (NPROC * npages);
+            let (pid, va) = (hand / npages, PROC_START_ADDR as usize + (hand % npages) * PAGESIZE as usize);
+            let Some(pte) = self.evictable(pid, va, read_only) else {
+                continue;
+            };
+            let entry = unsafe { *pte };
+            if entry.has(PTE_A) {
+                unsafe { *pte = entry.with_flags(0, PTE_A); }
+                continue;
+            }
+
+            let pa = entry.addr();
+            unsafe {
+                memcpy(self.swap.slot_address(slot) as *mut core::ffi::c_void,
+                    pa.as_ptr(), PAGESIZE as usize);
+                *pte = swap_entry(slot, entry.flags());
+            }
+            self.pageinfo_table.free_page(pa);
+            self.swap.swap_outs += 1;
+            return true;
+        }
+        self.swap.free_slot(slot);
+        false
+    }
+
+    // evictable(pid, va, read_only)
+    //    Returns the page table entry of the page at `va` of process `pid`
+    //    if swap_out may evict that page. Read-only pages are only eligible
+    //    if `read_only`.
+
+    fn evictable(&self, pid: usize, va: usize, read_only: bool) -> Option<*mut PageTableEntry> {
+        let p = self.proc_table.get_process_by_pid(pid);
+        if p.p_state == P_FREE || p.p_pagetable.is_null() {
+            return None;
+        }
+        let pte = pte_entry(p.p_pagetable, va)?;
+        let entry = unsafe { *pte };
+        if !entry.has(PTE_P | PTE_U) || (!read_only && !entry.has(PTE_W)) {
+            return None;
+        }
+        let page = self.pageinfo_table.pageinfo.get(entry.addr().page_number().as_usize())?;
+        (page.owner == pid as PidT && page.refcount == 1 && page.flags & (PF_COW | PF_SHARED | PF_PINNED) == 0).then_some(pte)
+    }
+
+    // swap_in(pid, va)
+    //    Read the swapped-out page at `va` of process `pid` back into a new
+    //    page and map it as it was. Returns false if no page is available.
+
+    fn swap_in(&mut self, pid: usize, va: usize) -> bool {
+        unsafe extern "C" {
+            fn memcpy(
+                dst: *mut core::ffi::c_void,
+                src: *const core::ffi::c_void,
+                n: usize,
+            ) -> *mut core::ffi::c_void;
+        }
+
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+        let Some(slot) = self.swapped_slot(pt, va) else {
+            return false;
+        };
+        let Some(pa) = self.alloc_user_page(pid) else {
+            return false;
+        };
+        unsafe {
+            memcpy(pa.as_ptr(),
+                self.swap.slot_address(slot) as *const core::ffi::c_void, PAGESIZE as usize);
+            // cannot fail: the entry exists, swapped out
+            if let Some(pte) = pte_entry(pt, va) {
+                *pte = PageTableEntry::new(pa, PTE_P | ((*pte).flags() & (PTE_W | PTE_U | PTE_NX | PTE_COW))).unwrap_or_default();
+            }
+        }
+        self.swap.free_slot(slot);
+        self.swap.swap_ins += 1;
+        true
+    }
+
+    // in_stack_guard(pid, addr)
+    //    Returns true iff `addr` is in the unmapped guard page just below the
+    //    stack of process `pid`.
+
+    pub fn in_stack_guard(&self, pid: usize, addr: usize) -> bool {
+        let bottom = self.proc_table.get_process_by_pid(pid).p_stack_bottom;
+        bottom != 0 && addr < bottom && addr >= bottom - PAGESIZE as usize
+    }
+
+    // grow_stack(pid)
+    //    Extend the stack of process `pid` down over its guard page, which
+    //    moves one page lower; the new stack page is backed when touched.
+    //    Returns 0 on success and -ENOMEM if the stack would exceed
+    //    RLIMIT_STACK or the new guard page would cover memory the process
+    //    uses (its heap, say): a stack overflow.
+
+    pub fn grow_stack(&mut self, pid: usize) -> i32 {
+        let p = *self.proc_table.get_process_by_pid(pid);
+        let page = PAGESIZE as usize;
+        let (bottom, guard) = (p.p_stack_bottom - page, p.p_stack_bottom - 2 * page);
+        if MEMSIZE_VIRTUAL as usize - bottom > p.p_rlimits.stack_bytes
+            || guard < PROC_START_ADDR as usize
+            || guard < (p.p_brk + PAGE_OFF_MASK) & !PAGE_OFF_MASK
+            || unsafe { virtual_memory_lookup(p.p_pagetable, guard) }.pn >= 0
+            || self.vmas[pid].find(guard).is_some()
+            || self.vmas[pid].insert(bottom, p.p_stack_bottom, PTE_P | PTE_W | PTE_U | PTE_NX).is_err() {
+            return -ENOMEM;
+        }
+        self.proc_table.get_process_by_pid_mut(pid).p_stack_bottom = bottom;
+        0
+    }
+
+    // brk(pid, addr)
+    //    Move the program break of process `pid` to `addr`. The heap is
+    //    `[p_heap_start, p_brk)` and is one of the process's areas: pages it
+    //    newly covers are reserved (and backed when first touched), and
+    //    pages it no longer covers are unmapped and freed. Returns 0 on
+    //    success, -EINVAL if `addr` is below the heap start or beyond
+    //    MEMSIZE_VIRTUAL, and -ENOMEM if growing would exceed RLIMIT_HEAP or
+    //    run into memory that is in use (the break then does not move).
+
+    pub fn brk(&mut self, pid: usize, addr: usize) -> i32 {
+        let p = *self.proc_table.get_process_by_pid(pid);
+        if addr < p.p_heap_start || addr > MEMSIZE_VIRTUAL as usize {
+            return -EINVAL;
+        }
+        if addr - p.p_heap_start > p.p_rlimits.heap_bytes {
+            return -ENOMEM;
+        }
+
+        let round_up = |va: usize| (va + PAGE_OFF_MASK) & !PAGE_OFF_MASK;
+        let (old_end, new_end) = (round_up(p.p_brk), round_up(addr));
+        if new_end > old_end {
+            if !(old_end..new_end).step_by(PAGESIZE as usize).all(|va| self.reservable(pid, va))
+                || self.vmas[pid].insert(old_end, new_end, PTE_P | PTE_W | PTE_U | PTE_NX).is_err() {
+                return -ENOMEM;
+            }
+        } else if new_end < old_end {
+            if self.vmas[pid].remove(new_end, old_end).is_err() {
+                return -ENOMEM;
+            }
+            self.unmap_range(pid, new_end, old_end);
+        }
+
+        self.proc_table.get_process_by_pid_mut(pid).p_brk = addr;
+        0
+    }
+
+    // unmap_range(pid, start, end)
+    //    Unmap the pages of process `pid` in `[start, end)`. Each mapped page
+    //    loses a reference (see release_page) and is freed with its last
+    //    one; a swapped-out page gives back its swap slot.
+
+    fn unmap_range(&mut self, pid: usize, start: usize, end: usize) {
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+        for va in (start..end).step_by(PAGESIZE as usize) {
+            if let Some(slot) = self.swapped_slot(pt, va) {
+                self.swap.free_slot(slot);
+            }
+            let mut released = [PhysAddr::ZERO; 1];
+            if unsafe { virtual_memory_unmap(pt, va, PAGESIZE as usize, released.as_mut_ptr()) } == 1 {
+                self.release_page(pid, va, released[0]);
+            }
+        }
+    }
+
+    // mmap(pid, hint, len, prot, flags)
+    //    Reserve `len` bytes (rounded up to whole pages) of anonymous memory
+    //    with protection `prot` for process `pid` (`sys_mmap`). The area goes
+    //    at `hint` if that range is free, and otherwise in the highest free
+    //    range between the heap and MMAP_STACK_GAP below the stack. Returns
+    //    the area's address, -EINVAL for bad arguments, or -ENOMEM if there
+    //    is no room.
+
+    pub fn mmap(&mut self, pid: usize, hint: usize, len: usize, prot: u64, flags: u64) -> Result<usize, i32> {
+        if len == 0
+            || len > MEMSIZE_VIRTUAL as usize
+            || flags != MAP_PRIVATE | MAP_ANONYMOUS
+            || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return Err(-EINVAL);
+        }
+        let page = PAGESIZE as usize;
+        let size = (len + PAGE_OFF_MASK) & !PAGE_OFF_MASK;
+        let free = |kernel: &Self, va: usize| {
+            kernel.reservable(pid, va) && kernel.vmas[pid].find(va).is_none()
+        };
+
+        let hint_fits = hint != 0
+            && hint.is_multiple_of(page)
+            && hint >= PROC_START_ADDR as usize
+            && hint.checked_add(size).is_some_and(|end| end <= MEMSIZE_VIRTUAL as usize)
+            && (hint..hint + size).step_by(page).all(|va| free(self, va));
+        let addr = if hint_fits {
+            hint
+        } else {
+            // scan down from the top for `size` bytes of free pages
+            let p = self.proc_table.get_process_by_pid(pid);
+            let low = ((p.p_brk + PAGE_OFF_MASK) & !PAGE_OFF_MASK).max(PROC_START_ADDR as usize);
+            let high = (p.p_stack_bottom - page).min(MEMSIZE_VIRTUAL as usize - MMAP_STACK_GAP);
+            let mut run = 0;
+            let mut found = None;
+            for va in (low..high).step_by(page).rev() {
+                run = if free(self, va) { run + page } else { 0 };
+                if run == size {
+                    found = Some(va);
+                    break;
+                }
+            }
+            found.ok_or(-ENOMEM)?
+        };
+
+        let perm = prot_perm(prot);
+        self.vmas[pid].insert(addr, addr + size, perm)?;
+        Ok(addr)
+    }
+
+    // munmap(pid, addr, len)
+    //    Remove the parts of process `pid`'s areas in `[addr, addr + len)`
+    //    and unmap their pages (`sys_munmap`). Returns 0 on success, -EINVAL
+    //    unless the range is page-aligned user memory, and -ENOMEM if an
+    //    area cannot be split.
+
+    pub fn munmap(&mut self, pid: usize, addr: usize, len: usize) -> i32 {
+        let end = match addr.checked_add(len) {
+            Some(end) if len > 0 && end <= MEMSIZE_VIRTUAL as usize => (end + PAGE_OFF_MASK) & !PAGE_OFF_MASK,
+            _ => return -EINVAL,
+        };
+        if !addr.is_multiple_of(PAGESIZE as usize) || addr < PROC_START_ADDR as usize {
+            return -EINVAL;
+        }
+        if self.shm.overlaps_attached(pid, addr, end) {
+            return -EINVAL;
+        }
+        let areas = self.vmas[pid];
+        if self.vmas[pid].remove(addr, end).is_err() {
+            return -ENOMEM;
+        }
+        for area in areas.areas() {
+            let (start, stop) = (area.start.max(addr), area.end.min(end));
+            if start < stop {
+                self.unmap_range(pid, start, stop);
+            }
+        }
+        0
+    }
+
+    // mprotect(pid, addr, len, prot)
+    //    Give the pages of process `pid` in `[addr, addr + len)` protection
+    //    `prot` (`sys_mprotect`). Every page must belong to the process: be
+    //    in one of its areas, or be mapped (or swapped out) from a physical
+    //    page it owns. Areas take the new protection, so pages backed later
+    //    get it too. A page shared with another process since fork is made
+    //    copy-on-write rather than writable. Returns 0 on success, -EINVAL
+    //    for bad arguments, -EPERM if a page is not the process's, and
+    //    -ENOMEM if an area cannot be split.
+
+    pub fn mprotect(&mut self, pid: usize, addr: usize, len: usize, prot: u64) -> i32 {
+        let end = match addr.checked_add(len) {
+            Some(end) if len > 0 && end <= MEMSIZE_VIRTUAL as usize => (end + PAGE_OFF_MASK) & !PAGE_OFF_MASK,
+            _ => return -EINVAL,
+        };
+        if !addr.is_multiple_of(PAGESIZE as usize)
+            || addr < PROC_START_ADDR as usize
+            || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return -EINVAL;
+        }
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+        let owned = |kernel: &Self, va: usize| {
+            let vam = unsafe { virtual_memory_lookup(pt, va) };
+            kernel.vmas[pid].find(va).is_some()
+                || (vam.pn >= 0 && kernel.pageinfo_table.pageinfo[vam.pn as usize].owner == pid as PidT)
+                || kernel.swapped_slot(pt, va).is_some()
+        };
+        if !(addr..end).step_by(PAGESIZE as usize).all(|va| owned(self, va)) {
+            return -EPERM;
+        }
+
+        let perm = prot_perm(prot);
+        let mut areas = self.vmas[pid];
+        for area in self.vmas[pid].areas() {
+            let (start, stop) = (area.start.max(addr), area.end.min(end));
+            if start < stop && areas.insert(start, stop, perm).is_err() {
+                return -ENOMEM;
+            }
+        }
+        // a page fork shared stays shared until written (see
+        // mark_copy_on_write)
+        if unsafe { virtual_memory_protect(pt, addr, end - addr, perm) } < 0 {
+            return -EINVAL;
+        }
+        self.vmas[pid] = areas;
+        0
+    }
+
+    // shm_create(key, size)
+    //    Returns the id of the shared memory segment with `key`, creating
+    //    one of `size` bytes (rounded up to whole pages) if there is none
+    //    (`sys_shm_create`). Fails as ShmTable::create does.
+
+    pub fn shm_create(&mut self, key: u64, size: usize) -> Result<usize, i32> {
+        self.shm.create(key, size.div_ceil(PAGESIZE as usize))
+    }
+
+    // shm_attach(pid, id, hint)
+    //    Map shared memory segment `id` read-write into process `pid`
+    //    (`sys_shm_attach`), at `hint` if that range is free and otherwise
+    //    wherever `mmap` would put it. The first process to attach the
+    //    segment allocates its pages; later ones add a reference to them.
+    //    Returns the segment's address, -EINVAL for a bad id or a segment the
+    //    process already attached, and -ENOMEM if there is no room or the
+    //    pages cannot be allocated within RLIMIT_RSS.
+
+    pub fn shm_attach(&mut self, pid: usize, id: usize, hint: usize) -> Result<usize, i32> {
+        let segment = *self.shm.get(id).ok_or(-EINVAL)?;
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+        if segment.attached_at[pid] != 0 {
+            return Err(-EINVAL);
+        }
+        let first = segment.attachers().next().is_none();
+        if first && !self.within_rss_limit(pid, segment.npages) {
+            return Err(-ENOMEM);
+        }
+        let areas = self.vmas[pid];
+        let addr = self.mmap(pid, hint, segment.size(), PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS)?;
+
+        let mut pages = segment.pages;
+        for i in 0..segment.npages {
+            if !first {
+                self.pageinfo_table.incref(pages[i]);
+                continue;
+            }
+            let Some(pa) = self.alloc_user_page(pid) else {
+                for &pa in &pages[..i] {
+                    self.pageinfo_table.free_page(pa);
+                }
+                self.vmas[pid] = areas;
+                return Err(-ENOMEM);
+            };
+            self.pageinfo_table.set_flags(pa, PF_SHARED);
+            pages[i] = pa;
+        }
+        for (i, &pa) in pages[..segment.npages].iter().enumerate() {
+            // cannot fail: the page table already covers MEMSIZE_VIRTUAL
+            unsafe {
+                virtual_memory_map(pt, addr + i * PAGESIZE as usize, pa.as_usize(), PAGESIZE as usize,
+                    PTE_P | PTE_W | PTE_U | PTE_NX);
+            }
+        }
+        if let Some(segment) = self.shm.get_mut(id) {
+            segment.pages = pages;
+            segment.attached_at[pid] = addr;
+        }
+        Ok(addr)
+    }
+
+    // shm_detach(pid, addr)
+    //    Unmap the shared memory segment process `pid` attached at `addr`
+    //    (`sys_shm_detach`). Returns 0 on success, -EINVAL if no segment is
+    //    attached there, and -ENOMEM if an area cannot be split.
+
+    pub fn shm_detach(&mut self, pid: usize, addr: usize) -> i32 {
+        let Some(id) = self.shm.find_attached(pid, addr) else {
+            return -EINVAL;
+        };
+        let size = self.shm.get(id).map_or(0, |segment| segment.size());
+        if self.vmas[pid].remove(addr, addr + size).is_err() {
+            return -ENOMEM;
+        }
+        self.detach_segment(pid, id);
+        0
+    }
+
+    // detach_segment(pid, id)
+    //    Unmap shared memory segment `id` from process `pid`, which has it
+    //    attached, dropping the process's references to its pages. Pages the
+    //    process owned pass to another attached process; when none is left,
+    //    the pages are free and the segment is released.
+
+    fn detach_segment(&mut self, pid: usize, id: usize) {
+        let Some(segment) = self.shm.get(id).copied() else {
+            return;
+        };
+        let addr = segment.attached_at[pid];
+        self.unmap_range(pid, addr, addr + segment.size());
+        let Some(segment) = self.shm.get_mut(id) else {
+            return;
+        };
+        segment.attached_at[pid] = 0;
+        let Some(heir) = segment.attachers().next() else {
+            self.shm.release(id);
+            return;
+        };
+        for &pa in &segment.pages[..segment.npages] {
+            let page = self.pageinfo_table.get_page_info_ref(pa.page_number().as_usize());
+            if page.owner == pid as PidT {
+                page.owner = heir as PidT;
+            }
+        }
+    }
+
+    // setrlimit(caller, pid, resource, limit)
+    //    Set the RLIMIT_* `resource` limit of process `pid` (0 for `caller`
+    //    itself) to `limit` on behalf of process `caller`. A process may
+    //    change its own limits and its children's, but never above its own.
+    //    Returns 0 on success, -EINVAL for an unknown resource or a free
+    //    process, and -EPERM otherwise.
+
+    pub fn setrlimit(&mut self, caller: usize, pid: usize, resource: u64, limit: usize) -> i32 {
+        let target = if pid == 0 { caller } else { pid };
+        if target >= NPROC || self.proc_table.processes[target].p_state == P_FREE {
+            return -EINVAL;
+        }
+        let Some(own_limit) = self.proc_table.get_process_by_pid(caller).p_rlimits.get(resource) else {
+            return -EINVAL;
+        };
+        let p = self.proc_table.get_process_by_pid_mut(target);
+        if (target != caller && p.p_parent != caller as PidT) || limit > own_limit {
+            return -EPERM;
+        }
+        if let Some(value) = p.p_rlimits.get_mut(resource) {
+            *value = limit;
+        }
+        0
+    }
+
+    // memstats(pid)
+    //    Returns the memory usage and limits of process `pid`.
+
+    pub fn memstats(&self, pid: usize) -> MemStats {
+        let p = self.proc_table.get_process_by_pid(pid);
+        MemStats {
+            resident_pages: self.pageinfo_table.pages_owned_by(pid as PidT),
+            heap_bytes: p.p_brk - p.p_heap_start,
+            children: self.proc_table.count_children(pid as PidT),
+            limits: p.p_rlimits,
+            free_pages: self.pageinfo_table.fragmentation_stats().free_pages,
+            total_pages: self.pageinfo_table.pageinfo.len(),
+            swap_ins: self.swap.swap_ins,
+            swap_outs: self.swap.swap_outs,
+        }
+    }
+
+    // dump_maps(caller, pid)
+    //    Log the user-accessible mappings of process `pid` (0 for `caller`
+    //    itself) to `log.txt`, one line per run of pages in the style of
+    //    `/proc/PID/maps`: virtual range, permissions (`s` for shared memory,
+    //    `p` otherwise), first physical address and what the pages hold.
+    //    Returns 0 on success and -EINVAL for an invalid or free process.
+
+    pub fn dump_maps(&self, caller: usize, pid: usize) -> i32 {
+        let target = if pid == 0 { caller } else { pid };
+        if target >= NPROC || self.proc_table.processes[target].p_state == P_FREE {
+            return -EINVAL;
+        }
+        let pagetable = self.proc_table.get_process_by_pid(target).p_pagetable;
+        let page = PAGESIZE as usize;
+        unsafe { log_printf(c"process %d maps:\n".as_ptr(), target as i32) };
+
+        let mut runs = [MappingRun::default(); 16];
+        let mut va = 0;
+        loop {
+            let n = unsafe { virtual_memory_mappings(pagetable, va, runs.as_mut_ptr(), runs.len()) };
+            for run in runs[..n].iter().filter(|run| run.perm & PTE_U != 0) {
+                // split the run wherever the pages start holding something else
+                let mut start = run.start;
+                while start < run.end {
+                    let label = self.maps_label(target, start);
+                    let mut end = start + page;
+                    while end < run.end && self.maps_label(target, end) == label {
+                        end += page;
+                    }
+                    let write = if run.perm & PTE_W != 0 { b'w' } else { b'-' };
+                    let exec = if run.perm & PTE_NX == 0 { b'x' } else { b'-' };
+                    let share = if label == c"[shm]" { b's' } else { b'p' };
+                    unsafe {
+                        log_printf(c"%08lx-%08lx r%c%c%c %08lx %s\n".as_ptr(), start, end,
+                            write as i32, exec as i32, share as i32, run.pa + (start - run.start), label.as_ptr());
+                    }
+                    start = end;
+                }
+            }
+            match runs[..n].last() {
+                Some(last) if n == runs.len() && last.end != 0 => va = last.end,
+                _ => return 0,
+            }
+        }
+    }
+
+    // maps_label(pid, va)
+    //    Returns what process `pid`'s page at `va` holds, as `dump_maps`
+    //    names it.
+
+    fn maps_label(&self, pid: usize, va: usize) -> &'static core::ffi::CStr {
+        let p = self.proc_table.get_process_by_pid(pid);
+        if va < PROC_START_ADDR as usize {
+            c"[kernel]"
+        } else if self.shm.overlaps_attached(pid, va, va + PAGESIZE as usize) {
+            c"[shm]"
+        } else if va >= p.p_stack_bottom && va < MEMSIZE_VIRTUAL as usize {
+            c"[stack]"
+        } else if va >= p.p_heap_start && va < p.p_brk {
+            c"[heap]"
+        } else if va < p.p_heap_start {
+            c"[program]"
+        } else {
+            c"[anon]"
+        }
+    }
+
+    // fork()
+    //    Create a copy of the current process with copy_pagetable. The
+    //    kernel's mappings below PROC_START_ADDR, the parent's shared
+    //    memory segments and its read-only user pages (program text,
+    //    mostly) are shared, the user pages gaining a reference; every
+    //    writable user page is copied. The new page table and page copies
+    //    belong to the child, and shared pages stay the parent's. The child
+    //    inherits the parent's areas, heap and limits; the parent's
+    //    swapped-out pages are read back in first. Returns the child's pid,
+    //    -EAGAIN if no process slot is free or the parent is at its
+    //    RLIMIT_NPROC, and -ENOMEM if the child would exceed its RLIMIT_RSS
+    //    or memory runs out.
+
+    pub fn fork(&mut self) -> Result<usize, i32> {
+        let parent = self.proc_table.get_current_process();
+        let ppid = parent.p_pid as usize;
+        if self.proc_table.count_children(parent.p_pid) >= parent.p_rlimits.children {
+            return Err(-EAGAIN);
+        }
+        let child = self.proc_table.find_free_pid().ok_or(-EAGAIN)?;
+        // the child is charged for its pages under the parent's limits
+        self.proc_table.get_process_by_pid_mut(child).p_rlimits = parent.p_rlimits;
+
+        // copy_pagetable copies present pages only, and the pages it
+        // allocates may come from swap_out: pin each page once present so
+        // neither a later swap_in nor the copy evicts it
+        for va in (PROC_START_ADDR as usize..MEMSIZE_VIRTUAL as usize).step_by(PAGESIZE as usize) {
+            if self.swapped_slot(parent.p_pagetable, va).is_some() && !self.swap_in(ppid, va) {
+                self.set_pinned(ppid, false);
+                return Err(-ENOMEM);
+            }
+            self.set_page_pinned(ppid, va, true);
+        }
+
+        let mut regions = [CopyRegion { start: 0, end: PROC_START_ADDR as usize, action: CopyAction::Share };
+            1 + MAX_SHM_SEGMENTS + FORK_MAX_SHARED_RUNS];
+        let mut nregions = 1;
+        for id in 0..MAX_SHM_SEGMENTS {
+            if let Some(segment) = self.shm.get(id).filter(|segment| segment.attached_at[ppid] != 0) {
+                let start = segment.attached_at[ppid];
+                regions[nregions] = CopyRegion { start, end: start + segment.size(), action: CopyAction::Share };
+                nregions += 1;
+            }
+        }
+        let first_run = nregions;
+        for va in (PROC_START_ADDR as usize..MEMSIZE_VIRTUAL as usize).step_by(PAGESIZE as usize) {
+            let vam = unsafe { virtual_memory_lookup(parent.p_pagetable, va) };
+            if vam.pn < 0 || vam.perm & (PTE_W | PTE_U) as i32 != PTE_U as i32 {
+                continue;
+            }
+            if nregions > first_run && regions[nregions - 1].end == va {
+                regions[nregions - 1].end += PAGESIZE as usize;
+            } else if nregions < regions.len() {
+                regions[nregions] = CopyRegion { start: va, end: va + PAGESIZE as usize, action: CopyAction::Share };
+                nregions += 1;
+            }
+        }
+        let mut n = 0;
+        let pt = unsafe {
+            copy_pagetable(parent.p_pagetable, regions.as_ptr(), nregions, CopyAction::Copy,
+                self.fork_pages.as_mut_ptr(), self.fork_pages.len(), &mut n)
+        };
+        self.set_pinned(ppid, false);
+        if pt.is_null() {
+            return Err(-ENOMEM);
+        }
+        if !self.within_rss_limit(child, n) {
+            for &pa in &self.fork_pages[..n] {
+                self.pageinfo_table.free_page(pa);
+            }
+            return Err(-ENOMEM);
+        }
+        for &pa in &self.fork_pages[..n] {
+            self.pageinfo_table.get_page_info_ref(pa.page_number().as_usize()).owner = child as PidT;
+        }
+        for region in &regions[first_run..nregions] {
+            for va in (region.start..region.end).step_by(PAGESIZE as usize) {
+                if let Some(pa) = unsafe { virtual_memory_lookup(parent.p_pagetable, va) }.page() {
+                    self.pageinfo_table.incref(pa);
+                }
+            }
+        }
+        for id in 0..MAX_SHM_SEGMENTS {
+            if let Some(segment) = self.shm.get(id).filter(|segment| segment.attached_at[ppid] != 0).copied() {
+                for &pa in &segment.pages[..segment.npages] {
+                    self.pageinfo_table.incref(pa);
+                }
+            }
+        }
+
+        let p = self.proc_table.get_process_by_pid_mut(child);
+        *p = Proc {
+            p_pid: child as PidT,
+            p_state: P_RUNNABLE,
+            p_pagetable: pt,
+            p_parent: parent.p_pid,
+            ..parent
+        };
+        p.p_registers.reg_rax = 0;
+        self.vmas[child] = self.vmas[parent.p_pid as usize];
+        self.shm.inherit(parent.p_pid as usize, child);
+        Ok(child)
+    }
+
+    // set_page_pinned(pid, va, pinned)
+    //    Set PF_PINNED on the page at `va` of process `pid` if `pinned`, or
+    //    clear it, provided the process owns that page. swap_out never
+    //    evicts a pinned page.
+
+    pub fn set_page_pinned(&mut self, pid: usize, va: usize, pinned: bool) {
+        let pt = self.proc_table.get_process_by_pid(pid).p_pagetable;
+        let Some(pa) = unsafe { virtual_memory_lookup(pt, va) }.page() else {
+            return;
+        };
+        if self.pageinfo_table.pageinfo[pa.page_number().as_usize()].owner != pid as PidT {
+            return;
+        }
+        if pinned {
+            self.pageinfo_table.set_flags(pa, PF_PINNED);
+        } else {
+            self.pageinfo_table.clear_flags(pa, PF_PINNED);
+        }
+    }
+
+    // set_pinned(pid, pinned)
+    //    set_page_pinned for every user page of process `pid`.
+
+    pub fn set_pinned(&mut self, pid: usize, pinned: bool) {
+        for va in (PROC_START_ADDR as usize..MEMSIZE_VIRTUAL as usize).step_by(PAGESIZE as usize) {
+            self.set_page_pinned(pid, va, pinned);
+        }
+    }
+
+    // alloc_kernel_page()
+    //    Allocates a page for the kernel's own use. If none is free, swaps a
+    //    user page out; if swap is full too and the out-of-memory killer is
+    //    enabled, kills the process owning the most pages (never the current
+    //    one). Then tries again. Returns None if memory stays exhausted.
+
+    pub fn alloc_kernel_page(&mut self) -> Option<PhysAddr> {
+        loop {
+            if let Some(pa) = self.pageinfo_table.alloc_page(PageOwner::PoKernel as PidT) {
+                return Some(pa);
+            }
+            if self.swap_out() {
+                continue;
+            }
+            if !self.oom_killer {
+                return None;
+            }
+            let current = self.proc_table.current.map(|p| unsafe { (*p).p_pid });
+            let processes = &self.proc_table.processes;
+            let (victim, npages) = self.pageinfo_table.largest_owner(|pid| {
+                Some(pid) != current && processes[pid as usize].p_state != P_FREE
+            })?;
+            unsafe {
+                log_printf(c"out of memory: killing process %d (%d pages)\n".as_ptr(),
+                    victim, npages as i32);
+            }
+            self.process_free(victim as usize);
+        }
     }
 
     // assign_physical_page(addr, owner)
@@ -140,16 +1237,28 @@ impl Kernel {
     //    success and -1 on failure. Used by the program loader.
 
     pub fn assign_physical_page(&mut self, addr: usize, owner: usize) -> i32 {
-        let pn = page_number(addr as *const u8);
-        if (addr & 0xFFF) != 0
-            || pn >= self.pageinfo_table.pageinfo.len()
-            || self.pageinfo_table.pageinfo[pn].refcount != 0 {
-           return -1;
+        match PhysAddr::new(addr) {
+            Some(pa) => self.pageinfo_table.assign(pa, owner as PidT),
+            None => -1,
+        }
+    }
+
+    // mark_pagetable_pages(pt, level)
+    //    Flag every page of the page table `pt` (at level `level`, where 0 is
+    //    the L4 table) as PF_PAGETABLE, so the ownership checks can tell page
+    //    table pages from data pages.
+
+    pub fn mark_pagetable_pages(&mut self, pt: *mut x86_64_pagetable, level: usize) {
+        if let Some(pa) = PhysAddr::from_ptr(pt) {
+            self.pageinfo_table.set_flags(pa, PF_PAGETABLE);
+        }
+        if level < 3 {
+            for index in 0..NPAGETABLEENTRIES as usize {
+                if let Some(next_pt) = unsafe { (*pt).entry[index] }.next_table() {
+                    self.mark_pagetable_pages(next_pt, level + 1);
+                }
+            }
         }
-    
-        self.pageinfo_table.pageinfo[pn].owner = owner as i8;
-        self.pageinfo_table.pageinfo[pn].refcount = 1;
-        0
     }
 
     // check_page_table_mappings
@@ -184,13 +1293,11 @@ impl Kernel {
                 }
                 
                 // FIX: my_assert! fails on multiple definitions
-                if !(vam_pa == va as usize) {
+                if vam_pa != va as usize {
                     c_panic("Assertion failed: vam_pa == va as usize".as_ptr() as *const i8);
                 }
-                if va >= start_data_addr {
-                    if !(vam_perm & PTE_W as i32 != 0) {
-                        c_panic("Assertion failed: vam_perm & PTE_W as i32 != 0".as_ptr() as *const i8);
-                    }
+                if va >= start_data_addr && vam_perm & PTE_W as i32 == 0 {
+                    c_panic("Assertion failed: vam_perm & PTE_W as i32 != 0".as_ptr() as *const i8);
                 }
             }
 
@@ -200,10 +1307,10 @@ impl Kernel {
             let vam_perm = vam.perm;
 
             // FIX: my_assert! fails on multiple definitions
-            if !(vam_pa == kstack as usize) {
+            if vam_pa != kstack as usize {
                 c_panic("Assertion failed: vam_pa == kstack as usize".as_ptr() as *const i8);
             }
-            if !(vam_perm & PTE_W as i32 != 0) {
+            if vam_perm & PTE_W as i32 == 0 {
                 c_panic("Assertion failed: vam_perm & PTE_W as i32 != 0".as_ptr() as *const i8);
             }
         }
@@ -235,21 +1342,21 @@ impl Kernel {
     #[allow(unused)]
     pub fn check_page_table_ownership_level(&self, pt: *mut x86_64_pagetable, level: usize, owner: i32, refcount: u32) {
         unsafe {
-            let page_number = (pt as usize) / PAGESIZE as usize;
+            let page_number = PhysAddr::from_ptr(pt).map_or(usize::MAX, |pa| pa.page_number().as_usize());
             // FIX: my_assert! fails on multiple definitions
-            if !(page_number < NPAGES as usize) {
-                c_panic("Assertion failed: page_number < NPAGES as usize".as_ptr() as *const i8);
-            } else if !(self.pageinfo_table.pageinfo[page_number].owner == owner as i8) {
+            if page_number >= self.pageinfo_table.pageinfo.len() {
+                c_panic(c"Assertion failed: page_number < pageinfo.len()".as_ptr());
+            } else if self.pageinfo_table.pageinfo[page_number].owner != owner {
                 c_panic("Assertion failed: pageinfo[page_number].owner == owner".as_ptr() as *const i8);
-            } else if !(self.pageinfo_table.pageinfo[page_number].refcount == refcount as i8) {
+            } else if self.pageinfo_table.pageinfo[page_number].refcount != refcount {
                 c_panic("Assertion failed: pageinfo[page_number].refcount == refcount".as_ptr() as *const i8);
+            } else if self.pageinfo_table.pageinfo[page_number].flags & PF_PAGETABLE == 0 {
+                c_panic(c"Assertion failed: pageinfo[page_number].flags & PF_PAGETABLE".as_ptr());
             }
 
             if level < 3 {
                 for index in 0..NPAGETABLEENTRIES {
-                    let entry = (*pt).entry[index as usize];
-                    if entry != 0 {
-                        let next_pt = (entry & !0xFFF) as *mut x86_64_pagetable;
+                    if let Some(next_pt) = (*pt).entry[index as usize].next_table() {
                         self.check_page_table_ownership_level(next_pt, level + 1, owner, 1);
                     }
                 }
@@ -257,13 +1364,43 @@ impl Kernel {
         }
     }
 
+    // check_user_page_flags(pt)
+    //    Check that the page flags agree with how page table `pt` maps
+    //    process memory: no user page holds a page table, a copy-on-write
+    //    entry is read-only and maps a PF_COW page outside any shared
+    //    memory segment, and a writable entry never maps a PF_COW page.
+    //    Panic if any of the invariants are false.
+
+    fn check_user_page_flags(&self, pt: *mut x86_64_pagetable) {
+        for va in (PROC_START_ADDR as usize..MEMSIZE_VIRTUAL as usize).step_by(PAGESIZE as usize) {
+            let Some(entry) = pte_entry(pt, va).map(|pte| unsafe { *pte }) else {
+                continue;
+            };
+            let Some(page) = self.pageinfo_table.pageinfo.get(entry.addr().page_number().as_usize())
+                .filter(|_| entry.has(PTE_P | PTE_U)) else {
+                continue;
+            };
+            unsafe {
+                if page.flags & PF_PAGETABLE != 0 {
+                    c_panic(c"Assertion failed: user page is not PF_PAGETABLE".as_ptr());
+                }
+                if entry.has(PTE_COW) && (entry.has(PTE_W) || page.flags & (PF_COW | PF_SHARED) != PF_COW) {
+                    c_panic(c"Assertion failed: PTE_COW page is read-only, PF_COW and not PF_SHARED".as_ptr());
+                }
+                if entry.has(PTE_W) && page.flags & PF_COW != 0 {
+                    c_panic(c"Assertion failed: writable page is not PF_COW".as_ptr());
+                }
+            }
+        }
+    }
+
     // check_virtual_memory
     //    Check operating system invariants about virtual memory. Panic if any
     //    of the invariants are false.
 
     pub fn check_virtual_memory(&mut self) {
         unsafe {
-            if !(self.proc_table.processes[0].p_state == P_FREE) {
+            if self.proc_table.processes[0].p_state != P_FREE {
                 c_panic("Assertion failed: processes[0].p_state == P_FREE".as_ptr() as *const i8);
             }
     
@@ -275,14 +1412,21 @@ impl Kernel {
                 if proc.p_state != P_FREE && proc.p_pagetable != kernel_pagetable {
                     self.check_page_table_mappings(proc.p_pagetable);
                     self.check_page_table_ownership(proc.p_pagetable, pid as i32);
+                    self.check_user_page_flags(proc.p_pagetable);
                 }
             }
     
-            for pn in 0..page_number(MEMSIZE_PHYSICAL as *const u8) {
+            for pn in 0..self.pageinfo_table.pageinfo.len() {
+                if (self.pageinfo_table.pageinfo[pn].refcount == 0) != self.pageinfo_table.is_free(pn) {
+                    c_panic("Assertion failed: pageinfo[pn].refcount == 0 iff page pn is free".as_ptr() as *const i8);
+                }
                 let page = self.pageinfo_table.get_page_info_ref(pn);
+                if page.refcount == 0 && page.flags != 0 {
+                    c_panic(c"Assertion failed: free page pn has no flags".as_ptr());
+                }
                 if page.refcount > 0 && page.owner >= 0 {
                     let p = self.proc_table.get_process_by_pid(page.owner as usize);
-                    if !(p.p_state != P_FREE) {
+                    if p.p_state == P_FREE {
                         c_panic("Assertion failed: processes[page.owner as usize].p_state != P_FREE".as_ptr() as *const i8);
                     }
                 }
@@ -290,6 +1434,102 @@ impl Kernel {
         }
     }
 
+    // read_process_message(p, addr)
+    //    Copy the NUL-terminated message at virtual address `addr` in process
+    //    `p` into a kernel buffer, as used by the panic and test syscalls.
+    //    Longer messages are cut at 159 bytes. The message is read a page at
+    //    a time, stopping at its NUL, so it may end just before an unmapped
+    //    page. Returns an empty message if `addr` is 0, and None if the
+    //    message runs into memory the process cannot read.
+
+    fn read_process_message(&mut self, p: &Proc, addr: u64) -> Option<[u8; 160]> {
+        let mut msg = [0u8; 160];
+        let (va, max) = (addr as usize, msg.len() - 1);
+        let mut copied = 0;
+        while addr != 0 && copied < max {
+            let src = va.checked_add(copied)?;
+            let n = (PAGESIZE as usize - (src & PAGE_OFF_MASK)).min(max - copied);
+            if !self.copy_from_process(p, src, &mut msg[copied..copied + n]) {
+                return None;
+            }
+            if msg[copied..copied + n].contains(&0) {
+                break;
+            }
+            copied += n;
+        }
+        Some(msg)
+    }
+
+    // copy_from_process(p, va, data)
+    //    Fill `data` from virtual address `va` in process `p`, first reading
+    //    back swapped-out pages of the source and backing reserved pages
+    //    that were never touched. Returns false unless every byte of the
+    //    source is then mapped present and user-accessible.
+
+    fn copy_from_process(&mut self, p: &Proc, va: usize, data: &mut [u8]) -> bool {
+        let Some(end) = va.checked_add(data.len()) else {
+            return false;
+        };
+        let readable = (PTE_P | PTE_U) as i32;
+        let page_start = va & !PAGE_OFF_MASK;
+        for page in (page_start..end).step_by(PAGESIZE as usize) {
+            let vam = unsafe { virtual_memory_lookup(p.p_pagetable, page) };
+            if vam.perm & readable != readable
+                && !self.handle_page_fault(p.p_pid as usize, page, PFERR_USER as u64) {
+                return false;
+            }
+        }
+
+        // the source may span physically discontiguous pages
+        let mut copied = 0;
+        while copied < data.len() {
+            let src = va + copied;
+            let n = (PAGESIZE as usize - (src & PAGE_OFF_MASK)).min(data.len() - copied);
+            let vam = unsafe { virtual_memory_lookup(p.p_pagetable, src) };
+            if vam.pn < 0 {
+                return false; // swapped out again while backing a later page
+            }
+            unsafe { core::ptr::copy_nonoverlapping(vam.pa as *const u8, data[copied..].as_mut_ptr(), n); }
+            copied += n;
+        }
+        true
+    }
+
+    // copy_to_process(p, va, data)
+    //    Copy `data` to virtual address `va` in process `p`, first backing
+    //    any reserved pages of the destination that were never touched.
+    //    Returns false, having copied nothing, unless every byte of the
+    //    destination is then mapped present, writable and user-accessible.
+
+    fn copy_to_process(&mut self, p: &Proc, va: usize, data: &[u8]) -> bool {
+        let Some(end) = va.checked_add(data.len()) else {
+            return false;
+        };
+        let writable = (PTE_P | PTE_W | PTE_U) as i32;
+        let page_start = va & !PAGE_OFF_MASK;
+        for page in (page_start..end).step_by(PAGESIZE as usize) {
+            let vam = unsafe { virtual_memory_lookup(p.p_pagetable, page) };
+            if vam.perm & writable != writable
+                && !self.handle_page_fault(p.p_pid as usize, page, (PFERR_USER | PFERR_WRITE) as u64) {
+                return false;
+            }
+        }
+
+        // the destination may span physically discontiguous pages
+        let mut copied = 0;
+        while copied < data.len() {
+            let dst = va + copied;
+            let n = (PAGESIZE as usize - (dst & PAGE_OFF_MASK)).min(data.len() - copied);
+            let vam = unsafe { virtual_memory_lookup(p.p_pagetable, dst) };
+            if vam.pn < 0 {
+                return false; // swapped out again while backing a later page
+            }
+            unsafe { core::ptr::copy_nonoverlapping(data[copied..].as_ptr(), vam.pa as *mut u8, n); }
+            copied += n;
+        }
+        true
+    }
+
     // exception(reg)
     //    Exception handler (for interrupts, traps, and faults).
     //
@@ -307,11 +1547,6 @@ impl Kernel {
             fn check_keyboard() -> core::ffi::c_int;
             fn console_show_cursor(cpos: core::ffi::c_int);
             fn default_exception(p: *mut Proc);
-            fn memcpy(
-                dst: *mut core::ffi::c_void,
-                src: *const core::ffi::c_void,
-                n: usize,
-            ) -> *mut ::std::os::raw::c_void;
             fn console_printf(
                 cpos: i32,
                 color: i32,
@@ -337,7 +1572,8 @@ impl Kernel {
         {
             self.check_virtual_memory();
             if DISP_GLOBAL.load(Ordering::SeqCst) != 0 {
-                unsafe{ memshow_physical(); }
+                memshow_physical(&self.pageinfo_table);
+                memshow_fragmentation(&self.pageinfo_table.fragmentation_stats());
                 unsafe{ memshow_virtual_animate(); }
             }
         }
@@ -355,18 +1591,27 @@ impl Kernel {
                     unsafe {
                         c_panic("(exception) current process has not been set yet".as_ptr() as *const core::ffi::c_char);
                     }
-                } else {
+                } else if let Some(msg) = self.read_process_message(&curr_proc, addr) {
                     unsafe {
-                        let map = virtual_memory_lookup(curr_proc.p_pagetable, addr as usize);
-                        let mut msg = [0u8; 160];
-                        memcpy(
-                            &mut msg as *mut [u8; 160] as *mut core::ffi::c_void, 
-                            map.pa as *const core::ffi::c_void, 
-                            160
-                        );
-                        c_panic(msg.as_ptr() as *const i8);
+                        // the message is the process's, so never a format
+                        c_panic(c"%s".as_ptr(), msg.as_ptr());
                         /* will not be reached */
                     }
+                } else {
+                    self.proc_table.set_register_rax(-EFAULT as u64);
+                }
+            }
+            INT_SYS_TEST_EXIT => {
+                // rdi stores the test status (0 is a pass), rsi the message
+                let status = curr_proc.p_registers.reg_rdi as i32;
+                match self.read_process_message(&curr_proc, curr_proc.p_registers.reg_rsi) {
+                    Some(msg) => unsafe {
+                        log_printf(c"%s\n".as_ptr(), msg.as_ptr());
+                        qemu_exit(if status == 0 { QEMU_EXIT_SUCCESS } else { QEMU_EXIT_FAILURE });
+                        // No exit device: show the result and wait for Control-C.
+                        c_panic(c"%s".as_ptr(), msg.as_ptr());
+                    },
+                    None => self.proc_table.set_register_rax(-EFAULT as u64),
                 }
             }
             INT_SYS_GETPID => {
@@ -378,28 +1623,133 @@ impl Kernel {
             }
             INT_SYS_PAGE_ALLOC => {
                 let addr = curr_proc.p_registers.reg_rdi;
-                let r = self.assign_physical_page(
-                    addr as usize, 
+                let r = self.page_reserve(curr_proc.p_pid as usize, addr as usize);
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_FORK => {
+                let r = match self.fork() {
+                    Ok(pid) => pid as i32,
+                    Err(error) => error,
+                };
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_BRK => {
+                let r = self.brk(curr_proc.p_pid as usize, curr_proc.p_registers.reg_rdi as usize);
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_SBRK => {
+                let old = curr_proc.p_brk;
+                let increment = curr_proc.p_registers.reg_rdi as isize;
+                let r = match old.checked_add_signed(increment) {
+                    Some(addr) if self.brk(curr_proc.p_pid as usize, addr) == 0 => old as u64,
+                    _ => u64::MAX, // (void *) -1
+                };
+                self.proc_table.set_register_rax(r);
+            }
+            INT_SYS_SETRLIMIT => {
+                let r = self.setrlimit(
                     curr_proc.p_pid as usize,
+                    curr_proc.p_registers.reg_rdi as PidT as usize,
+                    curr_proc.p_registers.reg_rsi,
+                    curr_proc.p_registers.reg_rdx as usize,
                 );
-                if r >= 0 {
-                    unsafe { 
-                        virtual_memory_map(
-                            curr_proc.p_pagetable, 
-                            addr as usize,
-                            addr as usize,
-                            PAGESIZE as usize,
-                            (PTE_P | PTE_W | PTE_U) as u32,
-                        );
-                    }
-                }
                 self.proc_table.set_register_rax(r as u64);
             }
+            INT_SYS_MEMSTATS => {
+                let stats = self.memstats(curr_proc.p_pid as usize);
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(&stats as *const MemStats as *const u8,
+                        size_of::<MemStats>())
+                };
+                let r = if self.copy_to_process(&curr_proc, curr_proc.p_registers.reg_rdi as usize, bytes) {
+                    0
+                } else {
+                    -EINVAL
+                };
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_MMAP => {
+                let r = self.mmap(
+                    curr_proc.p_pid as usize,
+                    curr_proc.p_registers.reg_rdi as usize,
+                    curr_proc.p_registers.reg_rsi as usize,
+                    curr_proc.p_registers.reg_rdx,
+                    curr_proc.p_registers.reg_rcx,
+                );
+                let r = match r {
+                    Ok(addr) => addr as u64,
+                    Err(_) => u64::MAX, // MAP_FAILED
+                };
+                self.proc_table.set_register_rax(r);
+            }
+            INT_SYS_MUNMAP => {
+                let r = self.munmap(
+                    curr_proc.p_pid as usize,
+                    curr_proc.p_registers.reg_rdi as usize,
+                    curr_proc.p_registers.reg_rsi as usize,
+                );
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_MPROTECT => {
+                let r = self.mprotect(
+                    curr_proc.p_pid as usize,
+                    curr_proc.p_registers.reg_rdi as usize,
+                    curr_proc.p_registers.reg_rsi as usize,
+                    curr_proc.p_registers.reg_rdx,
+                );
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_SHM_CREATE => {
+                let r = self.shm_create(
+                    curr_proc.p_registers.reg_rdi as u32 as u64, // an int key
+                    curr_proc.p_registers.reg_rsi as usize,
+                );
+                let r = match r {
+                    Ok(id) => id as i32,
+                    Err(error) => error,
+                };
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_SHM_ATTACH => {
+                let r = self.shm_attach(
+                    curr_proc.p_pid as usize,
+                    curr_proc.p_registers.reg_rdi as usize,
+                    curr_proc.p_registers.reg_rsi as usize,
+                );
+                let r = match r {
+                    Ok(addr) => addr as u64,
+                    Err(_) => u64::MAX, // MAP_FAILED
+                };
+                self.proc_table.set_register_rax(r);
+            }
+            INT_SYS_SHM_DETACH => {
+                let r = self.shm_detach(
+                    curr_proc.p_pid as usize,
+                    curr_proc.p_registers.reg_rdi as usize,
+                );
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_MAPS => {
+                let r = self.dump_maps(
+                    curr_proc.p_pid as usize,
+                    curr_proc.p_registers.reg_rdi as PidT as usize,
+                );
+                self.proc_table.set_register_rax(r as u64);
+            }
+            INT_SYS_EXIT => {
+                self.process_free(curr_proc.p_pid as usize);
+                self.proc_table.schedule();
+                /* will not be reached */
+            }
             INT_SYS_MAPPING => {
-                unsafe {
-                    let current = self.proc_table.get_current_process_mut();
-                    syscall_mapping(&mut *current);
-                }
+                // rdi stores where the mapping goes, rsi the address to look
+                // up; nothing is written unless the destination is writable
+                let vam = unsafe { virtual_memory_lookup(curr_proc.p_pagetable, curr_proc.p_registers.reg_rsi as usize) };
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(&vam as *const VAMapping as *const u8,
+                        size_of::<VAMapping>())
+                };
+                self.copy_to_process(&curr_proc, curr_proc.p_registers.reg_rdi as usize, bytes);
             }
             INT_SYS_MEM_TOG => {
                 unsafe {
@@ -413,25 +1763,52 @@ impl Kernel {
                 /* will not be reached */
             }
             INT_PAGEFAULT => {
-                let current = self.proc_table.get_current_process_mut();
                 // Analyze faulting address and access type.
-                // let addr = unsafe { rcr2() };
-                // let operation = if reg.reg_err & PFERR_WRITE as u64 != 0 { "write" } else { "read" };
-                // let problem = if reg.reg_err & PFERR_PRESENT as u64 != 0 { "protection problem" } else { "missing page" };
+                let addr = unsafe { rcr2() } as usize;
+                let operation = if reg.reg_err & PFERR_FETCH as u64 != 0 {
+                    c"execute"
+                } else if reg.reg_err & PFERR_WRITE as u64 != 0 {
+                    c"write"
+                } else {
+                    c"read"
+                };
+                let problem = if reg.reg_err & PFERR_PRESENT as u64 != 0 {
+                    c"protection problem"
+                } else {
+                    c"missing page"
+                };
 
                 if reg.reg_err & PFERR_USER as u64 == 0 {
                     unsafe {
-                        c_panic("Kernel page fault!".as_ptr() as *const core::ffi::c_char);
+                        c_panic(c"Kernel page fault for %p (%s %s, rip=%p)!\n".as_ptr(),
+                            addr, operation.as_ptr(), problem.as_ptr(), reg.reg_rip);
                     }
                 }
-                unsafe {
-                    console_printf(
-                        cpos!(24, 0), 
-                        0x0C00, 
-                        "Process page faule!".as_ptr() as *const u8,
-                    );
+                // A first touch of a reserved page just gets it backed, and a
+                // touch of the stack's guard page grows the stack; any other
+                // fault kills the process.
+                let pid = curr_proc.p_pid as usize;
+                if self.in_stack_guard(pid, addr) && self.grow_stack(pid) < 0 {
+                    unsafe {
+                        console_printf(
+                            cpos!(24, 0),
+                            0x0C00,
+                            c"Process %d stack overflow at %p (rip=%p)!\n".as_ptr() as *const u8,
+                            pid as i32, addr, reg.reg_rip,
+                        );
+                    }
+                    self.process_free(pid);
+                } else if !self.handle_page_fault(pid, addr, reg.reg_err) {
+                    unsafe {
+                        console_printf(
+                            cpos!(24, 0),
+                            0x0C00,
+                            c"Process %d page fault for %p (%s %s, rip=%p)!\n".as_ptr() as *const u8,
+                            pid as i32, addr, operation.as_ptr(), problem.as_ptr(), reg.reg_rip,
+                        );
+                    }
+                    self.process_free(pid);
                 }
-                current.p_state = P_BROKEN;
             }
             _ => {
                 unsafe {
@@ -443,7 +1820,7 @@ impl Kernel {
         }
 
         // Return to the current process (or run something else).
-        if curr_proc.p_state == P_RUNNABLE {
+        if self.proc_table.get_process_by_pid(curr_proc.p_pid as usize).p_state == P_RUNNABLE {
             self.proc_table.run(curr_proc.p_pid as usize);
         } else {
             self.proc_table.schedule();
@@ -451,40 +1828,31 @@ impl Kernel {
     }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn syscall_mapping(p: &mut Proc) {
-    extern "C" {
-        fn memcpy(
-            dst: *mut core::ffi::c_void,
-            src: *const core::ffi::c_void,
-            n: usize,
-        ) -> *mut ::std::os::raw::c_void;
+// prot_perm(prot)
+//    Returns the area permissions for PROT_* protection `prot`. PROT_NONE
+//    areas have no PTE_P, so their pages are never backed; areas without
+//    PROT_EXEC are PTE_NX.
+
+fn prot_perm(prot: u64) -> u64 {
+    let nx = if prot & PROT_EXEC != 0 { 0 } else { PTE_NX };
+    match prot {
+        PROT_NONE => 0,
+        _ if prot & PROT_WRITE != 0 => PTE_P | PTE_W | PTE_U | nx,
+        _ => PTE_P | PTE_U | nx,
     }
-    
-    let mapping_ptr = p.p_registers.reg_rdi;
-    let ptr = p.p_registers.reg_rsi;
-
-    // convert to physical address so the kernel can write to it
-    let map = virtual_memory_lookup(p.p_pagetable, mapping_ptr as usize);
-
-    // check for write access
-    if (map.perm & (PTE_W | PTE_U) as i32) != (PTE_W | PTE_U) as i32 {
-        return;
-    }
-    let endaddr = mapping_ptr + size_of::<VAMapping>() as u64 - 1;
-    // check for write access for the end address
-    let end_map = virtual_memory_lookup(p.p_pagetable, endaddr as usize);
-    if (end_map.perm & (PTE_W | PTE_P) as i32) != (PTE_W | PTE_P) as i32 {
-        return;
-    }
-    // find the actual mapping now
-    let ptr_lookup = virtual_memory_lookup(p.p_pagetable, ptr as usize);
-    unsafe {
-        let dest = map.pa as *mut VAMapping;
-        let src = &ptr_lookup as *const VAMapping as *const core::ffi::c_void;
-        let size = core::mem::size_of::<VAMapping>();
-        memcpy(dest as *mut core::ffi::c_void, src, size);
+}
+
+// pte_entry(pt, va)
+//    Returns the L1 page table entry for `va` in page table `pt`, or None if
+//    no L1 table covers `va`.
+
+pub fn pte_entry(pt: *mut x86_64_pagetable, va: usize) -> Option<*mut PageTableEntry> {
+    let va = VirtAddr::new(va)?;
+    let mut table = pt;
+    for level in 0..3 {
+        table = unsafe { (*table).entry[va.index(level)] }.next_table()?;
     }
+    Some(unsafe { &mut (*table).entry[l1_index(va)] })
 }
 
 #[no_mangle]
@@ -500,3 +1868,353 @@ pub unsafe extern "C" fn syscall_mem_tog(process: &mut Proc) {
         process.display_status = !process.display_status;
     }
 }
+
+
+// Self tests (run with the `selftest` boot command)
+
+kernel_test! {
+    fn assign_physical_page_claims_free_page_once(kernel: &mut Kernel) {
+        let pn = match kernel.pageinfo_table.pageinfo.iter().position(|p| p.refcount == 0) {
+            Some(pn) => pn,
+            None => return Err("no free physical page"),
+        };
+        let addr = pn * PAGESIZE as usize;
+        let pa = match PageNumber::new(pn) {
+            Some(pn) => pn.address(),
+            None => return Err("page number out of range"),
+        };
+
+        test_assert!(kernel.assign_physical_page(addr + 1, 1) < 0);
+        test_assert!(kernel.assign_physical_page(addr, 1) == 0);
+        test_assert!(kernel.pageinfo_table.pageinfo[pn].owner == 1);
+        test_assert!(kernel.pageinfo_table.pageinfo[pn].refcount == 1);
+        test_assert!(kernel.assign_physical_page(addr, 2) < 0);
+
+        test_assert!(kernel.pageinfo_table.free_page(pa) == Some(0));
+        test_assert!(kernel.pageinfo_table.pageinfo[pn].owner == PageOwner::PoFree as PidT);
+    }
+}
+
+kernel_test! {
+    fn kernel_pagetable_identity_maps_kernel(_kernel: &mut Kernel) {
+        unsafe {
+            let vam = virtual_memory_lookup(kernel_pagetable, KERNEL_START_ADDR as usize);
+            test_assert!(vam.pa == KERNEL_START_ADDR as usize);
+            let kstack = (KERNEL_STACK_TOP - PAGESIZE) as usize;
+            let vam = virtual_memory_lookup(kernel_pagetable, kstack);
+            test_assert!(vam.pa == kstack);
+            test_assert!(vam.perm & PTE_W as i32 != 0);
+        }
+    }
+}
+
+kernel_test! {
+    fn kernel_pagetable_identity_maps_physical_memory(kernel: &mut Kernel) {
+        let last = (kernel.pageinfo_table.pageinfo.len() - 1) * PAGESIZE as usize;
+        unsafe {
+            let vam = virtual_memory_lookup(kernel_pagetable, last);
+            test_assert!(vam.pa == last);
+            test_assert!(vam.perm & PTE_W as i32 != 0);
+        }
+    }
+}
+
+kernel_test! {
+    fn process_free_releases_process_pages(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        test_assert!(kernel.proc_table.get_process_by_pid(1).p_state == P_RUNNABLE);
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) > 0);
+
+        kernel.process_free(1);
+        test_assert!(kernel.proc_table.get_process_by_pid(1).p_state == P_FREE);
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == 0);
+    }
+}
+
+kernel_test! {
+    fn fork_copies_writable_pages_and_shares_text(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        kernel.proc_table.current = Some(kernel.proc_table.get_process_by_pid_mut(1) as *mut Proc);
+        let child = kernel.fork();
+        kernel.proc_table.current = None;
+        let child = match child {
+            Ok(pid) => pid,
+            Err(_) => return Err("fork failed"),
+        };
+
+        let p = *kernel.proc_table.get_process_by_pid(child);
+        test_assert!(p.p_state == P_RUNNABLE && p.p_registers.reg_rax == 0);
+        test_assert!(p.p_pagetable != unsafe { kernel_pagetable });
+        let parent_pt = kernel.proc_table.get_process_by_pid(1).p_pagetable;
+        let lookup = |va: usize| unsafe { (virtual_memory_lookup(parent_pt, va), virtual_memory_lookup(p.p_pagetable, va)) };
+        let page = |kernel: &Kernel, vam: VAMapping| kernel.pageinfo_table.pageinfo[vam.pn as usize];
+
+        // writable pages are copied, and the copies are the child's...
+        let stack = MEMSIZE_VIRTUAL as usize - PAGESIZE as usize;
+        let (parent_vam, child_vam) = lookup(stack);
+        test_assert!(child_vam.pa != parent_vam.pa && page(kernel, child_vam).owner == child as PidT);
+        // copies keep the whole entry, PTE_NX included
+        test_assert!(pte_entry(p.p_pagetable, stack).is_some_and(|pte| unsafe { (*pte).has(PTE_NX) }));
+        // ...while read-only text is shared and stays the parent's
+        let text = p.p_registers.reg_rip as usize;
+        let (parent_vam, child_vam) = lookup(text);
+        test_assert!(child_vam.pa == parent_vam.pa && child_vam.perm & PTE_W as i32 == 0);
+        test_assert!(page(kernel, child_vam).refcount == 2 && page(kernel, child_vam).owner == 1);
+        test_assert!(kernel.pageinfo_table.pages_owned_by(child as PidT) < kernel.pageinfo_table.pages_owned_by(1));
+    }
+}
+
+kernel_test! {
+    fn oom_killer_kills_largest_process(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        test_assert!(kernel.process_setup(2, b"allocator2") == 0);
+        // pages hoarded by an unused pid, which the killer never picks
+        let hoarder = (NPROC - 1) as PidT;
+        test_assert!(kernel.assign_physical_page(PROC_START_ADDR as usize + 2 * PROC_SIZE, 2) == 0);
+        while kernel.pageinfo_table.alloc_page(hoarder).is_some() {}
+
+        kernel.oom_killer = false;
+        let without_killer = kernel.alloc_kernel_page();
+        kernel.oom_killer = true;
+        let with_killer = kernel.alloc_kernel_page();
+        kernel.oom_killer = OOM_KILLER;
+
+        let killed = kernel.proc_table.get_process_by_pid(2).p_state == P_FREE;
+        let survived = kernel.proc_table.get_process_by_pid(1).p_state == P_RUNNABLE;
+        for pn in 0..kernel.pageinfo_table.pageinfo.len() {
+            if kernel.pageinfo_table.pageinfo[pn].owner == hoarder {
+                kernel.pageinfo_table.free_page(page_address(pn));
+            }
+        }
+        if let Some(pa) = with_killer {
+            kernel.pageinfo_table.free_page(pa);
+        }
+
+        test_assert!(without_killer.is_none());
+        test_assert!(with_killer.is_some());
+        test_assert!(killed && survived);
+    }
+}
+
+kernel_test! {
+    fn brk_respects_heap_limit(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        test_assert!(p.p_heap_start == p.p_brk && p.p_heap_start.is_multiple_of(PAGESIZE as usize));
+        let owned = kernel.pageinfo_table.pages_owned_by(1);
+
+        kernel.proc_table.get_process_by_pid_mut(1).p_rlimits.heap_bytes = 2 * PAGESIZE as usize;
+        test_assert!(kernel.brk(1, p.p_heap_start + 3 * PAGESIZE as usize) == -ENOMEM);
+        test_assert!(kernel.brk(1, p.p_heap_start - 1) == -EINVAL);
+        test_assert!(kernel.brk(1, p.p_heap_start + PAGESIZE as usize + 1) == 0);
+        // heap pages are backed when first touched
+        let second = p.p_heap_start + PAGESIZE as usize;
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == owned);
+        test_assert!(kernel.handle_page_fault(1, second, (PFERR_USER | PFERR_WRITE) as u64));
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == owned + 1);
+        let vam = unsafe { virtual_memory_lookup(p.p_pagetable, second) };
+        test_assert!(vam.perm == (PTE_P | PTE_W | PTE_U) as i32);
+
+        test_assert!(kernel.brk(1, p.p_heap_start) == 0);
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == owned);
+        test_assert!(!kernel.handle_page_fault(1, second, (PFERR_USER | PFERR_WRITE) as u64));
+        test_assert!(kernel.proc_table.get_process_by_pid(1).p_brk == p.p_heap_start);
+    }
+}
+
+kernel_test! {
+    fn fork_respects_child_limit(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        test_assert!(kernel.setrlimit(1, 0, RLIMIT_NPROC, 0) == 0);
+        test_assert!(kernel.setrlimit(1, 0, RLIMIT_NPROC, 1) == -EPERM);
+        test_assert!(kernel.setrlimit(1, 0, 4, 1) == -EINVAL);
+        test_assert!(kernel.memstats(1).limits.children == 0);
+        kernel.proc_table.current = Some(kernel.proc_table.get_process_by_pid_mut(1) as *mut Proc);
+        let child = kernel.fork();
+        kernel.proc_table.current = None;
+        test_assert!(child == Err(-EAGAIN));
+    }
+}
+
+kernel_test! {
+    fn process_message_must_be_readable(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        test_assert!(kernel.read_process_message(&p, p.p_heap_start as u64).is_none());
+        test_assert!(kernel.read_process_message(&p, u64::MAX).is_none());
+
+        // a message ending just before an unmapped page can be read...
+        let last = MEMSIZE_VIRTUAL as usize - 4;
+        test_assert!(kernel.copy_to_process(&p, last, b"ok!\0"));
+        test_assert!(kernel.read_process_message(&p, last as u64).is_some_and(|msg| msg.starts_with(b"ok!\0")));
+        // ...but not one that runs into it
+        test_assert!(kernel.copy_to_process(&p, last, b"bad!"));
+        test_assert!(kernel.read_process_message(&p, last as u64).is_none());
+    }
+}
+
+kernel_test! {
+    fn copy_to_process_backs_and_swaps_in_pages(kernel: &mut Kernel) {
+        test_assert!(kernel.swap.nslots() > 0);
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let va = p.p_heap_start;
+        // a reserved page is backed by the copy
+        test_assert!(kernel.page_reserve(1, va) == 0);
+        test_assert!(kernel.copy_to_process(&p, va, &0x5eedu64.to_ne_bytes()));
+        test_assert!(unsafe { *(virtual_memory_lookup(p.p_pagetable, va).pa as *const u64) } == 0x5eed);
+
+        // a swapped-out page is read back first
+        while kernel.swapped_slot(p.p_pagetable, va).is_none() {
+            test_assert!(kernel.swap_out());
+        }
+        test_assert!(kernel.copy_to_process(&p, va + 8, &1u64.to_ne_bytes()));
+        let pa = unsafe { virtual_memory_lookup(p.p_pagetable, va) }.pa;
+        test_assert!(unsafe { *(pa as *const u64) == 0x5eed && *((pa + 8) as *const u64) == 1 });
+        // and memory the process does not have is refused
+        test_assert!(!kernel.copy_to_process(&p, va + PAGESIZE as usize, &[0]));
+    }
+}
+
+kernel_test! {
+    fn write_to_cow_page_copies_it(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let va = p.p_heap_start;
+        test_assert!(kernel.page_reserve(1, va) == 0);
+        test_assert!(kernel.handle_page_fault(1, va, (PFERR_USER | PFERR_WRITE) as u64));
+        let write = (PFERR_USER | PFERR_WRITE | PFERR_PRESENT) as u64;
+        let Some(pte) = pte_entry(p.p_pagetable, va) else {
+            return Err("no page table entry");
+        };
+        let old = unsafe { *pte }.addr();
+        unsafe { *old.as_ptr::<u64>() = 0x5eed; }
+
+        // a page someone else references is copied...
+        kernel.pageinfo_table.incref(old);
+        kernel.pageinfo_table.set_flags(old, PF_COW);
+        unsafe { *pte = (*pte).with_flags(PTE_COW, PTE_W); }
+        test_assert!(kernel.handle_page_fault(1, va, write));
+        let entry = unsafe { *pte };
+        test_assert!(entry.addr() != old && entry.has(PTE_P | PTE_W | PTE_U) && !entry.has(PTE_COW));
+        test_assert!(unsafe { *entry.addr().as_ptr::<u64>() } == 0x5eed);
+        test_assert!(kernel.pageinfo_table.pageinfo[old.page_number().as_usize()].refcount == 1);
+        test_assert!(kernel.pageinfo_table.free_page(old) == Some(0));
+
+        // ...and one nobody else references is just made writable
+        kernel.pageinfo_table.set_flags(entry.addr(), PF_COW);
+        unsafe { *pte = entry.with_flags(PTE_COW, PTE_W); }
+        test_assert!(kernel.handle_page_fault(1, va, write));
+        test_assert!(unsafe { *pte } == entry);
+        test_assert!(kernel.pageinfo_table.pageinfo[entry.addr().page_number().as_usize()].flags & PF_COW == 0);
+        // a write to a read-only page that is not copy-on-write still fails
+        unsafe { *pte = entry.with_flags(0, PTE_W); }
+        test_assert!(!kernel.handle_page_fault(1, va, write));
+    }
+}
+
+kernel_test! {
+    fn dump_maps_names_what_each_page_holds(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let page = PAGESIZE as usize;
+        test_assert!(kernel.brk(1, p.p_heap_start + page) == 0);
+        let (anon, shm) = match (kernel.mmap(1, 0, page, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS),
+                                 kernel.shm_create(7, page).and_then(|id| kernel.shm_attach(1, id, 0))) {
+            (Ok(anon), Ok(shm)) => (anon, shm),
+            _ => return Err("mmap or shm_attach failed"),
+        };
+
+        test_assert!(kernel.maps_label(1, KERNEL_START_ADDR as usize) == c"[kernel]");
+        test_assert!(kernel.maps_label(1, p.p_registers.reg_rip as usize) == c"[program]");
+        test_assert!(kernel.maps_label(1, p.p_heap_start) == c"[heap]");
+        test_assert!(kernel.maps_label(1, p.p_stack_bottom) == c"[stack]");
+        test_assert!(kernel.maps_label(1, anon) == c"[anon]");
+        test_assert!(kernel.maps_label(1, shm) == c"[shm]");
+
+        // pid 0 is the caller; free or invalid processes have no maps
+        test_assert!(kernel.dump_maps(1, 0) == 0);
+        test_assert!(kernel.dump_maps(1, 2) == -EINVAL);
+        test_assert!(kernel.dump_maps(1, NPROC) == -EINVAL);
+    }
+}
+
+kernel_test! {
+    fn remapping_the_active_table_flushes_the_tlb(kernel: &mut Kernel) {
+        let (a, b) = match (kernel.pageinfo_table.alloc_page(1), kernel.pageinfo_table.alloc_page(1)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Err("no free physical page"),
+        };
+        let va = a.as_usize();
+        let (before, remapped, restored) = unsafe {
+            let active = rcr3() == kernel_pagetable as usize;
+            let perm = virtual_memory_lookup(kernel_pagetable, va).perm as X86_64PageentryT;
+            *a.as_ptr::<u64>() = 1;
+            *b.as_ptr::<u64>() = 2;
+            // the first read loads the translation into the TLB; the reads
+            // after each change only see it if the change flushed it
+            let before = core::ptr::read_volatile(va as *const u64);
+            virtual_memory_map(kernel_pagetable, va, b.as_usize(), PAGESIZE as usize, perm);
+            let remapped = core::ptr::read_volatile(va as *const u64);
+            virtual_memory_map(kernel_pagetable, va, va, PAGESIZE as usize, perm);
+            let restored = core::ptr::read_volatile(va as *const u64);
+            test_assert!(active);
+            (before, remapped, restored)
+        };
+        kernel.pageinfo_table.free_page(a);
+        kernel.pageinfo_table.free_page(b);
+        test_assert!(before == 1 && remapped == 2 && restored == 1);
+    }
+}
+
+kernel_test! {
+    fn only_code_is_executable(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let page = PAGESIZE as usize;
+        let flags = |va: usize| pte_entry(p.p_pagetable, va).map_or(0, |pte| unsafe { (*pte).flags() } & (PTE_P | PTE_NX));
+        test_assert!(flags(p.p_registers.reg_rip as usize) == PTE_P);
+        test_assert!(flags(p.p_stack_bottom) == PTE_P | PTE_NX);
+
+        // fetching from the heap is refused; PROT_EXEC memory may be run
+        test_assert!(kernel.brk(1, p.p_heap_start + page) == 0);
+        test_assert!(!kernel.handle_page_fault(1, p.p_heap_start, (PFERR_USER | PFERR_FETCH) as u64));
+        test_assert!(kernel.handle_page_fault(1, p.p_heap_start, PFERR_USER as u64));
+        test_assert!(flags(p.p_heap_start) == PTE_P | PTE_NX);
+        let addr = match kernel.mmap(1, 0, page, PROT_READ | PROT_EXEC, MAP_PRIVATE | MAP_ANONYMOUS) {
+            Ok(addr) => addr,
+            Err(_) => return Err("mmap failed"),
+        };
+        test_assert!(kernel.handle_page_fault(1, addr, (PFERR_USER | PFERR_FETCH) as u64));
+        test_assert!(flags(addr) == PTE_P);
+        test_assert!(kernel.mprotect(1, addr, page, PROT_READ) == 0 && flags(addr) == PTE_P | PTE_NX);
+    }
+}
+
+kernel_test! {
+    fn program_text_is_read_only(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let page = PAGESIZE as usize;
+        let flags = |va: usize| pte_entry(p.p_pagetable, va).map_or(0, |pte| unsafe { (*pte).flags() } & (PTE_P | PTE_W));
+        let text = p.p_registers.reg_rip as usize;
+        test_assert!(flags(text) == PTE_P);
+        test_assert!(flags(p.p_heap_start - page) == PTE_P | PTE_W);
+        // writing to code kills the process
+        test_assert!(!kernel.handle_page_fault(1, text, (PFERR_USER | PFERR_WRITE | PFERR_PRESENT) as u64));
+    }
+}
+
+kernel_test! {
+    fn programs_are_loaded_by_name(kernel: &mut Kernel) {
+        // test programs are linked in too, and load at their own addresses
+        test_assert!(kernel.process_setup(1, b"fork_basic") == 0);
+        test_assert!(kernel.process_setup(2, b"allocator2") == 0);
+        let rip = |pid: usize| kernel.proc_table.get_process_by_pid(pid).p_registers.reg_rip;
+        test_assert!(rip(1) < 0x140000 && rip(2) >= 0x140000);
+
+        test_assert!(kernel.process_setup(3, b"no-such-program") == -EINVAL);
+        test_assert!(kernel.proc_table.get_process_by_pid(3).p_state == P_FREE);
+        test_assert!(kernel.pageinfo_table.pages_owned_by(3) == 0);
+    }
+}
@@ -1,26 +1,44 @@
+#![cfg_attr(not(test), no_std)]
 #![allow(static_mut_refs)]
 
 mod kernel;
 mod process;
 mod memshow;
+mod multiboot;
 mod ph_page_info;
+mod selftest;
+mod shm;
+mod swap;
+mod vma;
 
 use bindings::bindings_x86_64::*;
 
 use crate::kernel::Kernel;
+
+// The kernel runs on one CPU with interrupts off, so nothing but the entry
+// points below touches KERNEL, and only from kernel code.
 static mut KERNEL: Option<Kernel> = None;
 
 
+/// # Safety
+///
+/// Called once, from the boot code, with `command` null or pointing to a
+/// NUL-terminated string and `multiboot_info` the address the boot loader
+/// passed, or 0.
 #[no_mangle]
-pub unsafe extern "C" fn kernel(command: *const u8) {
+pub unsafe extern "C" fn kernel(command: *const u8, multiboot_info: usize) {
     if KERNEL.is_none() {
         KERNEL = Some(Kernel::new());
     }
     if let Some(kernel) = &mut KERNEL {
-        kernel.kernel(command);
+        kernel.kernel(command, multiboot_info);
     }
 }
 
+/// # Safety
+///
+/// Called only from the exception entry code in k-exception.S, with the
+/// registers it saved.
 #[no_mangle]
 pub unsafe extern "C" fn exception(reg: &mut x86_64_registers) {
     if KERNEL.is_none() {
@@ -31,6 +49,10 @@ pub unsafe extern "C" fn exception(reg: &mut x86_64_registers) {
     }
 }
 
+/// # Safety
+///
+/// Must be called from kernel code, on the kernel's one CPU: KERNEL has no
+/// lock.
 #[no_mangle]
 pub unsafe extern "C" fn assign_physical_page(addr: usize, owner: usize) -> i32 {
     if KERNEL.is_none() {
@@ -41,3 +63,79 @@ pub unsafe extern "C" fn assign_physical_page(addr: usize, owner: usize) -> i32
     }
     -1
 }
+
+/// # Safety
+///
+/// As for `assign_physical_page`.
+#[no_mangle]
+pub unsafe extern "C" fn physical_memory_size() -> usize {
+    if KERNEL.is_none() {
+        KERNEL = Some(Kernel::new());
+    }
+    if let Some(kernel) = &mut KERNEL {
+        return kernel.pageinfo_table.pageinfo.len() * PAGESIZE as usize;
+    }
+    0
+}
+
+/// # Safety
+///
+/// As for `assign_physical_page`.
+#[no_mangle]
+pub unsafe extern "C" fn alloc_kernel_pagetable() -> usize {
+    if KERNEL.is_none() {
+        KERNEL = Some(Kernel::new());
+    }
+    if let Some(kernel) = &mut KERNEL {
+        return kernel.alloc_kernel_pagetable().map_or(0, PhysAddr::as_usize);
+    }
+    0
+}
+
+/// # Safety
+///
+/// As for `assign_physical_page`.
+#[no_mangle]
+pub unsafe extern "C" fn free_kernel_pagetable(pa: usize) {
+    if KERNEL.is_none() {
+        KERNEL = Some(Kernel::new());
+    }
+    if let (Some(kernel), Some(pa)) = (&mut KERNEL, PhysAddr::new(pa)) {
+        kernel.pageinfo_table.free_page(pa);
+    }
+}
+
+/// # Safety
+///
+/// As for `assign_physical_page`.
+#[no_mangle]
+pub unsafe extern "C" fn alloc_kernel_page() -> usize {
+    if KERNEL.is_none() {
+        KERNEL = Some(Kernel::new());
+    }
+    if let Some(kernel) = &mut KERNEL {
+        return kernel.alloc_kernel_page().map_or(0, PhysAddr::as_usize);
+    }
+    0
+}
+
+/// # Safety
+///
+/// As for `assign_physical_page`.
+#[no_mangle]
+pub unsafe extern "C" fn copy_on_write_page(pa: usize) -> bool {
+    if KERNEL.is_none() {
+        KERNEL = Some(Kernel::new());
+    }
+    if let (Some(kernel), Some(pa)) = (&mut KERNEL, PhysAddr::new(pa)) {
+        return kernel.mark_copy_on_write(pa);
+    }
+    false
+}
+
+// Outside host tests the crate runs on bare metal
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    stdlib::panic(info)
+}
@@ -1,9 +1,15 @@
 #![allow(unused)]
 
+use bindings::bindings_kernel::NPAGES;
+use bindings::bindings_lib::console;
 use bindings::bindings_x86_64::*;
+use stdlib::cpos;
 
-// memshow_physical
-//    Draw a picture of physical memory on the CGA console.
+use crate::ph_page_info::{FragmentationStats, PageOwner, PhysicalPageInfo, PhysicalPageInfoTable, PF_SHARED};
+
+unsafe extern "C" {
+    fn console_printf(cpos: i32, color: i32, format: *const u8, ...) -> i32;
+}
 
 const MEMSTATE_COLORS: [u16; 19] = [
     b'K' as u16 | 0x0D00, b'R' as u16 | 0x0700, b'.' as u16 | 0x0700, b'1' as u16 | 0x0C00,
@@ -14,34 +20,56 @@ const MEMSTATE_COLORS: [u16; 19] = [
 ];
 const SHARED_COLOR: u16 = MEMSTATE_COLORS[18];
 
-#[no_mangle]
-pub unsafe extern "C" fn memshow_physical() {
-    // console_printf(CPOS(0, 32), 0x0F00, "PHYSICAL MEMORY");
-    // for pn in 0..PAGENUMBER(MEMSIZE_PHYSICAL) {
-    //     if pn % 64 == 0 {
-    //         console_printf(CPOS(1 + pn / 64, 3), 0x0F00, "0x{:06X} ", pn << 12);
-    //     }
+// memshow_physical(table)
+//    Draw a picture of physical memory on the CGA console: one character
+//    per page, colored by memstate_color, 64 pages to a row. Only the first
+//    NPAGES pages fit above the fragmentation summary.
+
+pub fn memshow_physical(table: &PhysicalPageInfoTable) {
+    unsafe {
+        console_printf(cpos!(0, 32), 0x0F00, c"PHYSICAL MEMORY".as_ptr() as *const u8);
+    }
+    for (pn, page) in table.pageinfo.iter().enumerate().take(NPAGES as usize) {
+        unsafe {
+            if pn % 64 == 0 {
+                console_printf(cpos!(1 + pn / 64, 3) as i32, 0x0F00,
+                    c"0x%06X ".as_ptr() as *const u8, (pn << 12) as i32);
+            }
+            console[cpos!(1 + pn / 64, 12 + pn % 64)] = memstate_color(page);
+        }
+    }
+}
 
-    //     let mut owner = pageinfo[pn].owner;
-    //     if pageinfo[pn].refcount == 0 {
-    //         owner = PO_FREE;
-    //     }
-    //     let mut color = MEMSTATE_COLORS[(owner - PO_KERNEL) as usize];
 
-    //     // Apply darker color for shared pages
-    //     if pageinfo[pn].refcount > 1 && pn != PAGENUMBER(CONSOLE_ADDR) {
-    //         #[cfg(feature = "shared")]
-    //         {
-    //             color = SHARED_COLOR | 0x0F00;
-    //         }
-    //         #[cfg(not(feature = "shared"))]
-    //         {
-    //             color &= 0x77FF;
-    //         }
-    //     }
+// memstate_color(page)
+//    Returns the character and color that show physical page `page`: its
+//    owner's, or `S` for a process page that is shared (part of a shared
+//    memory segment, or mapped by several processes).
 
-    //     console[CPOS(1 + pn / 64, 12 + pn % 64)] = color;
-    // }
+pub fn memstate_color(page: &PhysicalPageInfo) -> u16 {
+    if page.refcount == 0 {
+        return MEMSTATE_COLORS[(PageOwner::PoFree as i32 - PageOwner::PoKernel as i32) as usize];
+    }
+    if page.owner >= 0 && (page.refcount > 1 || page.flags & PF_SHARED != 0) {
+        return SHARED_COLOR | 0x0F00;
+    }
+    MEMSTATE_COLORS[(page.owner - PageOwner::PoKernel as PidT) as usize]
+}
+
+
+// memshow_fragmentation(stats)
+//    Summarize free physical memory on the line below the physical memory
+//    map: the number of free pages and blocks, and the largest block that
+//    `alloc_pages` could still hand out.
+
+pub fn memshow_fragmentation(stats: &FragmentationStats) {
+    let nblocks: usize = stats.free_blocks.iter().sum();
+    let largest = stats.largest_free_order().map_or(0usize, |order| 1 << order);
+    unsafe {
+        console_printf(cpos!(9, 3), 0x0700,
+            c"FREE %3d pages in %3d blocks, largest block %4d pages".as_ptr() as *const u8,
+            stats.free_pages as i32, nblocks as i32, largest as i32);
+    }
 }
 
 
@@ -0,0 +1,384 @@
+use bindings::bindings_x86_64::*;
+
+use core::ops::Range;
+
+// multiboot.rs
+//
+//    The physical memory map. A multiboot boot loader (such as QEMU's
+//    `-kernel` loader) describes physical memory in the multiboot
+//    information structure: either as a list of typed regions (the BIOS
+//    e820 map) or just as the sizes of lower and upper memory. WeensyOS's
+//    own boot sector passes no information, so the kernel then asks CMOS
+//    how much memory there is (`cmos_memory_size`).
+//
+//    Anything the map does not list as available -- ACPI tables, holes
+//    such as the VGA/BIOS area, bad RAM -- is reserved.
+
+// The kernel's page tables identity-map physical memory, and the boot page
+// table covers only the first 1GB, so larger memories are truncated.
+pub const MEMSIZE_PHYSICAL_MAX: usize = 0x40000000;
+
+// Maximum number of regions a MemoryMap keeps.
+pub const MAX_MEMORY_REGIONS: usize = 32;
+
+const MULTIBOOT_INFO_MEMORY: u32 = 1 << 0;   // mem_lower, mem_upper valid
+const MULTIBOOT_INFO_MEM_MAP: u32 = 1 << 6;  // mmap_length, mmap_addr valid
+
+const MULTIBOOT_INFO_SIZE: usize = 52;      // through mmap_addr
+
+const LOWER_MEMORY_END: usize = 0xA0000;     // end of conventional memory
+const UPPER_MEMORY_START: usize = 0x100000;  // start of extended memory
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MemoryKind {
+    Available,          // usable RAM
+    Reserved,           // in use by firmware or devices
+    AcpiReclaimable,    // ACPI tables
+    AcpiNvs,            // ACPI non-volatile storage
+    BadRam,             // defective memory
+}
+
+impl MemoryKind {
+    // from_multiboot(kind)
+    //    Returns the region kind for a multiboot memory map type. Unknown
+    //    types are reserved.
+
+    fn from_multiboot(kind: u32) -> Self {
+        match kind {
+            1 => MemoryKind::Available,
+            3 => MemoryKind::AcpiReclaimable,
+            4 => MemoryKind::AcpiNvs,
+            5 => MemoryKind::BadRam,
+            _ => MemoryKind::Reserved,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,
+    pub kind: MemoryKind,
+}
+
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    nregions: usize,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        MemoryMap {
+            regions: [MemoryRegion { start: 0, end: 0, kind: MemoryKind::Reserved }; MAX_MEMORY_REGIONS],
+            nregions: 0,
+        }
+    }
+
+    // MemoryMap::from_size(memsize)
+    //    Returns the map of a PC with `memsize` bytes of memory: conventional
+    //    memory below 640KB and extended memory from 1MB up.
+
+    pub fn from_size(memsize: usize) -> Self {
+        let mut map = MemoryMap::new();
+        map.add(0, LOWER_MEMORY_END, MemoryKind::Available);
+        map.add(UPPER_MEMORY_START, memsize, MemoryKind::Available);
+        map
+    }
+
+    // MemoryMap::from_multiboot(info)
+    //    Parse the multiboot information structure at `info`. Returns None
+    //    if `info` is null or describes no memory.
+    //
+    //    Safety: `info` must be null or point to multiboot information
+    //    whose memory map, if any, is readable.
+
+    pub unsafe fn from_multiboot(info: *const u8) -> Option<Self> {
+        if info.is_null() {
+            return None;
+        }
+        let info = core::slice::from_raw_parts(info, MULTIBOOT_INFO_SIZE);
+        let mut mmap: &[u8] = &[];
+        if read_u32(info, 0) & MULTIBOOT_INFO_MEM_MAP != 0 {
+            mmap = core::slice::from_raw_parts(read_u32(info, 48) as usize as *const u8,
+                read_u32(info, 44) as usize);
+        }
+        Self::parse_multiboot(info, mmap)
+    }
+
+    // MemoryMap::parse_multiboot(info, mmap)
+    //    Parse the multiboot information `info`, whose memory map (if its
+    //    flags say there is one) has been copied into `mmap`.
+
+    fn parse_multiboot(info: &[u8], mmap: &[u8]) -> Option<Self> {
+        let flags = read_u32(info, 0);
+        let mut map = MemoryMap::new();
+
+        if flags & MULTIBOOT_INFO_MEM_MAP != 0 {
+            // Each entry is preceded by its size, which does not count
+            // the size field itself.
+            let mut offset = 0;
+            while offset + 24 <= mmap.len() {
+                let base = read_u64(mmap, offset + 4) as usize;
+                let length = read_u64(mmap, offset + 12) as usize;
+                let kind = read_u32(mmap, offset + 20);
+                map.add(base, base.saturating_add(length), MemoryKind::from_multiboot(kind));
+                offset += read_u32(mmap, offset) as usize + 4;
+            }
+        } else if flags & MULTIBOOT_INFO_MEMORY != 0 {
+            map.add(0, read_u32(info, 4) as usize * 1024, MemoryKind::Available);
+            map.add(UPPER_MEMORY_START, UPPER_MEMORY_START + read_u32(info, 8) as usize * 1024,
+                MemoryKind::Available);
+        }
+
+        if map.memsize() == 0 {
+            None
+        } else {
+            Some(map)
+        }
+    }
+
+    // add(start, end, kind)
+    //    Add the region `[start, end)` to the map. Empty regions and regions
+    //    beyond MAX_MEMORY_REGIONS are dropped; a dropped available region
+    //    just means less usable memory.
+
+    pub fn add(&mut self, start: usize, end: usize, kind: MemoryKind) {
+        if start < end && self.nregions < MAX_MEMORY_REGIONS {
+            self.regions[self.nregions] = MemoryRegion { start, end, kind };
+            self.nregions += 1;
+        }
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.nregions]
+    }
+
+    // memsize()
+    //    Returns the amount of physical memory to manage: the end of the
+    //    highest available region, rounded down to a page and capped at
+    //    MEMSIZE_PHYSICAL_MAX.
+
+    pub fn memsize(&self) -> usize {
+        let end = self.regions().iter()
+            .filter(|r| r.kind == MemoryKind::Available)
+            .map(|r| r.end)
+            .max()
+            .unwrap_or(0);
+        end.min(MEMSIZE_PHYSICAL_MAX) & !PAGE_OFF_MASK
+    }
+
+    // is_available(addr)
+    //    Returns true iff byte `addr` lies in an available region and in no
+    //    other kind of region.
+
+    fn is_available(&self, addr: usize) -> bool {
+        let mut available = false;
+        for r in self.regions().iter().filter(|r| r.start <= addr && addr < r.end) {
+            if r.kind != MemoryKind::Available {
+                return false;
+            }
+            available = true;
+        }
+        available
+    }
+
+    // reserved_regions(out)
+    //    Store the parts of `[0, memsize())` that are not available in
+    //    `out`, merging adjacent parts, and return how many were stored. If
+    //    `out` fills up, its last region is extended to cover the rest, so
+    //    nothing unavailable is ever reported as usable.
+
+    pub fn reserved_regions(&self, out: &mut [Range<usize>]) -> usize {
+        let memsize = self.memsize();
+
+        // Availability can only change at a region boundary, so test each
+        // interval between consecutive boundaries.
+        let mut boundaries = [0usize; 2 * MAX_MEMORY_REGIONS + 2];
+        let mut nboundaries = 0;
+        for r in self.regions() {
+            for addr in [r.start, r.end] {
+                if addr < memsize {
+                    boundaries[nboundaries] = addr;
+                    nboundaries += 1;
+                }
+            }
+        }
+        boundaries[nboundaries] = 0;
+        boundaries[nboundaries + 1] = memsize;
+        let boundaries = &mut boundaries[..nboundaries + 2];
+        boundaries.sort_unstable();
+
+        let mut n = 0;
+        for pair in boundaries.windows(2) {
+            let (start, end) = (pair[0], pair[1]);
+            if start == end || self.is_available(start) {
+                continue;
+            }
+            if n > 0 && (out[n - 1].end >= start || n == out.len()) {
+                out[n - 1].end = end;
+            } else if n < out.len() {
+                out[n] = start..end;
+                n += 1;
+            }
+        }
+        n
+    }
+
+    // find_free_range(size, above)
+    //    Returns the page-aligned start of the highest `size` bytes of
+    //    available memory at or above `above` and below `memsize()`, or None
+    //    if no available region has room.
+
+    pub fn find_free_range(&self, size: usize, above: usize) -> Option<usize> {
+        let memsize = self.memsize();
+        let above = (above + PAGE_OFF_MASK) & !PAGE_OFF_MASK;
+        // The highest free range ends at memsize or where some region
+        // starts or ends.
+        let ends = self.regions().iter().flat_map(|r| [r.start, r.end]);
+        core::iter::once(memsize).chain(ends)
+            .filter_map(|end| {
+                let start = (end.min(memsize) & !PAGE_OFF_MASK).checked_sub(size)? & !PAGE_OFF_MASK;
+                let clear = (start..start + size).step_by(PAGESIZE as usize)
+                    .all(|addr| self.is_available(addr));
+                (start >= above && clear).then_some(start)
+            })
+            .max()
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // parse(flags, mem_lower, mem_upper, entries)
+    //    Parse multiboot information with the given memory sizes and a
+    //    memory map of `entries` (base, length, type).
+    fn parse(flags: u32, mem_lower: u32, mem_upper: u32, entries: &[(u64, u64, u32)]) -> Option<MemoryMap> {
+        let mut mmap = Vec::new();
+        for &(base, length, kind) in entries {
+            mmap.extend_from_slice(&20u32.to_le_bytes());
+            mmap.extend_from_slice(&base.to_le_bytes());
+            mmap.extend_from_slice(&length.to_le_bytes());
+            mmap.extend_from_slice(&kind.to_le_bytes());
+        }
+        let mut info = [0u8; MULTIBOOT_INFO_SIZE];
+        info[0..4].copy_from_slice(&flags.to_le_bytes());
+        info[4..8].copy_from_slice(&mem_lower.to_le_bytes());
+        info[8..12].copy_from_slice(&mem_upper.to_le_bytes());
+        info[44..48].copy_from_slice(&(mmap.len() as u32).to_le_bytes());
+        MemoryMap::parse_multiboot(&info, &mmap)
+    }
+
+    // reserved(map)
+    //    Returns the reserved regions of `map` as (start, end) pairs.
+    fn reserved(map: &MemoryMap) -> Vec<(usize, usize)> {
+        let mut out = [const { 0..0 }; 8];
+        let n = map.reserved_regions(&mut out);
+        out[..n].iter().map(|r| (r.start, r.end)).collect()
+    }
+
+    // QEMU's e820 map for `-m 64M`
+    const QEMU_64M: [(u64, u64, u32); 4] = [
+        (0, 0x9FC00, 1),
+        (0x9FC00, 0x400, 2),
+        (0xF0000, 0x10000, 2),
+        (0x100000, 0x3EE0000, 1),
+    ];
+
+    #[test]
+    fn from_size_reserves_the_io_hole() {
+        let map = MemoryMap::from_size(0x4000000);
+        assert_eq!(map.memsize(), 0x4000000);
+        assert_eq!(reserved(&map), [(0xA0000, 0x100000)]);
+    }
+
+    #[test]
+    fn memsize_is_capped() {
+        let map = MemoryMap::from_size(0x100000000);
+        assert_eq!(map.memsize(), MEMSIZE_PHYSICAL_MAX);
+    }
+
+    #[test]
+    fn from_multiboot_without_memory_information() {
+        assert!(unsafe { MemoryMap::from_multiboot(core::ptr::null()) }.is_none());
+        // the soft reboot in check_keyboard passes only a command line
+        assert!(parse(4, 0, 0, &[]).is_none());
+    }
+
+    #[test]
+    fn from_multiboot_reads_memory_sizes() {
+        let map = parse(MULTIBOOT_INFO_MEMORY, 639, 64512, &[]).unwrap();
+        assert_eq!(map.memsize(), 0x100000 + 64512 * 1024);
+        assert_eq!(reserved(&map), [(0x9FC00, 0x100000)]);
+    }
+
+    #[test]
+    fn from_multiboot_prefers_memory_map() {
+        let map = parse(MULTIBOOT_INFO_MEMORY | MULTIBOOT_INFO_MEM_MAP, 639, 1024, &QEMU_64M).unwrap();
+        assert_eq!(map.regions().len(), 4);
+        assert_eq!(map.regions()[3], MemoryRegion { start: 0x100000, end: 0x3FE0000, kind: MemoryKind::Available });
+        assert_eq!(map.memsize(), 0x3FE0000);
+        // the BIOS areas and the hole between them are reserved
+        assert_eq!(reserved(&map), [(0x9FC00, 0x100000)]);
+    }
+
+    #[test]
+    fn acpi_and_unknown_regions_are_reserved() {
+        let map = parse(MULTIBOOT_INFO_MEM_MAP, 0, 0, &[
+            (0, 0x9F000, 1),
+            (0x100000, 0x700000, 1),
+            (0x800000, 0x10000, 3),
+            (0x810000, 0x10000, 4),
+            (0x820000, 0x7E0000, 1),
+            (0x900000, 0x1000, 5),
+            (0xA00000, 0x1000, 7),
+            (0x1000000, 0x1000000, 1),
+        ]).unwrap();
+        assert_eq!(map.memsize(), 0x2000000);
+        assert_eq!(reserved(&map), [
+            (0x9F000, 0x100000),
+            (0x800000, 0x820000),
+            (0x900000, 0x901000),
+            (0xA00000, 0xA01000),
+        ]);
+    }
+
+    #[test]
+    fn reserved_regions_never_drop_unavailable_memory() {
+        let mut map = MemoryMap::from_size(0x1000000);
+        for i in 0..8 {
+            map.add(0x200000 + i * 0x100000, 0x201000 + i * 0x100000, MemoryKind::Reserved);
+        }
+        let mut out = [const { 0..0 }; 3];
+        assert_eq!(map.reserved_regions(&mut out), 3);
+        assert_eq!(out[0], 0xA0000..0x100000);
+        assert_eq!(out[1], 0x200000..0x201000);
+        assert_eq!(out[2], 0x300000..0x901000);
+    }
+
+    #[test]
+    fn find_free_range_takes_top_of_available_memory() {
+        let map = parse(MULTIBOOT_INFO_MEM_MAP, 0, 0, &QEMU_64M).unwrap();
+        assert_eq!(map.find_free_range(0x31000, 0x300000), Some(0x3FE0000 - 0x31000));
+        assert_eq!(map.find_free_range(0x4000000, 0x300000), None);
+
+        let small = MemoryMap::from_size(0x200000);
+        assert_eq!(small.find_free_range(0x2000, 0x300000), None);
+        // reserved memory is skipped
+        assert_eq!(small.find_free_range(0x2000, 0x90000), Some(0x1FE000));
+        let mut holey = MemoryMap::from_size(0x400000);
+        holey.add(0x3FF000, 0x400000, MemoryKind::BadRam);
+        assert_eq!(holey.find_free_range(0x2000, 0x300000), Some(0x3FD000));
+        assert_eq!(holey.find_free_range(0x1000, 0x300000), Some(0x3FE000));
+    }
+}
@@ -1,6 +1,11 @@
 use bindings::bindings_x86_64::*;
 use bindings::bindings_kernel::*;
 
+use core::ops::Range;
+
+use crate::multiboot::MemoryMap;
+use crate::selftest::{kernel_test, test_assert};
+
 // PAGEINFO
 //
 //    The pageinfo[] array keeps track of information about each physical page.
@@ -16,18 +21,46 @@ use bindings::bindings_kernel::*;
 //    pageinfo[pn].owner is a constant indicating who owns the page.
 //      PO_KERNEL means the kernel, PO_RESERVED means reserved memory (such
 //      as the console), and a number >=0 means that process ID.
+//    pageinfo[pn].flags describes the page's role (PF_* below). Flags are
+//      cleared when the page is freed.
+//
+//    Reference counts are checked: overflowing one panics rather than
+//    silently wrapping to a free page.
+//
+//    pageinfo_init() sets up the initial pageinfo[] state from a MemoryLayout.
+//    The table itself never asks the hardware anything, so it can be tested
+//    on the host.
 //
-//    pageinfo_init() sets up the initial pageinfo[] state.
+//    The amount of physical memory is only known at boot, so pageinfo[] and
+//    the free bitmaps live in a metadata area the caller provides to
+//    pageinfo_init(); `metadata_words(npages)` says how large it must be.
+//
+//    Free pages are also kept by a buddy allocator. Free memory is split
+//    into blocks of 2^order pages, each aligned to its own size; a block of
+//    order `k` and its "buddy" (the other half of the enclosing block of
+//    order `k + 1`) are merged whenever both are free. There is one bitmap
+//    per order, where bit `i` is set iff the block starting at page
+//    `i << order` is free. alloc_pages() splits the smallest free block
+//    that is large enough, so it never has to scan pageinfo[]. Every
+//    function that changes a refcount to or from 0 keeps the bitmaps in
+//    sync.
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct PhysicalPageInfo {
-    pub owner: i8,
-    pub refcount: i8,
+    pub owner: PidT,
+    pub refcount: u32,
+    pub flags: u8,
 }
 
-#[repr(i8)]
-#[allow(unused)]
+// Page flags
+pub const PF_COW: u8 = 0x1;         // mapped copy-on-write (PTE_COW) somewhere
+pub const PF_PINNED: u8 = 0x2;      // must stay allocated (e.g. DMA buffers)
+pub const PF_PAGETABLE: u8 = 0x4;   // holds a page table
+pub const PF_SHARED: u8 = 0x10;     // belongs to a shared memory segment
+
+#[repr(i32)]
+#[allow(unused, clippy::enum_variant_names)] // PO_FREE etc. in the C kernel
 #[derive(PartialEq, Clone)]
 pub enum PageOwner {
     PoFree = 0,         // this page is free
@@ -35,49 +68,493 @@ pub enum PageOwner {
     PoKernel = -2,      // this page is used by the kernel
 }
 
+// Largest block the buddy allocator manages is 2^MAX_ORDER pages.
+pub const MAX_ORDER: usize = 10;
+
 pub struct PhysicalPageInfoTable {
-    pub pageinfo: [PhysicalPageInfo; NPAGES as usize],
+    pub pageinfo: &'static mut [PhysicalPageInfo],
+    free_map: &'static mut [u64],       // free blocks, one bitmap per order
+    free_map_start: [usize; MAX_ORDER + 2], // where each order's bitmap starts
+    zero_page: fn(PhysAddr),            // clears a newly allocated page
 }
 
-impl PhysicalPageInfoTable {
-    pub const fn new() -> Self {
-        let pageinfo = [
-            PhysicalPageInfo { 
-                owner: PageOwner::PoFree as i8, 
-                refcount: 0 
-            }; NPAGES as usize];
-        PhysicalPageInfoTable { pageinfo }
+// free_map_words(npages, order)
+//    Returns the number of words in the free bitmap of order `order` for
+//    `npages` pages.
+
+const fn free_map_words(npages: usize, order: usize) -> usize {
+    npages.div_ceil(1 << order).div_ceil(64)
+}
+
+// pageinfo_words(npages)
+//    Returns the number of words that hold `pageinfo[]` for `npages` pages.
+
+const fn pageinfo_words(npages: usize) -> usize {
+    (npages * core::mem::size_of::<PhysicalPageInfo>()).div_ceil(8)
+}
+
+// metadata_words(npages)
+//    Returns the size, in 64-bit words, of the metadata area
+//    `pageinfo_init` needs to manage `npages` physical pages.
+
+pub const fn metadata_words(npages: usize) -> usize {
+    let mut words = pageinfo_words(npages);
+    let mut order = 0;
+    while order <= MAX_ORDER {
+        words += free_map_words(npages, order);
+        order += 1;
+    }
+    words
+}
+
+// FragmentationStats
+//    A summary of free physical memory, as shown by the memory viewer.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FragmentationStats {
+    pub free_pages: usize,
+    pub free_blocks: [usize; MAX_ORDER + 1], // # free blocks of each order
+}
+
+impl FragmentationStats {
+    // largest_free_order()
+    //    Returns the order of the largest free block, or None if no memory
+    //    is free. No allocation of a larger order can succeed.
+
+    pub fn largest_free_order(&self) -> Option<usize> {
+        self.free_blocks.iter().rposition(|&n| n > 0)
+    }
+}
+
+// page_panic(msg)
+//    Stop the kernel because page metadata would become inconsistent. Host
+//    tests get an ordinary Rust panic instead, so they can expect it.
+
+#[cfg(not(test))]
+fn page_panic(msg: &'static core::ffi::CStr) -> ! {
+    extern "C" {
+        fn c_panic(format: *const core::ffi::c_char, ...) -> !;
+    }
+    unsafe { c_panic(msg.as_ptr()) }
+}
+
+#[cfg(test)]
+fn page_panic(msg: &'static core::ffi::CStr) -> ! {
+    panic!("{}", msg.to_string_lossy())
+}
+
+// page_address(pn)
+//    Returns the physical address of page `pn`, which indexes the page
+//    info table and so always has one.
+
+pub fn page_address(pn: usize) -> PhysAddr {
+    match PageNumber::new(pn) {
+        Some(pn) => pn.address(),
+        None => page_panic(c"(page_address) page number out of range"),
+    }
+}
+
+// zero_identity_mapped_page(pa)
+//    Clear the page at physical address `pa` through the kernel's identity
+//    mapping of physical memory.
+
+fn zero_identity_mapped_page(pa: PhysAddr) {
+    unsafe {
+        core::ptr::write_bytes(pa.as_usize() as *mut u8, 0, PAGESIZE as usize);
     }
+}
 
-    // pageinfo_init
-    //    Initialize the `pageinfo[]` array.
+// MemoryLayout
+//    What `pageinfo_init` needs to know about physical memory: how much of it
+//    there is, the address ranges the hardware reserves and the ranges the
+//    kernel occupies. A page belongs to a region if any part of it overlaps
+//    the region. The kernel gets its layout from `MemoryLayout::hardware`;
+//    host tests build their own.
 
-    pub fn pageinfo_init(&mut self) {
+pub struct MemoryLayout<'a> {
+    pub memsize: usize,
+    pub reserved: &'a [Range<usize>],
+    pub kernel: &'a [Range<usize>],
+}
+
+// Maximum number of reserved regions `MemoryLayout::hardware` reports.
+pub const MAX_RESERVED_REGIONS: usize = 16;
+
+impl<'a> MemoryLayout<'a> {
+    // MemoryLayout::hardware(map, metadata, reserved, kernel)
+    //    Returns the layout of this machine's physical memory as described
+    //    by `map`. Page 0 (so that null pointers stay invalid) and the
+    //    regions `map` does not list as available are stored in `reserved`;
+    //    `kernel` receives the kernel image (up to the `end` linker symbol),
+    //    the kernel stack, and the page metadata area `metadata`.
+
+    pub fn hardware(
+        map: &MemoryMap,
+        metadata: Range<usize>,
+        reserved: &'a mut [Range<usize>; MAX_RESERVED_REGIONS],
+        kernel: &'a mut [Range<usize>; 3],
+    ) -> Self {
         extern "C" {
-            fn physical_memory_isreserved(pa: usize) -> core::ffi::c_int;
             static end: u8;
         }
-    
+
+        reserved[0] = 0..PAGESIZE as usize;
+        let nreserved = 1 + map.reserved_regions(&mut reserved[1..]);
+
         let end_addr = unsafe { &end as *const u8 as usize };
-        for addr in (0..MEMSIZE_PHYSICAL).step_by(PAGESIZE as usize) {
-            let owner = unsafe {
-                if physical_memory_isreserved(addr as usize) != 0 {
-                    PageOwner::PoReserved
-                } else if (addr >= KERNEL_START_ADDR && addr < end_addr as u64)
-                    || addr == KERNEL_STACK_TOP - PAGESIZE
-                {
-                    PageOwner::PoKernel
-                } else {
-                    PageOwner::PoFree
-                }
+        kernel[0] = KERNEL_START_ADDR as usize..end_addr;
+        kernel[1] = (KERNEL_STACK_TOP - KERNEL_STACK_SIZE) as usize..KERNEL_STACK_TOP as usize;
+        kernel[2] = metadata;
+
+        MemoryLayout { memsize: map.memsize(), reserved: &reserved[..nreserved], kernel }
+    }
+}
+
+// overlaps(regions, addr)
+//    Returns true iff the page at physical address `addr` overlaps any of
+//    `regions`.
+
+fn overlaps(regions: &[Range<usize>], addr: usize) -> bool {
+    regions.iter().any(|r| addr < r.end && addr + PAGESIZE as usize > r.start)
+}
+
+impl PhysicalPageInfoTable {
+    // PhysicalPageInfoTable::new()
+    //    Returns a table that manages no pages until `pageinfo_init`.
+
+    pub const fn new() -> Self {
+        PhysicalPageInfoTable {
+            pageinfo: &mut [],
+            free_map: &mut [],
+            free_map_start: [0; MAX_ORDER + 2],
+            zero_page: zero_identity_mapped_page,
+        }
+    }
+
+    // with_zero_page(zero_page)
+    //    Returns an empty table that clears allocated pages with `zero_page`
+    //    instead of writing to physical memory. Used by host tests.
+
+    #[allow(unused)]
+    pub const fn with_zero_page(zero_page: fn(PhysAddr)) -> Self {
+        let mut table = Self::new();
+        table.zero_page = zero_page;
+        table
+    }
+
+    // block_is_free(order, pn)
+    //    Returns true iff the block of order `order` starting at page `pn`
+    //    is free.
+
+    fn block_is_free(&self, order: usize, pn: usize) -> bool {
+        let bit = pn >> order;
+        self.free_map_order(order)[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    // free_map_order(order)
+    //    Returns the free bitmap of order `order`.
+
+    fn free_map_order(&self, order: usize) -> &[u64] {
+        &self.free_map[self.free_map_start[order]..self.free_map_start[order + 1]]
+    }
+
+    // set_block_free(order, pn, free)
+    //    Record whether the block of order `order` starting at page `pn` is
+    //    free.
+
+    fn set_block_free(&mut self, order: usize, pn: usize, free: bool) {
+        let bit = pn >> order;
+        let word = &mut self.free_map[self.free_map_start[order] + bit / 64];
+        if free {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
+        }
+    }
+
+    // free_block_containing(pn)
+    //    Returns the order and first page of the free block containing page
+    //    `pn`, or None if page `pn` is allocated.
+
+    fn free_block_containing(&self, pn: usize) -> Option<(usize, usize)> {
+        (0..=MAX_ORDER)
+            .map(|order| (order, pn & !((1 << order) - 1)))
+            .find(|&(order, start)| self.block_is_free(order, start))
+    }
+
+    // insert_free_block(order, pn)
+    //    Return the block of order `order` starting at page `pn` to the
+    //    allocator, merging it with its buddy for as long as the buddy is
+    //    free too.
+
+    fn insert_free_block(&mut self, mut order: usize, mut pn: usize) {
+        while order < MAX_ORDER {
+            let buddy = pn ^ (1 << order);
+            if buddy >= self.pageinfo.len() || !self.block_is_free(order, buddy) {
+                break;
+            }
+            self.set_block_free(order, buddy, false);
+            pn = pn.min(buddy);
+            order += 1;
+        }
+        self.set_block_free(order, pn, true);
+    }
+
+    // remove_free_page(pn)
+    //    Take free page `pn` out of the allocator, splitting the free block
+    //    that contains it and returning the other halves.
+
+    fn remove_free_page(&mut self, pn: usize) {
+        let Some((mut order, mut start)) = self.free_block_containing(pn) else {
+            return;
+        };
+        self.set_block_free(order, start, false);
+        while order > 0 {
+            order -= 1;
+            let half = start + (1 << order);
+            if pn < half {
+                self.set_block_free(order, half, true);
+            } else {
+                self.set_block_free(order, start, true);
+                start = half;
+            }
+        }
+    }
+
+    // is_free(pn)
+    //    Returns true iff the buddy allocator holds page `pn` as free.
+
+    pub fn is_free(&self, pn: usize) -> bool {
+        self.free_block_containing(pn).is_some()
+    }
+
+    // fragmentation_stats()
+    //    Count the free pages and the free blocks of each order.
+
+    pub fn fragmentation_stats(&self) -> FragmentationStats {
+        let mut stats = FragmentationStats::default();
+        for order in 0..=MAX_ORDER {
+            let nblocks = self.free_map_order(order).iter().map(|w| w.count_ones() as usize).sum::<usize>();
+            stats.free_blocks[order] = nblocks;
+            stats.free_pages += nblocks << order;
+        }
+        stats
+    }
+
+    // pages_owned_by(owner)
+    //    Returns the number of allocated pages that belong to `owner`.
+
+    pub fn pages_owned_by(&self, owner: PidT) -> usize {
+        self.pageinfo.iter().filter(|p| p.refcount > 0 && p.owner == owner).count()
+    }
+
+    // largest_owner(eligible)
+    //    Returns the process that owns the most pages among those for which
+    //    `eligible(pid)` is true, with its page count, or None if no eligible
+    //    process owns a page. Ties go to the lowest pid. This is the victim
+    //    the out-of-memory killer picks.
+
+    pub fn largest_owner(&self, eligible: impl Fn(PidT) -> bool) -> Option<(PidT, usize)> {
+        let mut counts = [0usize; NPROC];
+        for page in self.pageinfo.iter() {
+            if page.refcount > 0 && page.owner > 0 && (page.owner as usize) < NPROC {
+                counts[page.owner as usize] += 1;
+            }
+        }
+        (1..NPROC)
+            .map(|pid| (pid as PidT, counts[pid]))
+            .filter(|&(pid, count)| count > 0 && eligible(pid))
+            .fold(None, |best: Option<(PidT, usize)>, (pid, count)| match best {
+                Some((_, most)) if most >= count => best,
+                _ => Some((pid, count)),
+            })
+    }
+
+    // pageinfo_init(layout, metadata)
+    //    Initialize the `pageinfo[]` array from the physical memory `layout`,
+    //    storing it and the free bitmaps in `metadata`. Panics if `metadata`
+    //    holds fewer than `metadata_words(npages)` words.
+
+    pub fn pageinfo_init(&mut self, layout: &MemoryLayout, metadata: &'static mut [u64]) {
+        let npages = layout.memsize / PAGESIZE as usize;
+        if metadata.len() < metadata_words(npages) {
+            page_panic(c"(pageinfo_init) page metadata area too small");
+        }
+
+        let (pageinfo, free_map) = metadata.split_at_mut(pageinfo_words(npages));
+        // PhysicalPageInfo needs no more alignment than u64 and every bit
+        // pattern is valid for it.
+        self.pageinfo = unsafe {
+            core::slice::from_raw_parts_mut(pageinfo.as_mut_ptr() as *mut PhysicalPageInfo, npages)
+        };
+        for order in 0..=MAX_ORDER {
+            self.free_map_start[order + 1] = self.free_map_start[order] + free_map_words(npages, order);
+        }
+        free_map.fill(0);
+        self.free_map = free_map;
+
+        for addr in (0..layout.memsize).step_by(PAGESIZE as usize) {
+            let owner = if overlaps(layout.reserved, addr) {
+                PageOwner::PoReserved
+            } else if overlaps(layout.kernel, addr) {
+                PageOwner::PoKernel
+            } else {
+                PageOwner::PoFree
             };
     
-            let page = &mut self.pageinfo[(addr / PAGESIZE) as usize];
-            page.owner = owner.clone() as i8;
+            let page = &mut self.pageinfo[addr / PAGESIZE as usize];
+            page.owner = owner.clone() as PidT;
             page.refcount = if owner != PageOwner::PoFree { 1 } else { 0 };
+            page.flags = 0;
+            if owner == PageOwner::PoFree {
+                self.insert_free_block(0, addr / PAGESIZE as usize);
+            }
+        }
+    }
+
+    // alloc_pages(order, owner)
+    //    Allocates 2^`order` physically contiguous pages, aligned to their
+    //    size, to `owner`. The pages are cleared, have no flags, and each
+    //    gets a refcount of 1. Returns the physical address of the first
+    //    page, or None if no large enough block is free.
+
+    pub fn alloc_pages(&mut self, order: usize, owner: PidT) -> Option<PhysAddr> {
+        let (mut block_order, start) = (order..=MAX_ORDER).find_map(|o| {
+            let map = self.free_map_order(o);
+            let word = map.iter().position(|&w| w != 0)?;
+            let bit = word * 64 + map[word].trailing_zeros() as usize;
+            Some((o, bit << o))
+        })?;
+
+        self.set_block_free(block_order, start, false);
+        while block_order > order {
+            block_order -= 1;
+            self.set_block_free(block_order, start + (1 << block_order), true);
+        }
+
+        for pn in start..start + (1 << order) {
+            self.pageinfo[pn].owner = owner;
+            self.pageinfo[pn].refcount = 1;
+            self.pageinfo[pn].flags = 0;
+            (self.zero_page)(page_address(pn));
+        }
+        Some(page_address(start))
+    }
+
+    // alloc_page(owner)
+    //    Allocates a single free physical page to `owner`, clears it, and
+    //    returns its physical address. Returns None if no page is free.
+
+    pub fn alloc_page(&mut self, owner: PidT) -> Option<PhysAddr> {
+        self.alloc_pages(0, owner)
+    }
+
+    // assign(addr, owner)
+    //    Allocates the page with physical address `addr` to the given owner.
+    //    Fails if physical page `addr` was already allocated. Returns 0 on
+    //    success and -1 on failure.
+
+    pub fn assign(&mut self, addr: PhysAddr, owner: PidT) -> i32 {
+        let pn = addr.page_number().as_usize();
+        if !addr.is_page_aligned()
+            || pn >= self.pageinfo.len()
+            || self.pageinfo[pn].refcount != 0 {
+           return -1;
+        }
+    
+        self.remove_free_page(pn);
+        self.pageinfo[pn].owner = owner;
+        self.pageinfo[pn].refcount = 1;
+        self.pageinfo[pn].flags = 0;
+        0
+    }
+
+    // incref(addr)
+    //    Adds a reference to the allocated page containing physical address
+    //    `addr`. Returns the new reference count, or None if the page is free
+    //    or out of range. Panics if the count would overflow.
+
+    pub fn incref(&mut self, addr: PhysAddr) -> Option<u32> {
+        let page = self.pageinfo.get_mut(addr.page_number().as_usize())?;
+        if page.refcount == 0 {
+            return None;
+        }
+        page.refcount = match page.refcount.checked_add(1) {
+            Some(refcount) => refcount,
+            None => page_panic(c"(incref) page refcount overflow"),
+        };
+        Some(page.refcount)
+    }
+
+    // free_page(addr)
+    //    Drops a reference to the allocated page containing physical address
+    //    `addr`. The page returns to the free pool, losing its flags, when
+    //    the last reference goes away. Returns the new reference count, or
+    //    None if the page is free, out of range, or would be freed while
+    //    PF_PINNED.
+
+    pub fn free_page(&mut self, addr: PhysAddr) -> Option<u32> {
+        let pn = addr.page_number().as_usize();
+        let page = self.pageinfo.get_mut(pn)?;
+        if page.refcount == 0 || (page.refcount == 1 && page.flags & PF_PINNED != 0) {
+            return None;
+        }
+        page.refcount -= 1;
+        if page.refcount == 0 {
+            page.owner = PageOwner::PoFree as PidT;
+            page.flags = 0;
+            self.insert_free_block(0, pn);
+        }
+        Some(self.pageinfo[pn].refcount)
+    }
+
+    // set_flags(addr, flags)
+    //    Sets `flags` on the allocated page containing physical address
+    //    `addr`. Returns 0 on success and -1 if the page is free or out of
+    //    range.
+
+    pub fn set_flags(&mut self, addr: PhysAddr, flags: u8) -> i32 {
+        match self.pageinfo.get_mut(addr.page_number().as_usize()) {
+            Some(page) if page.refcount > 0 => {
+                page.flags |= flags;
+                0
+            }
+            _ => -1,
+        }
+    }
+
+    // clear_flags(addr, flags)
+    //    Clears `flags` on the page containing physical address `addr`.
+
+    pub fn clear_flags(&mut self, addr: PhysAddr, flags: u8) {
+        if let Some(page) = self.pageinfo.get_mut(addr.page_number().as_usize()) {
+            page.flags &= !flags;
         }
     }
 
+    // free_pages(addr, order)
+    //    Drops a reference to each of the 2^`order` pages starting at
+    //    physical address `addr`, as allocated by `alloc_pages`. Freed pages
+    //    merge back into larger blocks. Returns 0 on success and -1 if the
+    //    block is misaligned, out of range, or contains a free or pinned page.
+
+    pub fn free_pages(&mut self, addr: PhysAddr, order: usize) -> i32 {
+        let start = addr.page_number().as_usize();
+        let npages = 1usize << order;
+        if !addr.is_page_aligned()
+            || !start.is_multiple_of(npages)
+            || start + npages > self.pageinfo.len()
+            || self.pageinfo[start..start + npages].iter()
+                .any(|p| p.refcount == 0 || (p.refcount == 1 && p.flags & PF_PINNED != 0)) {
+            return -1;
+        }
+
+        for pn in start..start + npages {
+            self.free_page(page_address(pn));
+        }
+        0
+    }
+
     // get_current_process_mut
     //    Returns a mutable reference to the pid process. 
 
@@ -94,3 +571,516 @@ impl PhysicalPageInfoTable {
         }
     }    
 }
+
+
+// Self tests (run with the `selftest` boot command)
+
+kernel_test! {
+    fn pageinfo_init_marks_reserved_and_kernel_pages(kernel: &mut Kernel) {
+        let pageinfo = &kernel.pageinfo_table.pageinfo;
+        let io_page = 0xA0000 >> PAGEOFFBITS;
+        let kernel_page = KERNEL_START_ADDR as usize >> PAGEOFFBITS;
+        let kstack_page = (KERNEL_STACK_TOP - PAGESIZE) as usize >> PAGEOFFBITS;
+
+        test_assert!(pageinfo[0].owner == PageOwner::PoReserved as PidT);
+        test_assert!(pageinfo[io_page].owner == PageOwner::PoReserved as PidT);
+        test_assert!(pageinfo[kernel_page].owner == PageOwner::PoKernel as PidT);
+        test_assert!(pageinfo[kstack_page].owner == PageOwner::PoKernel as PidT);
+        for page in pageinfo.iter() {
+            test_assert!((page.refcount == 0) == (page.owner == PageOwner::PoFree as PidT));
+        }
+    }
+}
+
+kernel_test! {
+    fn alloc_page_returns_zeroed_page(kernel: &mut Kernel) {
+        let pa = match kernel.pageinfo_table.alloc_page(1) {
+            Some(pa) => pa,
+            None => return Err("no free physical page"),
+        };
+        let pn = pa.page_number().as_usize();
+        test_assert!(kernel.pageinfo_table.pageinfo[pn].owner == 1);
+        test_assert!(kernel.pageinfo_table.pageinfo[pn].refcount == 1);
+        test_assert!(!kernel.pageinfo_table.is_free(pn));
+        let page = unsafe { core::slice::from_raw_parts(pa.as_ptr::<u8>(), PAGESIZE as usize) };
+        test_assert!(page.iter().all(|&b| b == 0));
+
+        test_assert!(kernel.pageinfo_table.free_page(pa) == Some(0));
+        test_assert!(kernel.pageinfo_table.is_free(pn));
+        // freeing merged the page back, so the same split happens again
+        test_assert!(kernel.pageinfo_table.alloc_page(1) == Some(pa));
+        test_assert!(kernel.pageinfo_table.free_page(pa) == Some(0));
+    }
+}
+
+kernel_test! {
+    fn alloc_pages_returns_aligned_blocks(kernel: &mut Kernel) {
+        let before = kernel.pageinfo_table.fragmentation_stats();
+        let pa = match kernel.pageinfo_table.alloc_pages(3, 1) {
+            Some(pa) => pa,
+            None => return Err("no free block of 8 pages"),
+        };
+        test_assert!(pa.as_usize().is_multiple_of(8 * PAGESIZE as usize));
+        let start = pa.page_number().as_usize();
+        for pn in start..start + 8 {
+            test_assert!(kernel.pageinfo_table.pageinfo[pn].owner == 1);
+            test_assert!(kernel.pageinfo_table.pageinfo[pn].refcount == 1);
+        }
+        test_assert!(kernel.pageinfo_table.fragmentation_stats().free_pages == before.free_pages - 8);
+
+        test_assert!(kernel.pageinfo_table.free_pages(pa, 3) == 0);
+        test_assert!(kernel.pageinfo_table.fragmentation_stats() == before);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::boxed::Box;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    const PAGE: usize = PAGESIZE as usize;
+
+    // The layout of the real machine: page 0 and the I/O hole are reserved,
+    // the kernel image starts at KERNEL_START_ADDR, and the stack sits below
+    // KERNEL_STACK_TOP.
+    const RESERVED: [Range<usize>; 2] = [0..PAGE, 0xA0000..0x100000];
+    const KERNEL: [Range<usize>; 2] = [
+        KERNEL_START_ADDR as usize..0x52345,
+        (KERNEL_STACK_TOP - KERNEL_STACK_SIZE) as usize..KERNEL_STACK_TOP as usize,
+    ];
+
+    std::thread_local! {
+        // pages passed to `record_zero_page` by the current test
+        static ZEROED: RefCell<Vec<PhysAddr>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_zero_page(pa: PhysAddr) {
+        ZEROED.with(|z| z.borrow_mut().push(pa));
+    }
+
+    fn phys(addr: usize) -> PhysAddr {
+        PhysAddr::new(addr).unwrap()
+    }
+
+    // check_buddy_invariants(table)
+    //    Every free page lies in exactly one free block, every page in a
+    //    free block has refcount 0, and no free block has a free buddy.
+    fn check_buddy_invariants(table: &PhysicalPageInfoTable) {
+        let mut covered = [0; NPAGES as usize];
+        for order in 0..=MAX_ORDER {
+            for pn in (0..NPAGES as usize).step_by(1 << order) {
+                if !table.block_is_free(order, pn) {
+                    continue;
+                }
+                assert!(pn + (1 << order) <= NPAGES as usize, "block {:#x} order {} out of range", pn, order);
+                let block = pn..pn + (1 << order);
+                assert!(table.pageinfo[block.clone()].iter().all(|p| p.refcount == 0),
+                    "block {:#x} order {} has allocated pages", pn, order);
+                for count in &mut covered[block] {
+                    *count += 1;
+                }
+                let buddy = pn ^ (1 << order);
+                assert!(order == MAX_ORDER || buddy >= NPAGES as usize || !table.block_is_free(order, buddy),
+                    "block {:#x} order {} has a free buddy", pn, order);
+            }
+        }
+        for (pn, page) in table.pageinfo.iter().enumerate() {
+            assert_eq!(covered[pn], if page.refcount == 0 { 1 } else { 0 }, "page {:#x}", pn);
+        }
+    }
+
+    // metadata(npages)
+    //    Returns a metadata area for `npages` pages that lives as long as
+    //    the test process.
+    fn metadata(npages: usize) -> &'static mut [u64] {
+        Box::leak(std::vec![u64::MAX; metadata_words(npages)].into_boxed_slice())
+    }
+
+    fn layout(memsize: usize) -> MemoryLayout<'static> {
+        MemoryLayout { memsize, reserved: &RESERVED, kernel: &KERNEL }
+    }
+
+    fn new_table() -> PhysicalPageInfoTable {
+        let mut table = PhysicalPageInfoTable::with_zero_page(record_zero_page);
+        table.pageinfo_init(&layout(MEMSIZE_PHYSICAL as usize), metadata(NPAGES as usize));
+        table
+    }
+
+    fn owner(table: &PhysicalPageInfoTable, addr: usize) -> PidT {
+        table.pageinfo[addr / PAGE].owner
+    }
+
+    // Xorshift64 generator, so the randomized tests need no dependencies
+    // and every failure can be replayed from its seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    #[test]
+    fn init_marks_reserved_and_kernel_pages() {
+        let table = new_table();
+        assert_eq!(owner(&table, 0), PageOwner::PoReserved as PidT);
+        assert_eq!(owner(&table, 0xA0000), PageOwner::PoReserved as PidT);
+        assert_eq!(owner(&table, 0xFF000), PageOwner::PoReserved as PidT);
+        assert_eq!(owner(&table, 0x100000), PageOwner::PoFree as PidT);
+        assert_eq!(owner(&table, KERNEL_START_ADDR as usize), PageOwner::PoKernel as PidT);
+        // a partially used last page still belongs to the kernel
+        assert_eq!(owner(&table, 0x52000), PageOwner::PoKernel as PidT);
+        assert_eq!(owner(&table, 0x53000), PageOwner::PoFree as PidT);
+        assert_eq!(owner(&table, KERNEL_STACK_TOP as usize - PAGE), PageOwner::PoKernel as PidT);
+        assert_eq!(owner(&table, (KERNEL_STACK_TOP - KERNEL_STACK_SIZE) as usize), PageOwner::PoKernel as PidT);
+        assert_eq!(owner(&table, KERNEL_STACK_TOP as usize), PageOwner::PoFree as PidT);
+        for page in table.pageinfo.iter() {
+            assert_eq!(page.refcount == 0, page.owner == PageOwner::PoFree as PidT);
+        }
+    }
+
+    #[test]
+    fn init_prefers_reserved_over_kernel() {
+        // the empty second region must not reserve page 0x41000
+        let reserved = [0x40000..0x41000, 0x41000..0x41000];
+        let mut table = PhysicalPageInfoTable::new();
+        let layout = MemoryLayout { memsize: MEMSIZE_PHYSICAL as usize, reserved: &reserved, kernel: &KERNEL };
+        table.pageinfo_init(&layout, metadata(NPAGES as usize));
+        assert_eq!(owner(&table, 0x40000), PageOwner::PoReserved as PidT);
+        assert_eq!(owner(&table, 0x41000), PageOwner::PoKernel as PidT);
+        assert_eq!(owner(&table, 0), PageOwner::PoFree as PidT);
+    }
+
+    #[test]
+    fn init_sizes_table_from_layout() {
+        let memsize = 0x4000000;
+        let npages = memsize / PAGE;
+        let mut table = PhysicalPageInfoTable::with_zero_page(record_zero_page);
+        table.pageinfo_init(&layout(memsize), metadata(npages));
+        assert_eq!(table.pageinfo.len(), npages);
+        assert_eq!(owner(&table, memsize - PAGE), PageOwner::PoFree as PidT);
+        assert_eq!(table.assign(phys(memsize), 1), -1);
+
+        // everything from 2MB up is free and forms blocks of MAX_ORDER
+        let stats = table.fragmentation_stats();
+        assert_eq!(stats.largest_free_order(), Some(MAX_ORDER));
+        assert_eq!(stats.free_blocks[MAX_ORDER], (memsize - 0x400000) / (PAGE << MAX_ORDER));
+        assert_eq!(table.alloc_pages(MAX_ORDER, 1), Some(phys(0x400000)));
+        assert!(table.is_free(npages - 1));
+
+        // the table can be reinitialized with a smaller memory
+        table.pageinfo_init(&layout(MEMSIZE_PHYSICAL as usize), metadata(NPAGES as usize));
+        assert_eq!(table.pageinfo.len(), NPAGES as usize);
+        check_buddy_invariants(&table);
+    }
+
+    #[test]
+    #[should_panic(expected = "metadata area too small")]
+    fn init_rejects_small_metadata_area() {
+        let mut table = PhysicalPageInfoTable::new();
+        table.pageinfo_init(&layout(0x4000000), metadata(NPAGES as usize));
+    }
+
+    #[test]
+    fn assign_claims_free_pages_only() {
+        let mut table = new_table();
+        assert_eq!(table.assign(phys(0x100001), 1), -1);
+        assert_eq!(table.assign(phys(0xA0000), 1), -1);
+        assert_eq!(table.assign(phys(KERNEL_START_ADDR as usize), 1), -1);
+        assert_eq!(table.assign(phys(MEMSIZE_PHYSICAL as usize), 1), -1);
+        assert_eq!(table.assign(phys(0x100000), 1), 0);
+        assert_eq!(table.assign(phys(0x100000), 2), -1);
+        assert_eq!(owner(&table, 0x100000), 1);
+    }
+
+    #[test]
+    fn refcount_frees_page_on_last_reference() {
+        let mut table = new_table();
+        assert_eq!(table.incref(phys(0x100000)), None);
+        assert_eq!(table.free_page(phys(0x100000)), None);
+        assert_eq!(table.assign(phys(0x100000), 3), 0);
+        assert_eq!(table.incref(phys(0x100123)), Some(2));
+        assert_eq!(table.free_page(phys(0x100000)), Some(1));
+        assert_eq!(owner(&table, 0x100000), 3);
+        assert_eq!(table.free_page(phys(0x100000)), Some(0));
+        assert_eq!(owner(&table, 0x100000), PageOwner::PoFree as PidT);
+        assert_eq!(table.free_page(phys(0x100000)), None);
+        assert_eq!(table.incref(phys(MEMSIZE_PHYSICAL as usize)), None);
+    }
+
+    #[test]
+    fn refcount_is_not_limited_to_a_byte() {
+        let mut table = new_table();
+        assert_eq!(table.assign(phys(0x100000), 1), 0);
+        for _ in 0..1000 {
+            table.incref(phys(0x100000));
+        }
+        assert_eq!(table.pageinfo[0x100].refcount, 1001);
+        assert_eq!(table.free_page(phys(0x100000)), Some(1000));
+    }
+
+    #[test]
+    #[should_panic(expected = "refcount overflow")]
+    fn refcount_overflow_panics() {
+        let mut table = new_table();
+        assert_eq!(table.assign(phys(0x100000), 1), 0);
+        table.pageinfo[0x100].refcount = u32::MAX;
+        table.incref(phys(0x100000));
+    }
+
+    #[test]
+    fn owner_can_exceed_a_byte() {
+        let mut table = new_table();
+        assert_eq!(table.assign(phys(0x100000), 300), 0);
+        assert_eq!(table.alloc_page(1000), Some(phys(0x1000)));
+        assert_eq!(owner(&table, 0x100000), 300);
+        assert_eq!(owner(&table, 0x1000), 1000);
+    }
+
+    #[test]
+    fn flags_follow_the_page_lifecycle() {
+        let mut table = new_table();
+        let pa = table.alloc_page(1).unwrap();
+        assert_eq!(table.set_flags(pa, PF_PAGETABLE), 0);
+        assert_eq!(table.free_page(pa), Some(0));
+        // a freed page's flags never reach its next owner
+        assert_eq!(table.alloc_page(1), Some(pa));
+        assert_eq!(table.pageinfo[pa.page_number().as_usize()].flags, 0);
+        assert_eq!(table.assign(phys(0x100000), 1), 0);
+        assert_eq!(table.pageinfo[0x100].flags, 0);
+
+        assert_eq!(table.set_flags(phys(0x100000), PF_PAGETABLE | PF_PINNED), 0);
+        table.clear_flags(phys(0x100000), PF_PINNED);
+        assert_eq!(table.pageinfo[0x100].flags, PF_PAGETABLE);
+        assert_eq!(table.free_page(phys(0x100000)), Some(0));
+        assert_eq!(table.pageinfo[0x100].flags, 0);
+        assert_eq!(table.set_flags(phys(0x100000), PF_PAGETABLE), -1);
+    }
+
+    #[test]
+    fn pinned_pages_stay_allocated() {
+        let mut table = new_table();
+        assert_eq!(table.alloc_pages(1, 1), Some(phys(0x2000)));
+        assert_eq!(table.set_flags(phys(0x3000), PF_PINNED), 0);
+        assert_eq!(table.free_pages(phys(0x2000), 1), -1);
+        assert_eq!(table.free_page(phys(0x3000)), None);
+        assert_eq!(table.incref(phys(0x3000)), Some(2));
+        assert_eq!(table.free_page(phys(0x3000)), Some(1));
+
+        table.clear_flags(phys(0x3000), PF_PINNED);
+        assert_eq!(table.free_pages(phys(0x2000), 1), 0);
+        assert!(table.is_free(3));
+        check_buddy_invariants(&table);
+    }
+
+    #[test]
+    fn alloc_page_prefers_smallest_free_block() {
+        let mut table = new_table();
+        // pages 1 and 0x53 are free blocks of order 0: their buddies are
+        // reserved and kernel pages
+        assert_eq!(table.alloc_page(1), Some(phys(0x1000)));
+        assert_eq!(table.alloc_page(2), Some(phys(0x53000)));
+        assert_eq!(owner(&table, 0x53000), 2);
+        assert_eq!(table.pageinfo[0x53].refcount, 1);
+        assert!(!table.is_free(0x53));
+        ZEROED.with(|z| assert_eq!(*z.borrow(), [0x1000, 0x53000].map(phys)));
+
+        // the next page splits the order 1 block at page 2
+        assert_eq!(table.alloc_page(3), Some(phys(0x2000)));
+        assert_eq!(table.alloc_page(4), Some(phys(0x3000)));
+        assert_eq!(table.free_page(phys(0x1000)), Some(0));
+        assert!(table.is_free(1));
+        assert_eq!(table.alloc_page(5), Some(phys(0x1000)));
+        check_buddy_invariants(&table);
+    }
+
+    #[test]
+    fn alloc_pages_returns_aligned_blocks() {
+        let mut table = new_table();
+        let before = table.fragmentation_stats();
+        // 0x100000-0x1FFFFF is the only free block of 256 pages
+        assert_eq!(before.largest_free_order(), Some(8));
+        assert_eq!(table.alloc_pages(8, 1), Some(phys(0x100000)));
+        assert_eq!(table.alloc_pages(8, 1), None);
+        assert_eq!(table.alloc_pages(MAX_ORDER + 1, 1), None);
+
+        // pages 0x20-0x3F form the only free block of order 5
+        assert_eq!(table.alloc_pages(5, 2), Some(phys(0x20000)));
+        assert!(table.pageinfo[0x20..0x40].iter().all(|p| p.owner == 2 && p.refcount == 1));
+        assert!(table.is_free(0x1F));
+        ZEROED.with(|z| assert_eq!(z.borrow().len(), 256 + 32));
+        check_buddy_invariants(&table);
+
+        assert_eq!(table.free_pages(phys(0x21000), 5), -1);
+        assert_eq!(table.free_pages(phys(0x20000), 5), 0);
+        assert_eq!(table.free_pages(phys(0x20000), 5), -1);
+        assert_eq!(table.free_pages(phys(0x100000), 8), 0);
+        assert_eq!(table.fragmentation_stats(), before);
+        check_buddy_invariants(&table);
+    }
+
+    #[test]
+    fn freeing_pages_merges_buddies() {
+        let mut table = new_table();
+        assert_eq!(table.alloc_pages(8, 1), Some(phys(0x100000)));
+        let stats = table.fragmentation_stats();
+        // give the block back one page at a time, highest page first
+        for pn in (0x100..0x200).rev() {
+            assert_eq!(table.free_page(phys(pn * PAGE)), Some(0));
+            check_buddy_invariants(&table);
+        }
+        let merged = table.fragmentation_stats();
+        assert_eq!(merged.free_pages, stats.free_pages + 256);
+        assert_eq!(merged.free_blocks[8], stats.free_blocks[8] + 1);
+        assert_eq!(merged.free_blocks[0], stats.free_blocks[0]);
+    }
+
+    #[test]
+    fn assign_splits_free_block() {
+        let mut table = new_table();
+        let stats = table.fragmentation_stats();
+        assert_eq!(table.assign(phys(0x1FF000), 1), 0);
+        // the block of 256 pages is now split into blocks of 128, 64, ... 1
+        let split = table.fragmentation_stats();
+        assert_eq!(split.free_pages, stats.free_pages - 1);
+        assert_eq!(split.free_blocks[8], 0);
+        assert_eq!(split.largest_free_order(), Some(7));
+        assert_eq!(table.alloc_pages(7, 2), Some(phys(0x100000)));
+        check_buddy_invariants(&table);
+        assert_eq!(table.free_page(phys(0x1FF000)), Some(0));
+        assert_eq!(table.free_pages(phys(0x100000), 7), 0);
+        assert_eq!(table.fragmentation_stats(), stats);
+    }
+
+    #[test]
+    fn largest_owner_counts_allocated_pages() {
+        let mut table = new_table();
+        assert_eq!(table.largest_owner(|_| true), None);
+        assert_eq!(table.alloc_pages(2, 3), Some(phys(0x4000)));
+        assert_eq!(table.alloc_pages(1, 5), Some(phys(0x2000)));
+        assert_eq!(table.assign(phys(0x100000), 5), 0);
+        assert_eq!(table.assign(phys(0x101000), 5), 0);
+        assert_eq!(table.pages_owned_by(3), 4);
+        assert_eq!(table.pages_owned_by(5), 4);
+        // kernel and reserved pages never make a process the largest
+        assert_eq!(table.pages_owned_by(PageOwner::PoKernel as PidT), 0x13 + 4);
+
+        // ties go to the lowest pid
+        assert_eq!(table.largest_owner(|_| true), Some((3, 4)));
+        assert_eq!(table.incref(phys(0x100000)), Some(2));
+        assert_eq!(table.assign(phys(0x102000), 5), 0);
+        assert_eq!(table.largest_owner(|_| true), Some((5, 5)));
+        assert_eq!(table.largest_owner(|pid| pid != 5), Some((3, 4)));
+        assert_eq!(table.largest_owner(|pid| pid == 7), None);
+
+        assert_eq!(table.free_pages(phys(0x4000), 2), 0);
+        assert_eq!(table.largest_owner(|pid| pid != 5), None);
+    }
+
+    #[test]
+    fn alloc_page_fails_when_memory_is_exhausted() {
+        let mut table = new_table();
+        let nfree = table.pageinfo.iter().filter(|p| p.refcount == 0).count();
+        for _ in 0..nfree {
+            assert!(table.alloc_page(1).is_some());
+        }
+        assert_eq!(table.alloc_page(1), None);
+        assert_eq!(table.free_page(phys(0x100000)), Some(0));
+        assert_eq!(table.alloc_page(1), Some(phys(0x100000)));
+    }
+
+    // Apply random alloc, assign, incref and free operations and compare the
+    // table with a model after every step.
+    #[test]
+    fn random_operations_match_model() {
+        for seed in 1..=64u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9E3779B97F4A7C15));
+            let mut table = new_table();
+            let initial = table.pageinfo.to_vec();
+            // addr -> (owner, refcount) for pages allocated by the test
+            let mut model: BTreeMap<usize, (PidT, u32)> = BTreeMap::new();
+
+            for _ in 0..2000 {
+                let addr = rng.below(NPAGES) as usize * PAGE;
+                let was_free = initial[addr / PAGE].refcount == 0;
+                match rng.below(4) {
+                    3 => {
+                        let owner = 1 + rng.below(15) as PidT;
+                        let order = rng.below(4) as usize;
+                        let npages = 1 << order;
+                        let is_free = |pn: usize| initial[pn].refcount == 0 && !model.contains_key(&(pn * PAGE));
+                        // merging guarantees that any free aligned run is allocatable
+                        let possible = (0..NPAGES as usize).step_by(npages)
+                            .any(|start| (start..start + npages).all(is_free));
+                        match table.alloc_pages(order, owner) {
+                            Some(pa) => {
+                                let start = pa.page_number().as_usize();
+                                assert!(start.is_multiple_of(npages), "seed {}", seed);
+                                assert!((start..start + npages).all(is_free), "seed {}", seed);
+                                for pn in start..start + npages {
+                                    model.insert(pn * PAGE, (owner, 1));
+                                }
+                            }
+                            None => assert!(!possible, "seed {}", seed),
+                        }
+                    }
+                    0 => {
+                        let owner = 1 + rng.below(15) as PidT;
+                        let expected = if was_free && !model.contains_key(&addr) {
+                            model.insert(addr, (owner, 1));
+                            0
+                        } else {
+                            -1
+                        };
+                        assert_eq!(table.assign(phys(addr), owner), expected, "seed {}", seed);
+                    }
+                    1 => {
+                        let expected = match model.get_mut(&addr) {
+                            Some((_, refcount)) => {
+                                *refcount += 1;
+                                Some(*refcount)
+                            }
+                            None if was_free => None,
+                            None => continue,
+                        };
+                        assert_eq!(table.incref(phys(addr)), expected, "seed {}", seed);
+                    }
+                    _ => {
+                        let expected = match model.get_mut(&addr) {
+                            Some((_, refcount)) => {
+                                *refcount -= 1;
+                                let remaining = *refcount;
+                                if remaining == 0 {
+                                    model.remove(&addr);
+                                }
+                                Some(remaining)
+                            }
+                            None if was_free => None,
+                            // leave kernel and reserved pages alone
+                            None => continue,
+                        };
+                        assert_eq!(table.free_page(phys(addr)), expected, "seed {}", seed);
+                    }
+                }
+
+                for (pn, page) in table.pageinfo.iter().enumerate() {
+                    let (owner, refcount) = match model.get(&(pn * PAGE)) {
+                        Some(&entry) => entry,
+                        None if initial[pn].refcount == 0 => (PageOwner::PoFree as PidT, 0),
+                        None => (initial[pn].owner, initial[pn].refcount),
+                    };
+                    assert_eq!((page.owner, page.refcount), (owner, refcount), "seed {} page {}", seed, pn);
+                    assert_eq!(table.is_free(pn), refcount == 0, "seed {} page {}", seed, pn);
+                    assert!(refcount > 0 || page.flags == 0, "seed {} page {}", seed, pn);
+                }
+                check_buddy_invariants(&table);
+            }
+        }
+    }
+}
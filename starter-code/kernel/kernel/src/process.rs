@@ -2,13 +2,14 @@ use bindings::bindings_x86_64::*;
 use bindings::bindings_kernel::*;
 use stdlib::my_assert;
 
+use crate::selftest::{kernel_test, test_assert};
+
 unsafe extern "C" {
     fn set_pagetable(pagetable: *mut x86_64_pagetable);
     fn program_load(process: *mut Proc, program_number: i32, arg: *const u8) -> i32;
-    fn exception_return(registers: *const x86_64_registers);
+    fn exception_return(registers: *const x86_64_registers) -> !;
     fn process_init(process: *mut Proc);
     fn c_panic(format: *const core::ffi::c_char, ...) -> !;
-    static kernel_pagetable: *mut x86_64_pagetable;
 }
 
 pub struct ProcessTable {
@@ -18,32 +19,36 @@ pub struct ProcessTable {
 
 impl ProcessTable {
     pub fn new() -> Self {
-        let mut processes = [Proc::default(); NPROC];
-        for pid in 0..NPROC {
-            // Note that `processes[0]` is never used.
-            processes[pid] = Proc::new(pid as i32, P_FREE);
-        }
+        // Note that `processes[0]` is never used.
+        let processes = core::array::from_fn(|pid| Proc::new(pid as i32, P_FREE));
         ProcessTable {
             processes,
             current: None,
         }
     }
 
-    // process_setup(pid, program_number)
-    //    Load application program `program_number` as process number `pid`.
-    //    This loads the application's code and data into memory, sets its
-    //    %rip and %rsp, gives it a stack page, and marks it as runnable.
+    // process_setup(pid, program_number, pagetable)
+    //    Load application program `program_number` as process number `pid`
+    //    with page table `pagetable`. This loads the application's code and
+    //    data into memory and sets its %rip. Returns the process, or Err with
+    //    the loader's result if the program could not be loaded (e.g. out of
+    //    memory); the process then still holds whatever pages were assigned
+    //    to it.
 
-    pub fn process_setup(&mut self, pid: usize, pn: usize) -> Proc {
+    pub fn process_setup(&mut self, pid: usize, pn: usize, pagetable: *mut x86_64_pagetable) -> Result<Proc, i32> {
         let p = self.get_process_by_pid_mut(pid);
         unsafe { 
             process_init(p);
-            p.p_pagetable = kernel_pagetable;
+            p.p_pagetable = pagetable;
+            p.p_parent = 0;
+            p.p_rlimits = Rlimits::UNLIMITED;
 
             let r = program_load(p, pn as i32, core::ptr::null());
-            my_assert!(r >= 0);
+            if r < 0 {
+                return Err(r);
+            }
         }
-        return *p;
+        Ok(*p)
     }
 
     // run(p)
@@ -66,7 +71,6 @@ impl ProcessTable {
             // registers then jumps back to user mode.
             exception_return(&p.p_registers);
         }
-        loop {} // should never get here
     }
 
     // schedule
@@ -100,16 +104,14 @@ impl ProcessTable {
     }
     
     // exception
-    //    Copy the saved registers into the `current` process descriptor
-    //    and set up the kernel's page table.
+    //    Copy the saved registers into the `current` process descriptor.
+    //    The process keeps its page table; the caller switches to the
+    //    kernel's.
     
     pub fn exception(&mut self, reg: &mut x86_64_registers) {
         if let Some(current_proc_ptr) = self.current {
             let current_proc = unsafe { &mut *current_proc_ptr };
             current_proc.p_registers = *reg;
-            unsafe {
-                current_proc.p_pagetable = kernel_pagetable;
-            }
         } else {
             unsafe {
                 c_panic("(exception) No current process available.".as_ptr() as *const core::ffi::c_char);
@@ -181,6 +183,23 @@ impl ProcessTable {
         &mut self.processes[pid]
     }
 
+    // find_free_pid()
+    //    Returns the lowest unused process ID, or None if every process slot
+    //    is in use.
+
+    pub fn find_free_pid(&self) -> Option<usize> {
+        (1..NPROC).find(|&pid| self.processes[pid].p_state == P_FREE)
+    }
+
+    // count_children(pid)
+    //    Returns the number of live processes whose parent is `pid`.
+
+    pub fn count_children(&self, pid: PidT) -> usize {
+        self.processes.iter()
+            .filter(|p| p.p_state != P_FREE && p.p_parent == pid)
+            .count()
+    }
+
     // set_register_rax
     //    Helper function to safely set a register in the current process.
 
@@ -195,3 +214,17 @@ impl ProcessTable {
         }
     }
 }
+
+
+// Self tests (run with the `selftest` boot command)
+
+kernel_test! {
+    fn process_table_starts_free(kernel: &mut Kernel) {
+        test_assert!(kernel.proc_table.current.is_none());
+        for pid in 0..NPROC {
+            let p = kernel.proc_table.get_process_by_pid(pid);
+            test_assert!(p.p_pid == pid as i32);
+            test_assert!(p.p_state == P_FREE);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use bindings::bindings_kernel::*;
+use stdlib::cpos;
+
+use crate::kernel::Kernel;
+
+// selftest.rs
+//
+//    In-kernel self tests. `kernel_test!` registers a test function in the
+//    `.kernel_tests` linker section (collected in link/kernel.ld). Booting
+//    with the `selftest` command runs every registered test after the
+//    hardware and `pageinfo` are initialized, but before any process exists.
+//    Each result is printed to the console and `log.txt`, and QEMU exits with
+//    a summary status (see `qemu_exit`).
+
+unsafe extern "C" {
+    fn error_printf(cpos: i32, color: i32, format: *const core::ffi::c_char, ...) -> i32;
+    fn qemu_exit(status: core::ffi::c_int);
+    fn check_keyboard() -> core::ffi::c_int;
+    static kernel_tests_start: u8;
+    static kernel_tests_end: u8;
+}
+
+// A test returns Err(message) describing the first failed check.
+pub type TestResult = Result<(), &'static str>;
+
+#[repr(C)]
+pub struct KernelTest {
+    pub name: &'static str,
+    pub func: fn(&mut Kernel) -> TestResult,
+}
+
+// kernel_test! { fn name(kernel: &mut Kernel) { ... } }
+//    Define a kernel self test and register it in `.kernel_tests`. The body
+//    evaluates to a `TestResult`; use `test_assert!` for individual checks.
+
+macro_rules! kernel_test {
+    (fn $name:ident($kernel:ident: &mut Kernel) $body:block) => {
+        #[allow(dead_code)]
+        fn $name($kernel: &mut $crate::kernel::Kernel) -> $crate::selftest::TestResult {
+            $body
+            Ok(())
+        }
+
+        // Host builds (`cargo test`) cannot link the kernel's C functions.
+        #[cfg(not(test))]
+        const _: () = {
+            #[used]
+            #[link_section = ".kernel_tests"]
+            static ENTRY: $crate::selftest::KernelTest = $crate::selftest::KernelTest {
+                name: stringify!($name),
+                func: $name,
+            };
+        };
+    };
+}
+
+// test_assert!(condition)
+//    Fail the current kernel test, reporting the location and condition,
+//    if `condition` is false.
+
+macro_rules! test_assert {
+    ($condition:expr) => {
+        if !$condition {
+            return Err(concat!(file!(), ":", line!(), ": ", stringify!($condition)));
+        }
+    };
+}
+
+pub(crate) use kernel_test;
+pub(crate) use test_assert;
+
+// kernel_tests()
+//    Returns every test registered with `kernel_test!`.
+
+fn kernel_tests() -> &'static [KernelTest] {
+    unsafe {
+        let start = &kernel_tests_start as *const u8 as *const KernelTest;
+        let end = &kernel_tests_end as *const u8 as *const KernelTest;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+// run_kernel_test(kernel, test)
+//    Run `test`, then free every process it left behind, whether or not it
+//    returned early. A test that passed still fails if it leaked physical
+//    pages or swap slots.
+
+fn run_kernel_test(kernel: &mut Kernel, test: &KernelTest) -> TestResult {
+    let before = kernel.pageinfo_table.fragmentation_stats();
+    let free_slots = kernel.swap.free_slots();
+    let result = (test.func)(kernel);
+
+    kernel.proc_table.current = None;
+    for pid in 1..NPROC {
+        if kernel.proc_table.get_process_by_pid(pid).p_state != P_FREE {
+            kernel.process_free(pid);
+        }
+    }
+    result?;
+    test_assert!(kernel.swap.free_slots() == free_slots);
+    test_assert!(kernel.pageinfo_table.fragmentation_stats() == before);
+    Ok(())
+}
+
+// run_kernel_tests(kernel)
+//    Run every registered test against `kernel`, report the results, and
+//    exit QEMU. Without the exit device, waits for Control-C instead.
+
+pub fn run_kernel_tests(kernel: &mut Kernel) -> ! {
+    let tests = kernel_tests();
+    let mut failed = 0;
+    let mut row = 0;
+
+    unsafe {
+        error_printf(cpos!(row, 0), 0x0F00,
+            c"SELFTEST: running %d tests\n".as_ptr(), tests.len() as i32);
+    }
+    for test in tests {
+        row = (row + 1) % 23;
+        let result = run_kernel_test(kernel, test);
+        unsafe {
+            match result {
+                Ok(()) => {
+                    error_printf(cpos!(row, 0), 0x0A00, c"  ok    %.*s\n".as_ptr(),
+                        test.name.len() as i32, test.name.as_ptr());
+                }
+                Err(msg) => {
+                    failed += 1;
+                    error_printf(cpos!(row, 0), 0x0C00, c"  FAIL  %.*s: %.*s\n".as_ptr(),
+                        test.name.len() as i32, test.name.as_ptr(),
+                        msg.len() as i32, msg.as_ptr());
+                }
+            }
+        }
+    }
+
+    unsafe {
+        error_printf(cpos!(23, 0), if failed == 0 { 0x0A00 } else { 0x0C00 },
+            c"SELFTEST: %d passed, %d failed\n".as_ptr(),
+            (tests.len() - failed) as i32, failed as i32);
+        qemu_exit(if failed == 0 { QEMU_EXIT_SUCCESS } else { QEMU_EXIT_FAILURE });
+        loop {
+            check_keyboard();
+        }
+    }
+}
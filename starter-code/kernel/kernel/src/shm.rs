@@ -0,0 +1,227 @@
+use bindings::bindings_x86_64::*;
+use bindings::bindings_kernel::*;
+
+use crate::selftest::{kernel_test, test_assert};
+
+// shm.rs
+//
+//    Shared memory segments. `sys_shm_create` names a segment by a key and
+//    a size; processes that attach it with `sys_shm_attach` map the same
+//    physical pages. The pages are allocated when the first process
+//    attaches the segment, belong to one of the processes attached to it,
+//    and hold one reference per attached process. A segment disappears
+//    with its pages when the last attached process detaches or exits.
+
+// Number of segments that can exist at once.
+pub const MAX_SHM_SEGMENTS: usize = 8;
+
+// Largest segment, in pages.
+pub const SHM_MAX_PAGES: usize = 16;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ShmSegment {
+    pub key: u64,
+    pub npages: usize,
+    pub pages: [PhysAddr; SHM_MAX_PAGES],  // backing pages while attached
+    pub attached_at: [usize; NPROC],        // address per process, 0 if none
+    in_use: bool,
+}
+
+impl ShmSegment {
+    const fn new() -> Self {
+        ShmSegment {
+            key: 0,
+            npages: 0,
+            pages: [PhysAddr::ZERO; SHM_MAX_PAGES],
+            attached_at: [0; NPROC],
+            in_use: false,
+        }
+    }
+
+    // size()
+    //    Returns the segment's size in bytes.
+
+    pub fn size(&self) -> usize {
+        self.npages * PAGESIZE as usize
+    }
+
+    // attachers()
+    //    Returns the processes the segment is attached to, lowest first.
+
+    pub fn attachers(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..NPROC).filter(|&pid| self.attached_at[pid] != 0)
+    }
+}
+
+pub struct ShmTable {
+    segments: [ShmSegment; MAX_SHM_SEGMENTS],
+}
+
+impl ShmTable {
+    pub const fn new() -> Self {
+        ShmTable {
+            segments: [ShmSegment::new(); MAX_SHM_SEGMENTS],
+        }
+    }
+
+    // create(key, npages)
+    //    Returns the id of the segment with `key`, creating one of `npages`
+    //    pages if there is none (or `key` is SHM_PRIVATE). Returns -EINVAL
+    //    if `npages` is 0 or more than SHM_MAX_PAGES or the existing segment
+    //    is smaller, and -ENOMEM if every segment is in use.
+
+    pub fn create(&mut self, key: u64, npages: usize) -> Result<usize, i32> {
+        if npages == 0 || npages > SHM_MAX_PAGES {
+            return Err(-EINVAL);
+        }
+        if key != SHM_PRIVATE {
+            if let Some(id) = self.segments.iter().position(|s| s.in_use && s.key == key) {
+                return if npages <= self.segments[id].npages { Ok(id) } else { Err(-EINVAL) };
+            }
+        }
+        let id = self.segments.iter().position(|s| !s.in_use).ok_or(-ENOMEM)?;
+        self.segments[id] = ShmSegment { key, npages, in_use: true, ..ShmSegment::new() };
+        Ok(id)
+    }
+
+    // get(id), get_mut(id)
+    //    Return segment `id`, if it exists.
+
+    pub fn get(&self, id: usize) -> Option<&ShmSegment> {
+        self.segments.get(id).filter(|s| s.in_use)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut ShmSegment> {
+        self.segments.get_mut(id).filter(|s| s.in_use)
+    }
+
+    // find_attached(pid, va)
+    //    Returns the id of the segment process `pid` attached at address
+    //    `va`, if any.
+
+    pub fn find_attached(&self, pid: usize, va: usize) -> Option<usize> {
+        (0..MAX_SHM_SEGMENTS).find(|&id| self.get(id).is_some_and(|s| s.attached_at[pid] == va && va != 0))
+    }
+
+    // overlaps_attached(pid, start, end)
+    //    Returns true iff `[start, end)` overlaps a segment process `pid`
+    //    has attached.
+
+    pub fn overlaps_attached(&self, pid: usize, start: usize, end: usize) -> bool {
+        self.segments.iter().any(|s| {
+            let addr = s.attached_at[pid];
+            s.in_use && addr != 0 && addr < end && start < addr + s.size()
+        })
+    }
+
+    // inherit(parent, child)
+    //    Attach every segment `parent` has attached to `child` at the same
+    //    address, as `fork` does. The caller maps the pages.
+
+    pub fn inherit(&mut self, parent: usize, child: usize) {
+        for segment in self.segments.iter_mut().filter(|s| s.in_use) {
+            segment.attached_at[child] = segment.attached_at[parent];
+        }
+    }
+
+    // release(id)
+    //    Forget segment `id`, whose pages must already be freed.
+
+    pub fn release(&mut self, id: usize) {
+        self.segments[id] = ShmSegment::new();
+    }
+}
+
+// Self tests (run with the `selftest` boot command)
+
+unsafe extern "C" {
+    fn virtual_memory_lookup(pagetable: *mut x86_64_pagetable, va: usize) -> VAMapping;
+}
+
+kernel_test! {
+    fn shared_segment_outlives_its_creator(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        test_assert!(kernel.process_setup(2, b"allocator2") == 0);
+        let page = PAGESIZE as usize;
+        let id = match kernel.shm_create(42, 2 * page - 1) {
+            Ok(id) => id,
+            Err(_) => return Err("shm_create failed"),
+        };
+        test_assert!(kernel.shm_create(42, page) == Ok(id));
+        test_assert!(kernel.shm_attach(1, id + 1, 0) == Err(-EINVAL));
+
+        let owned = kernel.pageinfo_table.pages_owned_by(1);
+        let (a1, a2) = match (kernel.shm_attach(1, id, 0), kernel.shm_attach(2, id, 0)) {
+            (Ok(a1), Ok(a2)) => (a1, a2),
+            _ => return Err("shm_attach failed"),
+        };
+        test_assert!(kernel.shm_attach(1, id, 0) == Err(-EINVAL));
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == owned + 2);
+        let pt1 = kernel.proc_table.get_process_by_pid(1).p_pagetable;
+        let pt2 = kernel.proc_table.get_process_by_pid(2).p_pagetable;
+        let (m1, m2) = unsafe { (virtual_memory_lookup(pt1, a1 + page), virtual_memory_lookup(pt2, a2 + page)) };
+        test_assert!(m1.pn >= 0 && m1.pa == m2.pa);
+        test_assert!(kernel.pageinfo_table.pageinfo[m1.pn as usize].refcount == 2);
+        test_assert!(kernel.munmap(1, a1, page) == -EINVAL);
+
+        // the pages pass to process 2 when process 1 exits
+        kernel.process_free(1);
+        test_assert!(kernel.pageinfo_table.pageinfo[m1.pn as usize].refcount == 1);
+        test_assert!(kernel.pageinfo_table.pageinfo[m1.pn as usize].owner == 2);
+        test_assert!(kernel.shm_detach(2, a2 + page) == -EINVAL);
+        test_assert!(kernel.shm_detach(2, a2) == 0);
+        test_assert!(kernel.pageinfo_table.pageinfo[m1.pn as usize].refcount == 0);
+        test_assert!(kernel.shm.get(id).is_none());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_finds_segments_by_key() {
+        let mut table = ShmTable::new();
+        assert_eq!(table.create(7, 2), Ok(0));
+        assert_eq!(table.create(7, 1), Ok(0));
+        assert_eq!(table.create(7, 3), Err(-EINVAL));
+        assert_eq!(table.create(8, 3), Ok(1));
+        // private segments are never shared by key
+        assert_eq!(table.create(SHM_PRIVATE, 1), Ok(2));
+        assert_eq!(table.create(SHM_PRIVATE, 1), Ok(3));
+        assert_eq!(table.get(1).map(|s| s.size()), Some(3 * PAGESIZE as usize));
+    }
+
+    #[test]
+    fn create_rejects_bad_sizes_and_runs_out() {
+        let mut table = ShmTable::new();
+        assert_eq!(table.create(1, 0), Err(-EINVAL));
+        assert_eq!(table.create(1, SHM_MAX_PAGES + 1), Err(-EINVAL));
+        for key in 0..MAX_SHM_SEGMENTS as u64 {
+            assert_eq!(table.create(key + 1, 1), Ok(key as usize));
+        }
+        assert_eq!(table.create(100, 1), Err(-ENOMEM));
+
+        table.release(3);
+        assert!(table.get(3).is_none());
+        assert_eq!(table.create(100, 1), Ok(3));
+    }
+
+    #[test]
+    fn attachments_are_found_by_process_and_address() {
+        let mut table = ShmTable::new();
+        let id = table.create(5, 2).unwrap();
+        let page = PAGESIZE as usize;
+        let segment = table.get_mut(id).unwrap();
+        segment.attached_at[1] = 0x200000;
+        segment.attached_at[3] = 0x280000;
+        assert_eq!(table.get(id).unwrap().attachers().collect::<std::vec::Vec<_>>(), [1, 3]);
+
+        assert_eq!(table.find_attached(1, 0x200000), Some(id));
+        assert_eq!(table.find_attached(3, 0x200000), None);
+        assert_eq!(table.find_attached(2, 0), None);
+        assert!(table.overlaps_attached(1, 0x200000 + page, 0x200000 + 2 * page));
+        assert!(!table.overlaps_attached(1, 0x200000 + 2 * page, 0x300000));
+        assert!(!table.overlaps_attached(2, 0, usize::MAX));
+    }
+}
@@ -0,0 +1,241 @@
+use bindings::bindings_x86_64::*;
+use bindings::bindings_kernel::*;
+
+use crate::kernel::{pte_entry, Kernel};
+use crate::selftest::{kernel_test, test_assert};
+
+// swap.rs
+//
+//    The swap area. When physical memory runs out the kernel evicts user
+//    pages to swap slots and frees them. An evicted page's page table entry
+//    is left not present, with PTE_SWAPPED set and the slot number where the
+//    physical address would be; the page fault handler reads the page back
+//    into a new physical page when the process touches it again.
+//
+//    The swap area is a RAM disk: SWAP_PAGES pages of physical memory set
+//    aside at boot (see `memory_init`) that the page allocator never hands
+//    out.
+
+// Number of pages set aside for swap.
+pub const SWAP_PAGES: usize = 256;
+
+// Available-to-software bit marking a not-present entry as swapped out.
+pub const PTE_SWAPPED: X86_64PageentryT = 0x200;
+
+const SLOT_WORDS: usize = SWAP_PAGES.div_ceil(64);
+
+pub struct SwapArea {
+    base: usize,                    // address of slot 0
+    nslots: usize,
+    used: [u64; SLOT_WORDS],        // bit set iff the slot holds a page
+    pub clock_hand: usize,          // next user page the clock looks at
+    pub swap_ins: usize,            // pages read back from swap
+    pub swap_outs: usize,           // pages written to swap
+}
+
+impl SwapArea {
+    // SwapArea::new()
+    //    Returns a swap area with no slots until `init`.
+
+    pub const fn new() -> Self {
+        SwapArea {
+            base: 0,
+            nslots: 0,
+            used: [0; SLOT_WORDS],
+            clock_hand: 0,
+            swap_ins: 0,
+            swap_outs: 0,
+        }
+    }
+
+    // init(base, nslots)
+    //    Use the `nslots` pages at address `base` (at most SWAP_PAGES) as
+    //    swap slots.
+
+    pub fn init(&mut self, base: usize, nslots: usize) {
+        *self = SwapArea::new();
+        self.base = base;
+        self.nslots = nslots.min(SWAP_PAGES);
+    }
+
+    pub fn nslots(&self) -> usize {
+        self.nslots
+    }
+
+    // free_slots()
+    //    Returns the number of slots holding no page.
+
+    pub fn free_slots(&self) -> usize {
+        let used: u32 = self.used.iter().map(|word| word.count_ones()).sum();
+        self.nslots - used as usize
+    }
+
+    // alloc_slot()
+    //    Returns the lowest free slot, now in use, or None if swap is full.
+
+    pub fn alloc_slot(&mut self) -> Option<usize> {
+        let slot = (0..self.nslots).find(|&slot| self.used[slot / 64] & (1 << (slot % 64)) == 0)?;
+        self.used[slot / 64] |= 1 << (slot % 64);
+        Some(slot)
+    }
+
+    // free_slot(slot)
+    //    Return `slot` to the free slots.
+
+    pub fn free_slot(&mut self, slot: usize) {
+        self.used[slot / 64] &= !(1 << (slot % 64));
+    }
+
+    // slot_address(slot)
+    //    Returns the address of the page-sized `slot`.
+
+    pub fn slot_address(&self, slot: usize) -> usize {
+        self.base + slot * PAGESIZE as usize
+    }
+}
+
+// swap_entry(slot, perm)
+//    Returns the page table entry for a page swapped out to `slot` that was
+//    mapped with permissions `perm` (only PTE_W, PTE_U and PTE_NX are
+//    kept).
+
+pub fn swap_entry(slot: usize, perm: X86_64PageentryT) -> PageTableEntry {
+    PageTableEntry::from_bits(((slot as X86_64PageentryT) << PAGEOFFBITS) | PTE_SWAPPED | (perm & (PTE_W | PTE_U | PTE_NX)))
+}
+
+// swap_slot(entry)
+//    Returns the slot of a page table entry made by `swap_entry`, or None if
+//    `entry` does not describe a swapped-out page.
+
+pub fn swap_slot(entry: PageTableEntry) -> Option<usize> {
+    if entry.is_present() || !entry.has(PTE_SWAPPED) {
+        return None;
+    }
+    Some(entry.addr().page_number().as_usize())
+}
+
+// Self tests (run with the `selftest` boot command)
+
+unsafe extern "C" {
+    fn virtual_memory_lookup(pagetable: *mut x86_64_pagetable, va: usize) -> VAMapping;
+}
+
+kernel_test! {
+    fn swapped_page_is_read_back_on_fault(kernel: &mut Kernel) {
+        test_assert!(kernel.swap.nslots() > 0);
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let va = p.p_heap_start;
+        let write = (PFERR_USER | PFERR_WRITE) as u64;
+        test_assert!(kernel.page_reserve(1, va) == 0 && kernel.handle_page_fault(1, va, write));
+        let pa = unsafe { virtual_memory_lookup(p.p_pagetable, va) }.pa;
+        unsafe { *(pa as *mut u64) = 0x5eed; }
+
+        // the clock evicts every page of the process within two sweeps
+        let (outs, ins) = (kernel.swap.swap_outs, kernel.swap.swap_ins);
+        while kernel.swapped_slot(p.p_pagetable, va).is_none() {
+            test_assert!(kernel.swap_out());
+        }
+        test_assert!(kernel.swap.swap_outs > outs);
+        test_assert!(unsafe { virtual_memory_lookup(p.p_pagetable, va) }.pn < 0);
+
+        test_assert!(kernel.handle_page_fault(1, va, PFERR_USER as u64));
+        test_assert!(kernel.swap.swap_ins == ins + 1);
+        let vam = unsafe { virtual_memory_lookup(p.p_pagetable, va) };
+        test_assert!(vam.perm == (PTE_P | PTE_W | PTE_U) as i32);
+        test_assert!(unsafe { *(vam.pa as *const u64) } == 0x5eed);
+    }
+}
+
+kernel_test! {
+    fn pinned_pages_are_not_swapped_out(kernel: &mut Kernel) {
+        test_assert!(kernel.swap.nslots() > 0);
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let pt = kernel.proc_table.get_process_by_pid(1).p_pagetable;
+        let user = PROC_START_ADDR as usize..MEMSIZE_VIRTUAL as usize;
+        let swapped = |kernel: &Kernel| user.clone().step_by(PAGESIZE as usize)
+            .filter(|&va| kernel.swapped_slot(pt, va).is_some()).count();
+
+        // fork pins the parent's pages while it copies them
+        kernel.set_pinned(1, true);
+        let pinned_out = kernel.swap_out();
+        let pinned_swapped = swapped(kernel);
+        kernel.set_pinned(1, false);
+        test_assert!(!pinned_out && pinned_swapped == 0);
+        test_assert!(kernel.swap_out());
+        test_assert!(swapped(kernel) == 1);
+    }
+}
+
+kernel_test! {
+    fn text_pages_are_swapped_out_last(kernel: &mut Kernel) {
+        test_assert!(kernel.swap.nslots() > 0);
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let text = p.p_registers.reg_rip as usize & !PAGE_OFF_MASK;
+        let flags = |va: usize| pte_entry(p.p_pagetable, va).map_or(0, |pte| unsafe { (*pte).flags() } & (PTE_P | PTE_W | PTE_U | PTE_NX));
+        let code = unsafe { *(virtual_memory_lookup(p.p_pagetable, text).pa as *const u64) };
+        test_assert!(kernel.swap_out());
+        test_assert!(flags(text) == PTE_P | PTE_U);
+
+        // with every writable page pinned the text page goes...
+        kernel.set_pinned(1, true);
+        kernel.set_page_pinned(1, text, false);
+        let out = kernel.swap_out();
+        kernel.set_pinned(1, false);
+        test_assert!(out && kernel.swapped_slot(p.p_pagetable, text).is_some());
+        // ...and comes back as it was when fetched from
+        test_assert!(kernel.handle_page_fault(1, text, (PFERR_USER | PFERR_FETCH) as u64));
+        test_assert!(flags(text) == PTE_P | PTE_U);
+        test_assert!(unsafe { *(virtual_memory_lookup(p.p_pagetable, text).pa as *const u64) } == code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_allocated_lowest_first() {
+        let mut swap = SwapArea::new();
+        assert_eq!(swap.alloc_slot(), None);
+
+        swap.init(0x400000, 3);
+        assert_eq!(swap.alloc_slot(), Some(0));
+        assert_eq!(swap.alloc_slot(), Some(1));
+        assert_eq!(swap.alloc_slot(), Some(2));
+        assert_eq!(swap.alloc_slot(), None);
+        assert_eq!(swap.free_slots(), 0);
+
+        swap.free_slot(1);
+        assert_eq!(swap.free_slots(), 1);
+        assert_eq!(swap.alloc_slot(), Some(1));
+        assert_eq!(swap.slot_address(2), 0x400000 + 2 * PAGESIZE as usize);
+    }
+
+    #[test]
+    fn init_caps_slots_at_swap_pages() {
+        let mut swap = SwapArea::new();
+        swap.init(0, SWAP_PAGES + 10);
+        assert_eq!(swap.nslots(), SWAP_PAGES);
+        assert_eq!(swap.free_slots(), SWAP_PAGES);
+        for slot in 0..SWAP_PAGES {
+            assert_eq!(swap.alloc_slot(), Some(slot));
+        }
+        assert_eq!(swap.alloc_slot(), None);
+    }
+
+    #[test]
+    fn swap_entries_round_trip() {
+        let entry = swap_entry(77, PTE_W | PTE_U | PTE_A);
+        assert!(!entry.is_present());
+        assert_eq!(entry.flags() & (PTE_W | PTE_U | PTE_A), PTE_W | PTE_U);
+        assert_eq!(swap_slot(entry), Some(77));
+        let entry = swap_entry(3, PTE_U | PTE_NX);
+        assert_eq!(entry.flags() & (PTE_W | PTE_U | PTE_NX), PTE_U | PTE_NX);
+        assert_eq!(swap_slot(entry), Some(3));
+
+        assert_eq!(swap_slot(PageTableEntry::EMPTY), None);
+        assert_eq!(swap_slot(PageTableEntry::from_bits(0x5000 | PTE_P | PTE_SWAPPED)), None);
+    }
+}
@@ -0,0 +1,342 @@
+use bindings::bindings_x86_64::*;
+use bindings::bindings_kernel::*;
+
+use crate::kernel::{pte_entry, Kernel, MMAP_STACK_GAP};
+use crate::ph_page_info::PF_COW;
+use crate::selftest::{kernel_test, test_assert};
+
+// vma.rs
+//
+//    Virtual memory areas. A VMA is a page-aligned range of a process's
+//    virtual address space that the process has reserved (with
+//    `sys_page_alloc` or `sys_brk`) together with the permissions its pages
+//    get. Pages in a VMA are not backed by physical memory until the
+//    process first touches them; the page fault handler then allocates a
+//    zeroed page and maps it. A fault outside every VMA is a real fault.
+
+// Maximum number of areas a process can have. Adjacent areas with the same
+// permissions are merged, so a heap grown page by page uses one.
+pub const MAX_VMAS: usize = 16;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Vma {
+    pub start: usize,   // first address
+    pub end: usize,     // first address past the area
+    pub perm: u64,      // PTE_* permissions of its pages
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct VmaList {
+    areas: [Vma; MAX_VMAS], // sorted by address, never overlapping
+    len: usize,
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        VmaList {
+            areas: [Vma { start: 0, end: 0, perm: 0 }; MAX_VMAS],
+            len: 0,
+        }
+    }
+
+    // areas()
+    //    Returns the areas in address order.
+
+    pub fn areas(&self) -> &[Vma] {
+        &self.areas[..self.len]
+    }
+
+    // find(va)
+    //    Returns the area containing virtual address `va`, if any.
+
+    pub fn find(&self, va: usize) -> Option<Vma> {
+        self.areas().iter().find(|a| a.start <= va && va < a.end).copied()
+    }
+
+    // clear()
+    //    Forget every area.
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // insert(start, end, perm)
+    //    Add the area `[start, end)` with permissions `perm`, replacing any
+    //    part of other areas it overlaps and merging it with adjacent areas
+    //    that have the same permissions. Returns -ENOMEM, leaving the list
+    //    unchanged, if that needs more than MAX_VMAS areas.
+
+    pub fn insert(&mut self, start: usize, end: usize, perm: u64) -> Result<(), i32> {
+        let mut rest = *self;
+        rest.remove(start, end)?;
+
+        let new = Vma { start, end, perm };
+        let mut out = VmaList::new();
+        let mut placed = false;
+        for &area in rest.areas() {
+            if !placed && area.start >= end {
+                out.push(new)?;
+                placed = true;
+            }
+            out.push(area)?;
+        }
+        if !placed {
+            out.push(new)?;
+        }
+        *self = out;
+        Ok(())
+    }
+
+    // remove(start, end)
+    //    Remove `[start, end)` from the areas, splitting an area that
+    //    contains it. Returns -ENOMEM, leaving the list unchanged, if that
+    //    needs more than MAX_VMAS areas.
+
+    pub fn remove(&mut self, start: usize, end: usize) -> Result<(), i32> {
+        let mut out = VmaList::new();
+        for area in self.areas() {
+            for (s, e) in [(area.start, area.end.min(start)), (area.start.max(end), area.end)] {
+                if s < e {
+                    out.push(Vma { start: s, end: e, perm: area.perm })?;
+                }
+            }
+        }
+        *self = out;
+        Ok(())
+    }
+
+    // push(area)
+    //    Append `area`, which must not start before the last area ends,
+    //    merging it into the last area if they touch and agree.
+
+    fn push(&mut self, area: Vma) -> Result<(), i32> {
+        if let Some(last) = self.areas[..self.len].last_mut() {
+            if last.end == area.start && last.perm == area.perm {
+                last.end = area.end;
+                return Ok(());
+            }
+        }
+        if self.len == MAX_VMAS {
+            return Err(-ENOMEM);
+        }
+        self.areas[self.len] = area;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+// Self tests (run with the `selftest` boot command)
+
+unsafe extern "C" {
+    fn virtual_memory_lookup(pagetable: *mut x86_64_pagetable, va: usize) -> VAMapping;
+}
+
+kernel_test! {
+    fn page_alloc_backs_page_on_first_touch(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let va = p.p_heap_start;
+        let owned = kernel.pageinfo_table.pages_owned_by(1);
+
+        test_assert!(kernel.page_reserve(1, va + 1) == -1);
+        test_assert!(kernel.page_reserve(1, p.p_registers.reg_rip as usize & !PAGE_OFF_MASK) == -1);
+        test_assert!(kernel.page_reserve(1, va) == 0);
+        test_assert!(unsafe { virtual_memory_lookup(p.p_pagetable, va) }.pn < 0);
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == owned);
+
+        let write = (PFERR_USER | PFERR_WRITE) as u64;
+        test_assert!(kernel.handle_page_fault(1, va + 8, write));
+        test_assert!(!kernel.handle_page_fault(1, va + PAGESIZE as usize, write));
+        let vam = unsafe { virtual_memory_lookup(p.p_pagetable, va) };
+        test_assert!(vam.pn >= 0 && vam.perm == (PTE_P | PTE_W | PTE_U) as i32);
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == owned + 1);
+        test_assert!(unsafe { *(vam.pa as *const u64) } == 0);
+    }
+}
+
+kernel_test! {
+    fn stack_grows_down_to_its_limit(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let top = MEMSIZE_VIRTUAL as usize;
+        let page = PAGESIZE as usize;
+        test_assert!(p.p_registers.reg_rsp == MEMSIZE_VIRTUAL && p.p_stack_bottom == top - page);
+        let vam = unsafe { virtual_memory_lookup(p.p_pagetable, top - page) };
+        test_assert!(vam.pn >= 0 && vam.pa != top - page);
+
+        // a touch of the guard page grows the stack by a page
+        kernel.proc_table.get_process_by_pid_mut(1).p_rlimits.stack_bytes = 2 * page;
+        test_assert!(kernel.in_stack_guard(1, top - page - 8));
+        test_assert!(kernel.page_reserve(1, top - 2 * page) == -1);
+        test_assert!(kernel.grow_stack(1) == 0);
+        test_assert!(kernel.handle_page_fault(1, top - page - 8, (PFERR_USER | PFERR_WRITE) as u64));
+        test_assert!(kernel.proc_table.get_process_by_pid(1).p_stack_bottom == top - 2 * page);
+        test_assert!(kernel.in_stack_guard(1, top - 2 * page - 8));
+        // but not past RLIMIT_STACK
+        test_assert!(kernel.grow_stack(1) == -ENOMEM);
+    }
+}
+
+kernel_test! {
+    fn mmap_places_areas_below_the_stack(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let page = PAGESIZE as usize;
+        let anonymous = MAP_PRIVATE | MAP_ANONYMOUS;
+        test_assert!(kernel.mmap(1, 0, 0, PROT_READ, anonymous) == Err(-EINVAL));
+        test_assert!(kernel.mmap(1, 0, page, PROT_READ, MAP_PRIVATE) == Err(-EINVAL));
+
+        let addr = match kernel.mmap(1, 0, 2 * page + 1, PROT_READ | PROT_WRITE, anonymous) {
+            Ok(addr) => addr,
+            Err(_) => return Err("mmap failed"),
+        };
+        test_assert!(addr + 3 * page <= MEMSIZE_VIRTUAL as usize - MMAP_STACK_GAP);
+        test_assert!(addr >= p.p_brk);
+        // a free hint is honoured, a taken one is not
+        test_assert!(kernel.mmap(1, p.p_heap_start, page, PROT_READ, anonymous) == Ok(p.p_heap_start));
+        test_assert!(kernel.mmap(1, addr, page, PROT_READ, anonymous).is_ok_and(|other| other != addr));
+
+        let write = (PFERR_USER | PFERR_WRITE) as u64;
+        let owned = kernel.pageinfo_table.pages_owned_by(1);
+        test_assert!(kernel.handle_page_fault(1, addr + page, write));
+        test_assert!(!kernel.handle_page_fault(1, p.p_heap_start, write));
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == owned + 1);
+
+        // unmapping the middle page splits the area and frees the page
+        test_assert!(kernel.munmap(1, addr + 1, page) == -EINVAL);
+        test_assert!(kernel.munmap(1, addr + page, page) == 0);
+        test_assert!(kernel.pageinfo_table.pages_owned_by(1) == owned);
+        test_assert!(kernel.vmas[1].find(addr).is_some() && kernel.vmas[1].find(addr + page).is_none());
+        test_assert!(unsafe { virtual_memory_lookup(p.p_pagetable, addr + page) }.pn < 0);
+    }
+}
+
+kernel_test! {
+    fn mprotect_makes_pages_read_only(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        let p = *kernel.proc_table.get_process_by_pid(1);
+        let page = PAGESIZE as usize;
+        let addr = match kernel.mmap(1, 0, 2 * page, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS) {
+            Ok(addr) => addr,
+            Err(_) => return Err("mmap failed"),
+        };
+        let (read, write) = (PFERR_USER as u64, (PFERR_USER | PFERR_WRITE) as u64);
+        test_assert!(kernel.handle_page_fault(1, addr, write));
+
+        test_assert!(kernel.mprotect(1, addr, 2 * page, 4) == -EINVAL);
+        test_assert!(kernel.mprotect(1, addr + 1, page, PROT_READ) == -EINVAL);
+        // the guard page below the stack is not the process's
+        test_assert!(kernel.mprotect(1, p.p_stack_bottom - page, page, PROT_READ) == -EPERM);
+        test_assert!(kernel.mprotect(1, addr, 2 * page, PROT_READ) == 0);
+        test_assert!(unsafe { virtual_memory_lookup(p.p_pagetable, addr) }.perm == (PTE_P | PTE_U) as i32);
+        // the unbacked page is read-only too
+        test_assert!(!kernel.handle_page_fault(1, addr + page, write));
+        test_assert!(kernel.handle_page_fault(1, addr + page, read));
+        test_assert!(unsafe { virtual_memory_lookup(p.p_pagetable, addr + page) }.perm == (PTE_P | PTE_U) as i32);
+
+        test_assert!(kernel.mprotect(1, addr, page, PROT_NONE) == 0);
+        test_assert!(unsafe { virtual_memory_lookup(p.p_pagetable, addr) }.perm & PTE_U as i32 == 0);
+        test_assert!(kernel.mprotect(1, addr, page, PROT_READ | PROT_WRITE) == 0);
+        test_assert!(unsafe { virtual_memory_lookup(p.p_pagetable, addr) }.perm == (PTE_P | PTE_W | PTE_U) as i32);
+    }
+}
+
+kernel_test! {
+    fn mprotect_makes_pages_shared_by_fork_copy_on_write(kernel: &mut Kernel) {
+        test_assert!(kernel.process_setup(1, b"allocator") == 0);
+        kernel.proc_table.current = Some(kernel.proc_table.get_process_by_pid_mut(1) as *mut Proc);
+        let child = kernel.fork();
+        kernel.proc_table.current = None;
+        let Ok(child) = child else {
+            return Err("fork failed");
+        };
+        let (pt, child_pt) = (kernel.proc_table.get_process_by_pid(1).p_pagetable,
+                              kernel.proc_table.get_process_by_pid(child).p_pagetable);
+        let text = kernel.proc_table.get_process_by_pid(1).p_registers.reg_rip as usize & !PAGE_OFF_MASK;
+        let entry = |pt: *mut x86_64_pagetable| pte_entry(pt, text).map_or(PageTableEntry::EMPTY, |pte| unsafe { *pte });
+        let flags = |kernel: &Kernel, pa: PhysAddr| kernel.pageinfo_table.pageinfo[pa.page_number().as_usize()].flags;
+        let shared = entry(pt).addr();
+        let rwx = PROT_READ | PROT_WRITE | PROT_EXEC;
+
+        // fork shares the read-only text page, so making it writable
+        // makes it copy-on-write instead
+        test_assert!(kernel.mprotect(1, text, PAGESIZE as usize, rwx) == 0);
+        test_assert!(entry(pt).has(PTE_P | PTE_U | PTE_COW) && !entry(pt).has(PTE_W));
+        test_assert!(flags(kernel, shared) & PF_COW != 0);
+
+        // a write gives the parent its own copy and leaves the child the page
+        test_assert!(kernel.handle_page_fault(1, text, (PFERR_USER | PFERR_WRITE | PFERR_PRESENT) as u64));
+        test_assert!(entry(pt).addr() != shared && entry(pt).has(PTE_W) && !entry(pt).has(PTE_COW));
+        test_assert!(kernel.pageinfo_table.pageinfo[shared.page_number().as_usize()].owner == child as PidT);
+
+        // now nothing else maps the child's page: it becomes plainly writable
+        test_assert!(kernel.mprotect(child, text, PAGESIZE as usize, rwx) == 0);
+        test_assert!(entry(child_pt).addr() == shared && entry(child_pt).has(PTE_W) && !entry(child_pt).has(PTE_COW));
+        test_assert!(flags(kernel, shared) & PF_COW == 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const RW: u64 = PTE_P | PTE_W | PTE_U;
+    const RO: u64 = PTE_P | PTE_U;
+
+    fn ranges(list: &VmaList) -> Vec<(usize, usize, u64)> {
+        list.areas().iter().map(|a| (a.start, a.end, a.perm)).collect()
+    }
+
+    #[test]
+    fn insert_keeps_areas_sorted_and_merges_neighbours() {
+        let mut list = VmaList::new();
+        assert_eq!(list.insert(0x3000, 0x4000, RW), Ok(()));
+        assert_eq!(list.insert(0x1000, 0x2000, RW), Ok(()));
+        assert_eq!(ranges(&list), [(0x1000, 0x2000, RW), (0x3000, 0x4000, RW)]);
+
+        assert_eq!(list.insert(0x2000, 0x3000, RW), Ok(()));
+        assert_eq!(ranges(&list), [(0x1000, 0x4000, RW)]);
+
+        assert_eq!(list.insert(0x4000, 0x5000, RO), Ok(()));
+        assert_eq!(ranges(&list), [(0x1000, 0x4000, RW), (0x4000, 0x5000, RO)]);
+    }
+
+    #[test]
+    fn insert_replaces_overlapped_parts() {
+        let mut list = VmaList::new();
+        assert_eq!(list.insert(0x1000, 0x5000, RW), Ok(()));
+        assert_eq!(list.insert(0x2000, 0x3000, RO), Ok(()));
+        assert_eq!(ranges(&list),
+            [(0x1000, 0x2000, RW), (0x2000, 0x3000, RO), (0x3000, 0x5000, RW)]);
+        assert_eq!(list.find(0x2fff).map(|a| a.perm), Some(RO));
+        assert_eq!(list.find(0x5000), None);
+    }
+
+    #[test]
+    fn remove_splits_areas() {
+        let mut list = VmaList::new();
+        assert_eq!(list.insert(0x1000, 0x5000, RW), Ok(()));
+        assert_eq!(list.remove(0x2000, 0x3000), Ok(()));
+        assert_eq!(ranges(&list), [(0x1000, 0x2000, RW), (0x3000, 0x5000, RW)]);
+        assert_eq!(list.remove(0, 0x4000), Ok(()));
+        assert_eq!(ranges(&list), [(0x4000, 0x5000, RW)]);
+        list.clear();
+        assert!(list.areas().is_empty());
+    }
+
+    #[test]
+    fn full_list_is_left_unchanged() {
+        let mut list = VmaList::new();
+        for i in 0..MAX_VMAS {
+            assert_eq!(list.insert(0x2000 * i, 0x2000 * i + 0x1000, RW), Ok(()));
+        }
+        let before = ranges(&list);
+        assert_eq!(list.insert(0x100000, 0x101000, RW), Err(-ENOMEM));
+        assert_eq!(list.remove(0x2000 * 3 + 0x400, 0x2000 * 3 + 0x800), Err(-ENOMEM));
+        assert_eq!(ranges(&list), before);
+        // filling a gap merges two areas into one
+        assert_eq!(list.insert(0x1000, 0x2000, RW), Ok(()));
+        assert_eq!(list.areas().len(), MAX_VMAS - 1);
+    }
+}
@@ -0,0 +1,50 @@
+// build.rs
+//
+//    Generate the program registry, `PROGRAMS`, from the user programs the
+//    GNUmakefile links into the kernel image: every `p-NAME.c` in uspace/
+//    and tests/, plus every `p-NAME.ld` in link/ (programs built from
+//    p-allocator.c with their own load address). `ld -b binary` names the
+//    contents of obj/p-NAME `_binary_obj_p_NAME_start` to `..._end`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("../..");
+    let mut names = BTreeSet::new();
+    for (dir, extension) in [("uspace", "c"), ("tests", "c"), ("link", "ld")] {
+        let dir = root.join(dir);
+        println!("cargo:rerun-if-changed={}", dir.display());
+        for entry in fs::read_dir(&dir).unwrap_or_else(|e| panic!("{}: {e}", dir.display())) {
+            let path = entry.unwrap().path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix("p-"))
+                .and_then(|n| n.strip_suffix(&format!(".{extension}"))) else {
+                continue;
+            };
+            if !names.insert(name.to_string()) {
+                panic!("two user programs are named {name}");
+            }
+        }
+    }
+
+    let symbol = |name: &str, which: &str| {
+        let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        format!("_binary_obj_p_{name}_{which}")
+    };
+    let mut out = String::from("// Generated by kloader/build.rs.\n\nextern \"C\" {\n");
+    for name in &names {
+        out += &format!("    static {}: u8;\n    static {}: u8;\n", symbol(name, "start"), symbol(name, "end"));
+    }
+    out += &format!("}}\n\npub static PROGRAMS: [Program; {}] = [\n", names.len());
+    for name in &names {
+        out += &format!(
+            "    Program {{ name: \"{name}\", image: RamImage {{ begin: unsafe {{ &{} }}, end: unsafe {{ &{} }} }} }},\n",
+            symbol(name, "start"), symbol(name, "end"));
+    }
+    out += "];\n";
+
+    let path = Path::new(&std::env::var("OUT_DIR").unwrap()).join("programs.rs");
+    fs::write(path, out).unwrap();
+}
@@ -1,7 +1,8 @@
 use crate::*;
 use core::ffi::c_void;
 use core::ffi::c_int;
-use stdlib::my_assert;
+use core::mem::size_of;
+use bindings::bindings_kernel::{MEMSIZE_VIRTUAL, PROC_START_ADDR};
 
 // k-loader.c
 //
@@ -11,26 +12,11 @@ extern "C" {
     pub fn c_panic(format: *const core::ffi::c_char, ...) -> !;
     pub fn assign_physical_page(addr: usize, owner: usize) -> i32;
     pub fn set_pagetable(pagetable: *mut x86_64_pagetable);
-    pub fn virtual_memory_map(pagetable: *mut x86_64_pagetable, vaddr: usize, paddr: usize, size: usize, flags: u32) -> core::ffi::c_int;
-    pub fn memcpy(dst: *mut core::ffi::c_void, src: *const core::ffi::c_void, n: usize) -> *mut ::std::os::raw::c_void;
+    pub fn virtual_memory_map(pagetable: *mut x86_64_pagetable, vaddr: usize, paddr: usize, size: usize, flags: X86_64PageentryT) -> core::ffi::c_int;
+    pub fn memcpy(dst: *mut core::ffi::c_void, src: *const core::ffi::c_void, n: usize) -> *mut core::ffi::c_void;
     pub fn memset(s: *mut core::ffi::c_void, c: core::ffi::c_int, n: core::ffi::c_ulong) -> *mut core::ffi::c_void;
     
     pub static mut kernel_pagetable: *mut x86_64_pagetable;
-
-    pub static _binary_obj_p_allocator_start: u8;
-    pub static _binary_obj_p_allocator_end: u8;
-    pub static _binary_obj_p_allocator2_start: u8;
-    pub static _binary_obj_p_allocator2_end: u8;
-    pub static _binary_obj_p_allocator3_start: u8;
-    pub static _binary_obj_p_allocator3_end: u8;
-    pub static _binary_obj_p_allocator4_start: u8;
-    pub static _binary_obj_p_allocator4_end: u8;
-    pub static _binary_obj_p_fork_start: u8;
-    pub static _binary_obj_p_fork_end: u8;
-    pub static _binary_obj_p_forkexit_start: u8;
-    pub static _binary_obj_p_forkexit_end: u8;
-    pub static _binary_obj_p_test_start: u8;
-    pub static _binary_obj_p_test_end: u8;
 }
 
 #[repr(C)]
@@ -39,111 +25,475 @@ pub struct RamImage {
     end: &'static u8,
 }
 
-pub static RAMIMAGES: [RamImage; 7] = [
-    RamImage { begin: unsafe { &_binary_obj_p_allocator_start }, end: unsafe { &_binary_obj_p_allocator_end } },
-    RamImage { begin: unsafe { &_binary_obj_p_allocator2_start }, end: unsafe { &_binary_obj_p_allocator2_end } },
-    RamImage { begin: unsafe { &_binary_obj_p_allocator3_start }, end: unsafe { &_binary_obj_p_allocator3_end } },
-    RamImage { begin: unsafe { &_binary_obj_p_allocator4_start }, end: unsafe { &_binary_obj_p_allocator4_end } },
-    RamImage { begin: unsafe { &_binary_obj_p_fork_start }, end: unsafe { &_binary_obj_p_fork_end } },
-    RamImage { begin: unsafe { &_binary_obj_p_forkexit_start }, end: unsafe { &_binary_obj_p_forkexit_end } },
-    RamImage { begin: unsafe { &_binary_obj_p_test_start }, end: unsafe { &_binary_obj_p_test_end } },
-];
+impl RamImage {
+    // bytes()
+    //    Returns the contents of the image, `[begin, end)`.
+
+    pub fn bytes(&self) -> &'static [u8] {
+        let begin = self.begin as *const u8;
+        let len = self.end as *const u8 as usize - begin as usize;
+        unsafe { core::slice::from_raw_parts(begin, len) }
+    }
+}
+
+// A user program linked into the kernel image
+pub struct Program {
+    pub name: &'static str,
+    pub image: RamImage,
+}
+
+// The user programs, by name (generated by build.rs)
+include!(concat!(env!("OUT_DIR"), "/programs.rs"));
+
+// program_lookup(name, len)
+//    Returns the number of the program called `[name, name + len)`, for
+//    `program_load`, or -1 if there is none. Program names are the names of
+//    their sources without `p-`, such as "allocator" or "fork".
+
+/// # Safety
+///
+/// Unless it is null, `name` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn program_lookup(name: *const u8, len: usize) -> c_int {
+    if name.is_null() {
+        return -1;
+    }
+    let name = core::slice::from_raw_parts(name, len);
+    find_program(&PROGRAMS, name).map_or(-1, |n| n as c_int)
+}
+
+// find_program(programs, name)
+//    Returns the index of the program called `name` in `programs`.
+
+fn find_program(programs: &[Program], name: &[u8]) -> Option<usize> {
+    programs.iter().position(|program| program.name.as_bytes() == name)
+}
+
+// Why a program cannot be loaded
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LoadError {
+    NoSuchProgram,          // no program has that number
+    BadHeader,              // not a 64-bit little-endian x86-64 executable
+    BadProgramHeaders,      // the program headers lie outside the image
+    SegmentOutsideImage,    // a segment's contents lie outside the image
+    SegmentTooLarge,        // a segment has more file bytes than memory bytes
+    SegmentOutsideUserSpace, // a segment lies outside process memory
+    OverlappingSegments,    // two segments load to the same addresses
+    ConflictingPermissions, // segments sharing a page disagree on its permissions
+    OutOfMemory,            // a segment's pages could not be mapped
+}
+
+impl LoadError {
+    // errno()
+    //    Returns the negated error number `program_load` returns for the
+    //    error.
+
+    pub fn errno(self) -> i32 {
+        match self {
+            LoadError::NoSuchProgram => -EINVAL,
+            LoadError::OutOfMemory => -ENOMEM,
+            _ => -ENOEXEC,
+        }
+    }
+}
 
 
 // program_load(p, programnumber)
-//    Load the code corresponding to program `programnumber` into the process
-//    `p` and set `p->p_registers.reg_rip` to its entry point. Calls
-//    `assign_physical_page` to as required. Returns 0 on success and
-//    -1 on failure (e.g. out-of-memory). `allocator` is passed to
+//    Load the code corresponding to program `programnumber` (see
+//    `program_lookup`) into the process
+//    `p`, set `p->p_registers.reg_rip` to its entry point, and start its
+//    heap (`p->p_heap_start` and `p->p_brk`) after its last segment. Calls
+//    `assign_physical_page` to as required. Segments that are not writable
+//    (`ELF_PFLAG_WRITE`) are made read-only once every segment is copied.
+//    Returns 0 on success and a negated error number on failure: -EINVAL
+//    for an unknown program, -ENOEXEC if `check_elf` rejects its image, and
+//    -ENOMEM if out of memory. `allocator` is passed to
 //    `virtual_memory_map`.
 
+/// # Safety
+///
+/// `p` must point to a process whose `p_pagetable` is a valid page table,
+/// and whose program pages are free for `assign_physical_page`.
 #[no_mangle]
 pub unsafe extern "C" fn program_load(
     p: *mut Proc, 
     programnumber: usize, 
-    allocator: extern fn() -> *mut c_void
+    allocator: extern "C" fn() -> *mut c_void
 ) -> i32 {
+    match load_program(p, programnumber, allocator) {
+        Ok(()) => 0, // Success (Required by C-kernel)
+        Err(err) => err.errno(),
+    }
+}
+
+// load_program(p, programnumber, allocator)
+//    Does the work of `program_load`, returning why it failed.
+
+unsafe fn load_program(
+    p: *mut Proc,
+    programnumber: usize,
+    allocator: extern "C" fn() -> *mut c_void
+) -> Result<(), LoadError> {
     // is this a valid program?
-    let n_programs = RAMIMAGES.len();
-    my_assert!(programnumber < n_programs);
-    let ram_image = &RAMIMAGES[programnumber];
-    let eh_ptr = ram_image.begin as *const u8;
-    let eh: &ElfHeader = unsafe { 
-        &*(eh_ptr as *const ElfHeader) 
-    };
-    my_assert!(eh.e_magic == ELF_MAGIC);
+    let image = PROGRAMS.get(programnumber).ok_or(LoadError::NoSuchProgram)?.image.bytes();
+    let (eh, phs) = check_elf(image)?;
 
     // load each loadable program segment into memory
-    let ph: &[ElfProgram] = unsafe {
-        let program_header_ptr = (eh as *const ElfHeader as *const u8).add(eh.e_phoff as usize);
-        let program_array = program_header_ptr as *const ElfProgram;
-        core::slice::from_raw_parts(program_array, eh.e_phnum as usize)
-    };
-    
-    for i in 0..eh.e_phnum as usize {
-        if ph[i].p_type == ELF_PTYPE_LOAD {
-            let pdata = unsafe {
-                (eh as *const ElfHeader as *const u8).offset(ph[i].p_offset as isize)
-            };
+    let loads = || phs.iter().filter(|ph| ph.p_type == ELF_PTYPE_LOAD);
+    let mut heap_start = VirtAddr::default();
+    for (i, ph) in phs.iter().enumerate().filter(|(_, ph)| ph.p_type == ELF_PTYPE_LOAD) {
+        let (_, end) = segment_pages(ph).ok_or(LoadError::SegmentOutsideUserSpace)?;
+        heap_start = heap_start.max(end);
+        let pdata = image.as_ptr().add(ph.p_offset as usize);
+        if program_load_segment(p, ph, &phs[..i], pdata, allocator) < 0 {
+            return Err(LoadError::OutOfMemory);
+        }
+    }
 
-            if program_load_segment(p, &ph[i], pdata, allocator) < 0 {
-                return -1;
-            }
+    // the contents are in place: now map read-only segments read-only
+    // (each page is at the physical address equal to its virtual address)
+    for ph in loads().filter(|ph| ph.p_flags & ELF_PFLAG_WRITE == 0) {
+        let (start, end) = segment_pages(ph).ok_or(LoadError::SegmentOutsideUserSpace)?;
+        let (va, size) = (start.as_usize(), end.as_usize() - start.as_usize());
+        if virtual_memory_map((*p).p_pagetable, va, va, size, segment_perm(ph)) < 0 {
+            return Err(LoadError::OutOfMemory);
         }
     }
 
     // set the entry point from the ELF header
     (*p).p_registers.reg_rip = eh.e_entry;
-    0 // Success (Required by C-kernel)
+    // the heap starts on the page after the last segment
+    (*p).p_heap_start = heap_start.as_usize();
+    (*p).p_brk = (*p).p_heap_start;
+    Ok(())
+}
+
+// check_elf(image)
+//    Returns the ELF header and program headers of the executable in
+//    `image` after checking that `program_load` can load it safely: it is
+//    a 64-bit little-endian x86-64 executable, its program headers and the
+//    contents of its loadable segments lie within `image`, and those
+//    segments lie in process memory (`[PROC_START_ADDR, MEMSIZE_VIRTUAL)`),
+//    do not overlap, and agree on the permissions of any page they share.
+
+pub fn check_elf(image: &[u8]) -> Result<(&ElfHeader, &[ElfProgram]), LoadError> {
+    if image.len() < size_of::<ElfHeader>() {
+        return Err(LoadError::BadHeader);
+    }
+    // ElfHeader and ElfProgram are packed, so any address is aligned
+    let eh = unsafe { &*(image.as_ptr() as *const ElfHeader) };
+    if eh.e_magic != ELF_MAGIC
+        || eh.e_elf[0] != ELF_CLASS_64
+        || eh.e_elf[1] != ELF_DATA_LSB
+        || eh.e_type != ELF_TYPE_EXEC
+        || eh.e_machine != ELF_MACHINE_X86_64 {
+        return Err(LoadError::BadHeader);
+    }
+
+    let phnum = eh.e_phnum as usize;
+    if eh.e_phentsize as usize != size_of::<ElfProgram>()
+        || !within(image, eh.e_phoff, (phnum * size_of::<ElfProgram>()) as u64) {
+        return Err(LoadError::BadProgramHeaders);
+    }
+    let ph = unsafe {
+        let ph_ptr = image.as_ptr().add(eh.e_phoff as usize) as *const ElfProgram;
+        core::slice::from_raw_parts(ph_ptr, phnum)
+    };
+
+    let loads = || ph.iter().filter(|ph| ph.p_type == ELF_PTYPE_LOAD);
+    for ph in loads() {
+        if !within(image, ph.p_offset, ph.p_filesz) {
+            return Err(LoadError::SegmentOutsideImage);
+        }
+        if ph.p_filesz > ph.p_memsz {
+            return Err(LoadError::SegmentTooLarge);
+        }
+        match segment_range(ph) {
+            Some((start, end)) if start.as_usize() >= PROC_START_ADDR as usize
+                && end.as_usize() <= MEMSIZE_VIRTUAL as usize => {}
+            _ => return Err(LoadError::SegmentOutsideUserSpace),
+        }
+    }
+    // every segment has a range now
+    let range = |ph| segment_range(ph).unwrap_or_default();
+    for (i, a) in loads().enumerate() {
+        for b in loads().skip(i + 1) {
+            let ((a_start, a_end), (b_start, b_end)) = (range(a), range(b));
+            if a_start < b_end && b_start < a_end {
+                return Err(LoadError::OverlappingSegments);
+            }
+            if share_page(a, b) && segment_perm(a) != segment_perm(b) {
+                return Err(LoadError::ConflictingPermissions);
+            }
+        }
+    }
+    Ok((eh, ph))
 }
 
-// program_load_segment(p, ph, src, allocator)
+// within(image, offset, size)
+//    Returns true iff `[offset, offset + size)` lies within `image`.
+
+fn within(image: &[u8], offset: u64, size: u64) -> bool {
+    offset.checked_add(size).is_some_and(|end| end <= image.len() as u64)
+}
+
+// program_load_segment(p, ph, loaded, src, allocator)
 //    Load an ELF segment at virtual address `ph->p_va` in process `p`. Copies
 //    `[src, src + ph->p_filesz)` to `dst`, then clears
 //    `[ph->p_va + ph->p_filesz, ph->p_va + ph->p_memsz)` to 0.
 //    Calls `assign_physical_page` to allocate pages and `virtual_memory_map`
-//    to map them in `p->p_pagetable`. Returns 0 on success and -1 on failure.
+//    to map them in `p->p_pagetable`, writable so they can be filled in and
+//    no-execute unless the segment is executable (`ELF_PFLAG_EXEC`). A page
+//    shared with a loadable segment in `loaded`, the program headers before
+//    `ph`, is already in place and is reused. Returns 0 on success and -1 on
+//    failure (e.g. out-of-memory).
 
-#[no_mangle]
-pub unsafe extern "C" fn program_load_segment(
+unsafe fn program_load_segment(
     p: *mut Proc,
-    ph: *const ElfProgram,
+    ph: &ElfProgram,
+    loaded: &[ElfProgram],
     src: *const u8,
     _allocator: extern "C" fn() -> *mut c_void,
 ) -> c_int {
-    if p.is_null() || ph.is_null() {
+    if p.is_null() {
         return -1; // Validate pointers
     }
 
-    let mut va = (*ph).p_va;
-    let _end_file = va + (*ph).p_filesz;
-    let end_mem = va + (*ph).p_memsz;
-    va &= !(PAGESIZE - 1);       // round to page boundary
-
-    // allocate memory
-    unsafe {
-        while va < end_mem {
-            if assign_physical_page(va as usize, (*p).p_pid as usize) < 0
-                || virtual_memory_map((*p).p_pagetable, va as usize, va as usize, PAGESIZE as usize, (PTE_P | PTE_W | PTE_U) as u32) < 0
-            {
-                c_panic(
-                    "(program_load_segment) can't assign address!".as_ptr() as *const core::ffi::c_char
-                );
+    // the segment must lie in the address space
+    let (Some((start, _)), Some((first, end))) = (segment_range(ph), segment_pages(ph)) else {
+        return -1;
+    };
+    let perm = segment_perm(ph) | PTE_W;
+
+    // allocate memory; on failure the pages assigned so far stay with the
+    // process, and the caller releases them when it frees the process
+    let mut va = first;
+    while va < end {
+        // programs are loaded at the physical address equal to their
+        // virtual address
+        let Some(pa) = PhysAddr::new(va.as_usize()) else {
+            return -1;
+        };
+        // a page shared with an earlier segment is already there
+        if !page_loaded(loaded, va) {
+            if assign_physical_page(pa.as_usize(), (*p).p_pid as usize) < 0 {
+                return -1;
+            }
+            if virtual_memory_map((*p).p_pagetable, va.as_usize(), pa.as_usize(), PAGESIZE as usize, perm) < 0 {
+                return -1;
             }
-            va += PAGESIZE;
         }
+        // `end` is a canonical address, so the page before it has a successor
+        va = va.add(PAGESIZE as usize).unwrap_or(end);
     }
 
     // ensure new memory mappings are active
     set_pagetable((*p).p_pagetable);
 
     // copy data from the source to the destination in memory
-    let dst = (*ph).p_va as *mut c_void;
-    memcpy(dst, src as *const c_void, (*ph).p_filesz as usize);
-    let clear_start = (dst as usize + (*ph).p_filesz as usize) as *mut c_void;
-    memset(clear_start, 0, ((*ph).p_memsz - (*ph).p_filesz) as u64);
+    let dst = start.as_usize() as *mut c_void;
+    memcpy(dst, src as *const c_void, ph.p_filesz as usize);
+    let clear_start = (start.as_usize() + ph.p_filesz as usize) as *mut c_void;
+    memset(clear_start, 0, ph.p_memsz - ph.p_filesz);
 
     // eestore the kernel pagetable
     set_pagetable(kernel_pagetable);
     0 // Success
 }
+
+// segment_perm(ph)
+//    Returns the page permissions segment `ph` asks for with its p_flags.
+//    x86-64 cannot map a page that is not readable, so ELF_PFLAG_READ
+//    changes nothing.
+
+fn segment_perm(ph: &ElfProgram) -> X86_64PageentryT {
+    let write = if ph.p_flags & ELF_PFLAG_WRITE != 0 { PTE_W } else { 0 };
+    let nx = if ph.p_flags & ELF_PFLAG_EXEC != 0 { 0 } else { PTE_NX };
+    PTE_P | PTE_U | write | nx
+}
+
+// segment_range(ph)
+//    Returns the virtual address range `[start, end)` segment `ph` occupies
+//    in memory, or None if it does not lie in the address space.
+
+fn segment_range(ph: &ElfProgram) -> Option<(VirtAddr, VirtAddr)> {
+    let start = VirtAddr::new(ph.p_va as usize)?;
+    Some((start, start.add(ph.p_memsz as usize)?))
+}
+
+// segment_pages(ph)
+//    Returns the page-aligned virtual address range covering segment `ph`,
+//    or None if it does not lie in the address space.
+
+fn segment_pages(ph: &ElfProgram) -> Option<(VirtAddr, VirtAddr)> {
+    let (start, end) = segment_range(ph)?;
+    Some((start.page_down(), end.page_up()?))
+}
+
+// page_loaded(loaded, va)
+//    Returns true iff the page containing `va` belongs to one of the
+//    loadable segments in `loaded`.
+
+fn page_loaded(loaded: &[ElfProgram], va: VirtAddr) -> bool {
+    loaded.iter().filter(|ph| ph.p_type == ELF_PTYPE_LOAD).any(|ph| {
+        segment_pages(ph).is_some_and(|(start, end)| start <= va && va < end)
+    })
+}
+
+// share_page(a, b)
+//    Returns true iff segments `a` and `b` have a page in common.
+
+fn share_page(a: &ElfProgram, b: &ElfProgram) -> bool {
+    match (segment_pages(a), segment_pages(b)) {
+        (Some((a_start, a_end)), Some((b_start, b_end))) => a_start < b_end && b_start < a_end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHOFF: usize = size_of::<ElfHeader>();
+
+    // image(segments)
+    //    Returns an executable whose loadable segments are `segments`, each
+    //    `(p_va, p_filesz, p_memsz, p_flags)`, with their contents after the
+    //    program headers.
+
+    fn image(segments: &[(u64, u64, u64, u32)]) -> std::vec::Vec<u8> {
+        let mut eh: ElfHeader = unsafe { core::mem::zeroed() };
+        eh.e_magic = ELF_MAGIC;
+        eh.e_elf[0] = ELF_CLASS_64;
+        eh.e_elf[1] = ELF_DATA_LSB;
+        eh.e_type = ELF_TYPE_EXEC;
+        eh.e_machine = ELF_MACHINE_X86_64;
+        eh.e_phoff = PHOFF as u64;
+        eh.e_phentsize = size_of::<ElfProgram>() as u16;
+        eh.e_phnum = segments.len() as u16;
+
+        let mut bytes = std::vec::Vec::new();
+        bytes.extend_from_slice(unsafe { as_bytes(&eh) });
+        let mut offset = (PHOFF + segments.len() * size_of::<ElfProgram>()) as u64;
+        for &(p_va, p_filesz, p_memsz, p_flags) in segments {
+            let ph = ElfProgram {
+                p_type: ELF_PTYPE_LOAD, p_flags, p_offset: offset, p_va, p_pa: p_va,
+                p_filesz, p_memsz, p_align: PAGESIZE,
+            };
+            bytes.extend_from_slice(unsafe { as_bytes(&ph) });
+            offset += p_filesz;
+        }
+        bytes.resize(offset as usize, 0xCC);
+        bytes
+    }
+
+    unsafe fn as_bytes<T>(value: &T) -> &[u8] {
+        core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
+    }
+
+    // set(image, offset, value)
+    //    Overwrites the bytes of `image` at `offset` with `value`.
+
+    fn set<T>(image: &mut [u8], offset: usize, value: T) {
+        let bytes = unsafe { as_bytes(&value) };
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn va(addr: usize) -> VirtAddr {
+        VirtAddr::new(addr).unwrap()
+    }
+
+    const TEXT: (u64, u64, u64, u32) = (0x100000, 0x80, 0x80, ELF_PFLAG_READ | ELF_PFLAG_EXEC);
+    const DATA: (u64, u64, u64, u32) = (0x101000, 0x10, 0x2000, ELF_PFLAG_READ | ELF_PFLAG_WRITE);
+
+    #[test]
+    fn programs_are_found_by_name() {
+        static BYTES: [u8; 2] = [0; 2];
+        let program = |name| Program { name, image: RamImage { begin: &BYTES[0], end: &BYTES[1] } };
+        let programs = [program("allocator"), program("allocator2"), program("fork")];
+        assert_eq!(find_program(&programs, b"allocator2"), Some(1));
+        assert_eq!(find_program(&programs, b"fork"), Some(2));
+        assert_eq!(find_program(&programs, b"for"), None);
+        assert_eq!(find_program(&programs, b""), None);
+        assert_eq!(programs[0].image.bytes().len(), 1);
+    }
+
+    #[test]
+    fn well_formed_executables_are_accepted() {
+        let image = image(&[TEXT, DATA]);
+        let (eh, ph) = check_elf(&image).unwrap();
+        assert_eq!({ eh.e_phnum }, 2);
+        assert_eq!(ph.len(), 2);
+        assert_eq!({ ph[1].p_memsz }, 0x2000);
+    }
+
+    #[test]
+    fn headers_are_checked() {
+        let good = image(&[TEXT, DATA]);
+        assert_eq!(check_elf(&good[..PHOFF - 1]).err(), Some(LoadError::BadHeader));
+
+        // magic, class, endianness, type and machine
+        for (offset, byte) in [(0, 0u8), (4, 1), (5, 2), (16, 3), (18, 3)] {
+            let mut bad = good.clone();
+            bad[offset] = byte;
+            assert_eq!(check_elf(&bad).err(), Some(LoadError::BadHeader), "offset {offset}");
+        }
+
+        // program headers past the end of the image, or of the wrong size
+        let mut bad = good.clone();
+        set(&mut bad, 0x20, good.len() as u64 - 8);
+        assert_eq!(check_elf(&bad).err(), Some(LoadError::BadProgramHeaders));
+        let mut bad = good.clone();
+        set(&mut bad, 0x20, u64::MAX);
+        assert_eq!(check_elf(&bad).err(), Some(LoadError::BadProgramHeaders));
+        let mut bad = good.clone();
+        set(&mut bad, 0x36, 8u16);
+        assert_eq!(check_elf(&bad).err(), Some(LoadError::BadProgramHeaders));
+    }
+
+    #[test]
+    fn segments_are_checked() {
+        // contents past the end of the image
+        let mut bad = image(&[TEXT, DATA]);
+        set(&mut bad, PHOFF + size_of::<ElfProgram>() + 0x08, u64::MAX - 4);
+        assert_eq!(check_elf(&bad).err(), Some(LoadError::SegmentOutsideImage));
+        let mut bad = image(&[TEXT]);
+        bad.pop();
+        assert_eq!(check_elf(&bad).err(), Some(LoadError::SegmentOutsideImage));
+
+        assert_eq!(check_elf(&image(&[(0x100000, 0x20, 0x10, ELF_PFLAG_EXEC)])).err(),
+                   Some(LoadError::SegmentTooLarge));
+
+        // below PROC_START_ADDR, past MEMSIZE_VIRTUAL, or wrapping around
+        for va in [0x0, 0xFF000, MEMSIZE_VIRTUAL - 0x10, u64::MAX - 0x10] {
+            assert_eq!(check_elf(&image(&[(va, 0x10, 0x20, ELF_PFLAG_EXEC)])).err(),
+                       Some(LoadError::SegmentOutsideUserSpace), "va {va:#x}");
+        }
+
+        // segments may share a page if they agree on its permissions
+        let rodata = (0x100080, 0x10, 0x10, ELF_PFLAG_READ | ELF_PFLAG_EXEC);
+        assert!(check_elf(&image(&[TEXT, rodata])).is_ok());
+        let overlapping = (0x10007F, 0x10, 0x10, ELF_PFLAG_READ | ELF_PFLAG_EXEC);
+        assert_eq!(check_elf(&image(&[TEXT, overlapping])).err(), Some(LoadError::OverlappingSegments));
+        let writable = (0x100080, 0x10, 0x10, ELF_PFLAG_READ | ELF_PFLAG_WRITE);
+        assert_eq!(check_elf(&image(&[TEXT, writable])).err(), Some(LoadError::ConflictingPermissions));
+    }
+
+    #[test]
+    fn shared_pages_are_found_from_the_headers() {
+        let rodata = (0x100080, 0x10, 0x10, ELF_PFLAG_READ | ELF_PFLAG_EXEC);
+        let image = image(&[TEXT, rodata, DATA]);
+        let (_, ph) = check_elf(&image).unwrap();
+        // the second segment's page is the first segment's page
+        assert!(page_loaded(&ph[..1], va(0x100000)));
+        assert!(!page_loaded(&ph[..1], va(0x101000)));
+        assert!(!page_loaded(&ph[..2], va(0x101000)));
+        assert!(page_loaded(&ph[..3], va(0x102000)));
+        assert!(!page_loaded(&[], va(0x100000)));
+
+        // only loadable segments are loaded
+        let mut note = ph[0];
+        note.p_type = 4; // PT_NOTE
+        assert!(!page_loaded(&[note], va(0x100000)));
+    }
+}
@@ -1,3 +1,4 @@
+#![cfg_attr(not(test), no_std)]
 
 use bindings::{
     bindings_x86_64::*,
@@ -5,3 +6,10 @@ use bindings::{
 };
 
 pub mod kloader;
+
+// Outside host tests the crate runs on bare metal
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    stdlib::panic(info)
+}
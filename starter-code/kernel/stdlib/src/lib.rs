@@ -1,3 +1,4 @@
+#![cfg_attr(not(test), no_std)]
 #![allow(unused_macros)]
 
 // This file provides missing functionality for the Rust kernel
@@ -116,3 +117,21 @@ macro_rules! ccol {
         ($cpos) % 80
     };
 }
+
+// panic(info)
+//    Report a Rust panic with `c_panic`, which prints the message and
+//    stops the kernel. The crates that build the kernel image call this
+//    from their `#[panic_handler]`. Only constant messages are printed in
+//    full, so panicking does not pull in `core::fmt`.
+
+pub fn panic(info: &core::panic::PanicInfo) -> ! {
+    unsafe extern "C" {
+        fn c_panic(format: *const core::ffi::c_char, ...) -> !;
+    }
+    let message = info.message().as_str().unwrap_or("explicit panic");
+    let (file, line) = info.location().map_or(("?", 0), |l| (l.file(), l.line()));
+    unsafe {
+        c_panic(c"%.*s:%d: %.*s\n".as_ptr(), file.len() as i32, file.as_ptr(),
+                line as i32, message.len() as i32, message.as_ptr())
+    }
+}
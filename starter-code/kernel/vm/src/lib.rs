@@ -1,13 +1,18 @@
+#![cfg_attr(not(test), no_std)]
 #![allow(static_mut_refs)]
 
 use bindings::bindings_x86_64::*;
 
+pub mod physmem;
 pub mod vm;
 
-use crate::vm::KernelPageTables;
+use crate::vm::{CopyPolicy, KernelPageTables};
 static mut VM: Option<KernelPageTables> = None;
 
 
+/// # Safety
+///
+/// See `KernelPageTables::virtual_memory_init`: called once, at boot.
 #[no_mangle]
 pub unsafe extern "C" fn virtual_memory_init() {
     if VM.is_none() {
@@ -18,6 +23,9 @@ pub unsafe extern "C" fn virtual_memory_init() {
     }
 }
 
+/// # Safety
+///
+/// See `KernelPageTables::set_pagetable`.
 #[no_mangle]
 pub unsafe extern "C" fn set_pagetable(
     pagetable: *mut x86_64_pagetable,
@@ -30,13 +38,16 @@ pub unsafe extern "C" fn set_pagetable(
     }
 }
 
+/// # Safety
+///
+/// See `KernelPageTables::virtual_memory_map`.
 #[no_mangle]
 pub unsafe extern "C" fn virtual_memory_map(
     pagetable: *mut x86_64_pagetable, // Pointer to the page table
     va: usize,                        // Virtual address
     pa: usize,                        // Physical address
     sz: usize,                        // Size
-    perm: i32,                        // Permissions
+    perm: X86_64PageentryT,           // Permissions
 ) -> i32 {
     if VM.is_none() {
         VM = Some(KernelPageTables::new());
@@ -53,6 +64,140 @@ pub unsafe extern "C" fn virtual_memory_map(
     -1
 }
 
+/// # Safety
+///
+/// See `KernelPageTables::virtual_memory_protect`.
+#[no_mangle]
+pub unsafe extern "C" fn virtual_memory_protect(
+    pagetable: *mut x86_64_pagetable, // Pointer to the page table
+    va: usize,                        // Virtual address
+    sz: usize,                        // Size
+    perm: X86_64PageentryT,           // Permissions
+) -> i32 {
+    if VM.is_none() {
+        VM = Some(KernelPageTables::new());
+    }
+    if let Some(vm) = &mut VM {
+        return vm.virtual_memory_protect(
+            pagetable,
+            va,
+            sz,
+            perm,
+        );
+    }
+    -1
+}
+
+/// # Safety
+///
+/// See `KernelPageTables::virtual_memory_unmap`. `released` must point to
+/// room for `sz / PAGESIZE` addresses.
+#[no_mangle]
+pub unsafe extern "C" fn virtual_memory_unmap(
+    pagetable: *mut x86_64_pagetable, // Pointer to the page table
+    va: usize,                        // Virtual address
+    sz: usize,                        // Size
+    released: *mut PhysAddr,          // Room for `sz / PAGESIZE` addresses
+) -> i32 {
+    if VM.is_none() {
+        VM = Some(KernelPageTables::new());
+    }
+    if let Some(vm) = &mut VM {
+        let released = core::slice::from_raw_parts_mut(released, sz / PAGESIZE as usize);
+        return vm.virtual_memory_unmap(
+            pagetable,
+            va,
+            sz,
+            released,
+        );
+    }
+    -1
+}
+
+/// # Safety
+///
+/// See `KernelPageTables::pagetable_free`.
+#[no_mangle]
+pub unsafe extern "C" fn pagetable_free(
+    pagetable: *mut x86_64_pagetable, // Pointer to the page table
+) -> usize {
+    if VM.is_none() {
+        VM = Some(KernelPageTables::new());
+    }
+    if let Some(vm) = &mut VM {
+        return vm.pagetable_free(pagetable);
+    }
+    0
+}
+
+/// # Safety
+///
+/// See `KernelPageTables::copy_pagetable`. `regions` must point to
+/// `nregions` regions, `allocated` to room for `max` addresses, and
+/// `nallocated` to a writable `usize`.
+#[no_mangle]
+pub unsafe extern "C" fn copy_pagetable(
+    src: *mut x86_64_pagetable,       // Page table to copy
+    regions: *const CopyRegion,       // Policy regions
+    nregions: usize,                  // Number of regions
+    default: CopyAction,              // Action outside the regions
+    allocated: *mut PhysAddr,         // Physical addresses of new pages
+    max: usize,                       // Room in `allocated`
+    nallocated: *mut usize,           // Number of new pages
+) -> *mut x86_64_pagetable {
+    if VM.is_none() {
+        VM = Some(KernelPageTables::new());
+    }
+    if let Some(vm) = &mut VM {
+        let policy = CopyPolicy {
+            regions: core::slice::from_raw_parts(regions, nregions),
+            default,
+        };
+        let allocated = core::slice::from_raw_parts_mut(allocated, max);
+        if let Some((copy, n)) = vm.copy_pagetable(src, &policy, allocated) {
+            *nallocated = n;
+            return copy;
+        }
+    }
+    core::ptr::null_mut()
+}
+
+/// # Safety
+///
+/// See `KernelPageTables::mappings`. `runs` must point to room for `max`
+/// runs.
+#[no_mangle]
+pub unsafe extern "C" fn virtual_memory_mappings(
+    pagetable: *mut x86_64_pagetable, // Pointer to the page table
+    va: usize,                        // Lowest virtual address to report
+    runs: *mut MappingRun,            // Runs found
+    max: usize,                       // Room in `runs`
+) -> usize {
+    if VM.is_none() {
+        VM = Some(KernelPageTables::new());
+    }
+    let mut n = 0;
+    if let Some(vm) = &VM {
+        let runs = core::slice::from_raw_parts_mut(runs, max);
+        // a run that starts below `va` is cut at `va`, so callers can
+        // continue from the end of the last run they got
+        for mapping in vm.mappings(pagetable).filter(|m| m.va.end > va).take(max) {
+            let start = mapping.va.start.max(va);
+            runs[n] = MappingRun {
+                start,
+                end: mapping.va.end,
+                pa: mapping.pa.as_usize() + (start - mapping.va.start),
+                perm: mapping.perm,
+            };
+            n += 1;
+        }
+    }
+    n
+}
+
+/// # Safety
+///
+/// See `KernelPageTables::lookup_l1pagetable`.
 #[no_mangle]
 pub unsafe extern "C" fn lookup_l1pagetable(
     pagetable: *mut x86_64_pagetable, // Pointer to the page table
@@ -72,6 +217,9 @@ pub unsafe extern "C" fn lookup_l1pagetable(
     core::ptr::null_mut()
 }
 
+/// # Safety
+///
+/// See `KernelPageTables::virtual_memory_lookup`.
 #[no_mangle]
 pub unsafe extern "C" fn virtual_memory_lookup(
     pagetable: *mut x86_64_pagetable, // Pointer to the page table
@@ -92,3 +240,10 @@ pub unsafe extern "C" fn virtual_memory_lookup(
         perm: 0,
     }
 }
+
+// Outside host tests the crate runs on bare metal
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    stdlib::panic(info)
+}
@@ -0,0 +1,115 @@
+use bindings::bindings_x86_64::*;
+
+// PHYSICAL MEMORY ACCESS
+//
+//    Page table entries hold physical addresses, so walking a page table
+//    means turning each of them back into something we can dereference.
+//    The kernel identity-maps physical memory, which makes that a cast
+//    (`IdentityMemory`); host-side tests instead keep page table pages in
+//    an ordinary `Vec` and hand out arena offsets as physical addresses.
+
+pub trait PhysicalMemory {
+    // pagetable(pa)
+    //    Returns a pointer through which the page table page at physical
+    //    address `pa` can be read and written.
+
+    fn pagetable(&self, pa: PhysAddr) -> *mut x86_64_pagetable;
+
+    // alloc_pagetable()
+    //    Returns the physical address of a new, zeroed page table page, or
+    //    None if no page is available. The default never allocates, so
+    //    mappings must fit in the page tables that already exist.
+
+    fn alloc_pagetable(&mut self) -> Option<PhysAddr> {
+        None
+    }
+
+    // alloc_page()
+    //    Returns the physical address of a new, zeroed page for data (the
+    //    copy of a mapped page, say), or None if no page is available. It is
+    //    reached through `pagetable()` like any other page. The default
+    //    takes it from `alloc_pagetable()`.
+
+    fn alloc_page(&mut self) -> Option<PhysAddr> {
+        self.alloc_pagetable()
+    }
+
+    // free_pagetable(pa)
+    //    Gives back the page at physical address `pa`, from `alloc_pagetable`
+    //    or `alloc_page`, which nothing links to any more. The default keeps
+    //    it.
+
+    fn free_pagetable(&mut self, _pa: PhysAddr) {}
+
+    // flush_page(pagetable, va)
+    //    Called after the present entry for `va` in the page table at
+    //    physical address `pagetable` changed or went away, so the CPU
+    //    forgets its old translation if that table is active. The default
+    //    does nothing.
+
+    fn flush_page(&mut self, _pagetable: PhysAddr, _va: VirtAddr) {}
+
+    // copy_on_write(pa)
+    //    Called when `virtual_memory_protect` makes the present page at
+    //    physical address `pa` writable. Returns true if other mappings
+    //    share the page, so it must be mapped copy-on-write instead; the
+    //    owner of the page metadata records that here. The default shares
+    //    nothing.
+
+    fn copy_on_write(&mut self, _pa: PhysAddr) -> bool {
+        false
+    }
+}
+
+// IdentityMemory
+//    Physical memory as the kernel sees it: address `pa` is mapped at
+//    virtual address `pa`. Page table pages come from, and go back to, the
+//    kernel's page allocator.
+
+pub struct IdentityMemory;
+
+extern "C" {
+    fn alloc_kernel_pagetable() -> usize;
+    fn alloc_kernel_page() -> usize;
+    fn free_kernel_pagetable(pa: usize);
+    fn copy_on_write_page(pa: usize) -> bool;
+}
+
+impl PhysicalMemory for IdentityMemory {
+    fn pagetable(&self, pa: PhysAddr) -> *mut x86_64_pagetable {
+        pa.as_ptr()
+    }
+
+    fn alloc_pagetable(&mut self) -> Option<PhysAddr> {
+        match unsafe { alloc_kernel_pagetable() } {
+            0 => None,
+            pa => PhysAddr::new(pa),
+        }
+    }
+
+    fn alloc_page(&mut self) -> Option<PhysAddr> {
+        match unsafe { alloc_kernel_page() } {
+            0 => None,
+            pa => PhysAddr::new(pa),
+        }
+    }
+
+    fn free_pagetable(&mut self, pa: PhysAddr) {
+        unsafe { free_kernel_pagetable(pa.as_usize()) }
+    }
+
+    fn flush_page(&mut self, pagetable: PhysAddr, va: VirtAddr) {
+        // The kernel edits process page tables while its own table is
+        // loaded, and loading a process's table with `lcr3` flushes its
+        // stale entries, so only the active table needs an `invlpg`.
+        unsafe {
+            if rcr3() == pagetable.as_usize() {
+                invlpg(va.as_usize());
+            }
+        }
+    }
+
+    fn copy_on_write(&mut self, pa: PhysAddr) -> bool {
+        unsafe { copy_on_write_page(pa.as_usize()) }
+    }
+}
@@ -3,24 +3,203 @@
 use bindings::bindings_x86_64::*;
 use bindings::bindings_kernel::*;
 
+use crate::physmem::{IdentityMemory, PhysicalMemory};
+
 // NOTE
 // Read x86-64.h for some useful functions and macros relevant here!
 
 extern "C" {
     pub fn c_panic(format: *const core::ffi::c_char, ...) -> !;
     pub fn default_int_handler();
+    pub fn physical_memory_size() -> usize;
 }
 
 #[no_mangle]
 #[allow(non_upper_case_globals)]
 static mut kernel_pagetable: *mut x86_64_pagetable = core::ptr::null_mut();
 
-pub struct KernelPageTables {
+// CopyPolicy
+//    Says what `copy_pagetable` does with the pages at each virtual
+//    address: the action of the first region containing the address, or
+//    `default` if none does.
+
+pub struct CopyPolicy<'a> {
+    pub regions: &'a [CopyRegion],
+    pub default: CopyAction,
+}
+
+impl CopyPolicy<'_> {
+    pub fn action(&self, va: VirtAddr) -> CopyAction {
+        let va = va.as_usize();
+        self.regions.iter()
+            .find(|region| region.start <= va && va < region.end)
+            .map_or(self.default, |region| region.action)
+    }
+}
+
+// cow_entry(entry)
+//    Returns page table entry `entry` made copy-on-write if it is writable.
+
+fn cow_entry(entry: PageTableEntry) -> PageTableEntry {
+    if entry.is_writable() {
+        entry.with_flags(PTE_COW, PTE_W)
+    } else {
+        entry
+    }
+}
+
+// Mapping
+//    A run of pages mapped at consecutive virtual addresses to consecutive
+//    physical addresses with the same permissions, as `Mappings` yields it.
+//    `perm` holds `PTE_P`, `PTE_W` and `PTE_U` if every level grants them,
+//    as virtual_memory_lookup reports them, and `PTE_NX` if the page's
+//    entry has it.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub va: core::ops::Range<usize>,
+    pub pa: PhysAddr,
+    pub perm: X86_64PageentryT,
+}
+
+// Mappings
+//    Iterator over the mappings of a page table, lowest address first (see
+//    KernelPageTables::mappings). It walks all four levels, keeping the
+//    path to the current entry, and merges each page into the previous run
+//    when it continues it.
+
+pub struct Mappings<'a, M: PhysicalMemory> {
+    mem: &'a M,
+    tables: [PhysAddr; 4],              // table at each level of the path
+    index: [usize; 4],                  // next entry to visit at each level
+    allowed: [X86_64PageentryT; 4],     // PTE_W | PTE_U granted above each level
+    level: usize,
+    pending: Option<Mapping>,           // first page of the next run
+}
+
+impl<M: PhysicalMemory> Mappings<'_, M> {
+    // next_page()
+    //    Returns the next present page, or large page, of the table as a
+    //    one-page run.
+
+    fn next_page(&mut self) -> Option<Mapping> {
+        loop {
+            let level = self.level;
+            if self.index[level] == NPAGETABLEENTRIES as usize {
+                if level == 0 {
+                    return None;
+                }
+                self.level -= 1;
+                self.index[level - 1] += 1;
+                continue;
+            }
+
+            let entry = unsafe { (*self.mem.pagetable(self.tables[level])).entry[self.index[level]] };
+            if !entry.is_present() {
+                self.index[level] += 1;
+                continue;
+            }
+            if level == 3 || entry.is_huge() {
+                let size = 1usize << (PAGEOFFBITS + (3 - level) * PAGEINDEXBITS);
+                let mut va = (0..=level).fold(0, |va, l| level_base(va, l, self.index[l]));
+                if self.index[0] >= NPAGETABLEENTRIES as usize / 2 {
+                    va |= !0 << VIRTADDR_BITS; // upper half: sign-extend
+                }
+                self.index[level] += 1;
+                return Some(Mapping {
+                    va: va..va + size,
+                    pa: PhysAddr::new(entry.addr().as_usize() & !(size - 1))?,
+                    perm: entry.flags() & (PTE_P | PTE_NX | (self.allowed[level] & entry.flags())),
+                });
+            }
+
+            self.tables[level + 1] = entry.addr();
+            self.allowed[level + 1] = self.allowed[level] & entry.bits();
+            self.index[level + 1] = 0;
+            self.level += 1;
+        }
+    }
+}
+
+impl<M: PhysicalMemory> Iterator for Mappings<'_, M> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let mut run = self.pending.take().or_else(|| self.next_page())?;
+        while let Some(page) = self.next_page() {
+            if page.va.start == run.va.end
+                && page.perm == run.perm
+                && run.pa.add(run.va.len()) == Some(page.pa)
+            {
+                run.va.end = page.va.end;
+            } else {
+                self.pending = Some(page);
+                break;
+            }
+        }
+        Some(run)
+    }
+}
+
+// checked_range(va, sz)
+//    Returns `va` as a virtual address if `va` and `sz` are page-aligned
+//    and every address in `[va, va+sz)` is canonical, and None otherwise.
+
+fn checked_range(va: usize, sz: usize) -> Option<VirtAddr> {
+    let start = VirtAddr::new(va)?;
+    if !start.is_page_aligned() || !sz.is_multiple_of(PAGESIZE as usize) {
+        return None;
+    }
+    if sz != 0 {
+        // both ends canonical and on the same side of the hole
+        let last = start.add(sz - 1)?;
+        if (last.as_usize() ^ va) >> (VIRTADDR_BITS - 1) != 0 {
+            return None;
+        }
+    }
+    Some(start)
+}
+
+// pages(start, sz)
+//    Returns the address of each page of `[start, start+sz)`, a range that
+//    passed checked_range.
+
+fn pages(start: VirtAddr, sz: usize) -> impl Iterator<Item = (usize, VirtAddr)> {
+    (0..sz).step_by(PAGESIZE as usize).filter_map(move |offset| Some((offset, start.add(offset)?)))
+}
+
+// level_base(base, level, index)
+//    Returns the first virtual address translated by entry `index` of a
+//    level-`level` table whose first address is `base`.
+
+fn level_base(base: usize, level: usize, index: usize) -> usize {
+    base | index << (PAGEOFFBITS + (3 - level) * PAGEINDEXBITS)
+}
+
+// KernelPageTables
+//    The page table functions, working on page tables whose pages are
+//    reached through `mem`. A `pagetable` passed to them must be
+//    `kernel_pagetable` or an L4 table built by them, every table it links
+//    to must be reachable through `mem`, and nothing else may change it
+//    during the call.
+//
+//    `preset_end` is the end of the low range whose page tables every
+//    process gets up front (see `process_pagetable_alloc` in the kernel):
+//    unmapping never frees the tables translating `[0, preset_end)`, so
+//    mapping there later never needs a new page table.
+
+pub struct KernelPageTables<M: PhysicalMemory = IdentityMemory> {
     pub kernel_pagetables: [x86_64_pagetable; 5],
+    pub mem: M,
+    pub preset_end: usize,
 }
 
-impl KernelPageTables {
-    pub fn new() -> Self {
+impl<M: PhysicalMemory> KernelPageTables<M> {
+    // with_memory(mem)
+    //    Page tables whose pages are reached through `mem` (see physmem.rs),
+    //    with no preset range.
+
+    pub fn with_memory(mem: M) -> Self {
         KernelPageTables {
             kernel_pagetables: [
                 x86_64_pagetable::new(),
@@ -29,46 +208,579 @@ impl KernelPageTables {
                 x86_64_pagetable::new(),
                 x86_64_pagetable::new(),
             ],
+            mem,
+            preset_end: 0,
+        }
+    }
+
+    // virtual_memory_map(pagetable, va, pa, sz, perm)
+    //    Map virtual address range `[va, va+sz)` in `pagetable`.
+    //    When `X >= 0 && X < sz`, the new pagetable will map virtual address
+    //    `va+X` to physical address `pa+X` with permissions `perm`.
+    //
+    //    Precondition: `va`, `pa`, and `sz` must be multiples of PAGESIZE
+    //    (4096).
+    //
+    //    Typically `perm` is a combination of `PTE_P` (the memory is Present),
+    //    `PTE_W` (the memory is Writable), and `PTE_U` (the memory may be
+    //    accessed by User applications), plus `PTE_NX` if the memory must not
+    //    be executed. If `!(perm & PTE_P)`, `pa` is ignored.
+    //    Pages that were mapped before are flushed from the TLB if
+    //    `pagetable` is active.
+    //
+    //    Missing intermediate page tables are allocated with
+    //    `self.mem.alloc_pagetable()`. Returns 0 if the map succeeds, -1 if
+    //    it fails (because the arguments are not page-aligned, the ranges
+    //    are not valid addresses, `perm` has bits outside PTE_FLAGS, or a
+    //    required page table could not be allocated).
+
+    /// # Safety
+    ///
+    /// `pagetable` must be valid as described at `KernelPageTables`, and
+    /// the kernel must not rely on the old mappings in the range.
+    pub unsafe fn virtual_memory_map(
+        &mut self,
+        pagetable: *mut x86_64_pagetable, // Pointer to the page table
+        va: usize,                        // Virtual address
+        pa: usize,                        // Physical address
+        sz: usize,                        // Size
+        perm: X86_64PageentryT,           // Permissions
+    ) -> i32 {
+        let flags = perm;
+        let present = flags & PTE_P != 0;
+        let Some(start) = checked_range(va, sz) else {
+            return -1;
+        };
+        let pa = match PhysAddr::new(pa) {
+            Some(pa) if pa.is_page_aligned() && pa.add(sz).is_some() => pa,
+            _ if !present => PhysAddr::default(),
+            _ => return -1,
+        };
+        if flags & !PTE_FLAGS != 0 {
+            return -1;
         }
+
+        for (offset, va) in pages(start, sz) {
+            let l1pagetable = self.lookup_l1pagetable(pagetable, va.as_usize(), (flags & (PTE_P | PTE_W | PTE_U)) as i32);
+            if l1pagetable.is_null() {
+                if !present {
+                    continue; // nothing is mapped here, so nothing to remove
+                }
+                return -1;
+            }
+
+            let entry = if present {
+                match pa.add(offset).and_then(|pa| PageTableEntry::new(pa, flags)) {
+                    Some(entry) => entry,
+                    None => return -1,
+                }
+            } else {
+                PageTableEntry::from_bits(flags)
+            };
+            let l1 = self.mem.pagetable(table_addr(l1pagetable));
+            let old = core::mem::replace(&mut (*l1).entry[l1_index(va)], entry);
+            if old.is_present() {
+                self.mem.flush_page(table_addr(pagetable), va);
+            }
+        }
+        0
     }
-    
+
+    // virtual_memory_protect(pagetable, va, sz, perm)
+    //    Change the permissions of the pages mapped in `[va, va+sz)` in
+    //    `pagetable`: their `PTE_W`, `PTE_U` and `PTE_NX` bits become those
+    //    of `perm`, and every other bit, including the physical address, is
+    //    kept. A present page made writable that `self.mem.copy_on_write()`
+    //    reports shared is made copy-on-write (`PTE_COW` without `PTE_W`)
+    //    instead, and any other present page loses `PTE_COW`. Pages that are
+    //    not present keep their other bits (the kernel may store information
+    //    there). Empty entries and missing page tables are skipped; nothing
+    //    is allocated.
+    //
+    //    Returns 0 on success and -1 if `va` or `sz` is not page-aligned.
+    //    Changed pages are flushed from the TLB if `pagetable` is active.
+
+    /// # Safety
+    ///
+    /// `pagetable` must be valid as described at `KernelPageTables`, and
+    /// the kernel must not rely on the permissions it takes away.
+    pub unsafe fn virtual_memory_protect(
+        &mut self,
+        pagetable: *mut x86_64_pagetable, // Pointer to the page table
+        va: usize,                        // Virtual address
+        sz: usize,                        // Size
+        perm: X86_64PageentryT,           // Permissions
+    ) -> i32 {
+        let Some(start) = checked_range(va, sz) else {
+            return -1;
+        };
+
+        let bits = perm & (PTE_W | PTE_U | PTE_NX);
+        for (_, va) in pages(start, sz) {
+            let l1pagetable = self.lookup_l1pagetable(pagetable, va.as_usize(), 0);
+            if l1pagetable.is_null() {
+                continue;
+            }
+            let l1 = self.mem.pagetable(table_addr(l1pagetable));
+            let entry = &mut (*l1).entry[l1_index(va)];
+            if entry.is_empty() {
+                continue;
+            }
+            let old = *entry;
+            *entry = old.with_flags(bits, PTE_W | PTE_U | PTE_NX);
+            if old.is_present() {
+                *entry = if bits & PTE_W != 0 && self.mem.copy_on_write(old.addr()) {
+                    cow_entry(*entry)
+                } else {
+                    entry.with_flags(0, PTE_COW)
+                };
+                if old != *entry {
+                    self.mem.flush_page(table_addr(pagetable), va);
+                }
+            }
+        }
+        0
+    }
+
+    // virtual_memory_unmap(pagetable, va, sz, released)
+    //    Remove every mapping in `[va, va+sz)` from `pagetable`, clearing
+    //    entries that are not present too. The physical addresses of the
+    //    pages that were mapped are stored in `released`, which must have
+    //    room for `sz / PAGESIZE` of them; the caller decides what becomes
+    //    of those pages. Page tables left empty are freed with
+    //    `self.mem.free_pagetable()`, except for the L4 table itself, the
+    //    tables of `kernel_pagetable`, and the tables that translate
+    //    `[0, self.preset_end)`.
+    //
+    //    Returns the number of released pages, or -1 if `va` or `sz` is not
+    //    page-aligned or `released` is too small. Unmapped pages are
+    //    flushed from the TLB if `pagetable` is active.
+
+    /// # Safety
+    ///
+    /// `pagetable` must be valid as described at `KernelPageTables`, and
+    /// the kernel must not rely on the mappings it removes.
+    pub unsafe fn virtual_memory_unmap(
+        &mut self,
+        pagetable: *mut x86_64_pagetable, // Pointer to the page table
+        va: usize,                        // Virtual address
+        sz: usize,                        // Size
+        released: &mut [PhysAddr],        // Physical addresses of unmapped pages
+    ) -> i32 {
+        let Some(start) = checked_range(va, sz) else {
+            return -1;
+        };
+        if released.len() < sz / PAGESIZE as usize {
+            return -1;
+        }
+
+        let mut nreleased = 0;
+        for (_, va) in pages(start, sz) {
+            let Some(tables) = self.tables_on_path(pagetable, va) else {
+                continue; // no L1 table, so nothing is mapped here
+            };
+            let l1 = self.mem.pagetable(tables[3]);
+            let entry = &mut (*l1).entry[l1_index(va)];
+            let old = core::mem::replace(entry, PageTableEntry::EMPTY);
+            if old.is_present() {
+                released[nreleased] = old.addr();
+                nreleased += 1;
+                self.mem.flush_page(table_addr(pagetable), va);
+            }
+            if pagetable != kernel_pagetable && va.as_usize() >= self.preset_end {
+                self.free_empty_tables(&tables, va);
+            }
+        }
+        nreleased as i32
+    }
+
+    // tables_on_path(pagetable, va)
+    //    Returns the physical addresses of the L4, L3, L2 and L1 tables that
+    //    translate `va` in `pagetable`, or None if there is no L1 table.
+
+    unsafe fn tables_on_path(&self, pagetable: *mut x86_64_pagetable, va: VirtAddr) -> Option<[PhysAddr; 4]> {
+        let mut tables = [table_addr(pagetable); 4];
+        for level in 0..3 {
+            let entry = (*self.mem.pagetable(tables[level])).entry[va.index(level)];
+            if !entry.is_present() || entry.is_huge() {
+                return None;
+            }
+            tables[level + 1] = entry.addr();
+        }
+        Some(tables)
+    }
+
+    // free_empty_tables(tables, va)
+    //    Free the tables in `tables` (as returned by `tables_on_path(_, va)`)
+    //    that hold no entries, from the L1 table up, unlinking each from the
+    //    table above. The L4 table is kept.
+
+    unsafe fn free_empty_tables(&mut self, tables: &[PhysAddr; 4], va: VirtAddr) {
+        for level in (1..4).rev() {
+            if (*self.mem.pagetable(tables[level])).entry.iter().any(|entry| !entry.is_empty()) {
+                return;
+            }
+            self.mem.free_pagetable(tables[level]);
+            (*self.mem.pagetable(tables[level - 1])).entry[va.index(level - 1)] = PageTableEntry::EMPTY;
+        }
+    }
+
+    // pagetable_free(pagetable)
+    //    Free every page table page of `pagetable`, the L4 table included,
+    //    with `self.mem.free_pagetable()`. The pages it maps are left alone,
+    //    so the caller must release them first (see virtual_memory_unmap).
+    //    `kernel_pagetable` is never freed. Returns the number of table
+    //    pages freed.
+
+    /// # Safety
+    ///
+    /// `pagetable` must be valid as described at `KernelPageTables`, must
+    /// not be active, and must not be used again.
+    pub unsafe fn pagetable_free(&mut self, pagetable: *mut x86_64_pagetable) -> usize {
+        if pagetable == kernel_pagetable {
+            return 0;
+        }
+        self.pagetable_free_level(table_addr(pagetable), 0)
+    }
+
+    unsafe fn pagetable_free_level(&mut self, pt: PhysAddr, level: usize) -> usize {
+        let mut nfreed = 1;
+        if level < 3 {
+            for index in 0..NPAGETABLEENTRIES as usize {
+                let entry = (*self.mem.pagetable(pt)).entry[index];
+                if entry.is_present() && !entry.is_huge() {
+                    nfreed += self.pagetable_free_level(entry.addr(), level + 1);
+                }
+            }
+        }
+        self.mem.free_pagetable(pt);
+        nfreed
+    }
+
+    // copy_pagetable(src, policy, allocated)
+    //    Returns a new page table mapping what `src` maps. Each page mapped
+    //    in `src` is handled as `policy` says for its virtual address:
+    //    - `CopyAction::Share`: the copy maps the same physical page with the
+    //      same permissions.
+    //    - `CopyAction::Copy`: the copy maps a new page, from
+    //      `self.mem.alloc_page()`, holding the same contents.
+    //    - `CopyAction::Cow`: the page is shared, but if it is writable both
+    //      tables map it read-only and mark it `PTE_COW`, so the first
+    //      write faults and can be given its own copy.
+    //    Entries that are not present are not copied, and large pages are
+    //    shared.
+    //
+    //    The physical addresses of the pages allocated for the copy (its
+    //    page tables and the copied pages) are stored in `allocated`, and
+    //    their number is returned with the new L4 table, so the caller can
+    //    account for them. Returns None, having freed whatever it allocated
+    //    and leaving `src` unchanged, if memory or `allocated` runs out.
+    //    Pages marked copy-on-write are flushed from the TLB if `src` is
+    //    active.
+
+    /// # Safety
+    ///
+    /// `src` must be valid as described at `KernelPageTables`, and the
+    /// kernel must not rely on writing the pages this marks copy-on-write.
+    pub unsafe fn copy_pagetable(
+        &mut self,
+        src: *mut x86_64_pagetable,       // Page table to copy
+        policy: &CopyPolicy,              // What to do with each page
+        allocated: &mut [PhysAddr],       // Physical addresses of new pages
+    ) -> Option<(*mut x86_64_pagetable, usize)> {
+        let mut nallocated = 0;
+        match self.copy_level(table_addr(src), 0, 0, policy, allocated, &mut nallocated) {
+            Some(copy) => {
+                self.mark_cow(table_addr(src), table_addr(src), 0, 0, policy);
+                Some((copy.as_ptr(), nallocated))
+            }
+            None => {
+                for &pa in &allocated[..nallocated] {
+                    self.mem.free_pagetable(pa);
+                }
+                None
+            }
+        }
+    }
+
+    // copy_level(pt, level, base, policy, allocated, nallocated)
+    //    Returns a copy of the level-`level` table `pt`, which translates
+    //    the virtual addresses starting at `base`, for copy_pagetable. New
+    //    pages are appended to `allocated[..*nallocated]`.
+
+    unsafe fn copy_level(
+        &mut self,
+        pt: PhysAddr,
+        level: usize,
+        base: usize,
+        policy: &CopyPolicy,
+        allocated: &mut [PhysAddr],
+        nallocated: &mut usize,
+    ) -> Option<PhysAddr> {
+        let copy = self.alloc_for_copy(false, allocated, nallocated)?;
+        for index in 0..NPAGETABLEENTRIES as usize {
+            let entry = (*self.mem.pagetable(pt)).entry[index];
+            if !entry.is_present() {
+                continue;
+            }
+            let va = level_base(base, level, index);
+            let new = if level < 3 && !entry.is_huge() {
+                let next = self.copy_level(entry.addr(), level + 1, va, policy, allocated, nallocated)?;
+                PageTableEntry::new(next, entry.flags())?
+            } else if level < 3 {
+                entry
+            } else {
+                match policy.action(VirtAddr::new(va)?) {
+                    CopyAction::Share => entry,
+                    CopyAction::Copy => {
+                        let page = self.alloc_for_copy(true, allocated, nallocated)?;
+                        core::ptr::copy_nonoverlapping(
+                            self.mem.pagetable(entry.addr()),
+                            self.mem.pagetable(page),
+                            1,
+                        );
+                        PageTableEntry::new(page, entry.flags())?
+                    }
+                    CopyAction::Cow => cow_entry(entry),
+                }
+            };
+            (*self.mem.pagetable(copy)).entry[index] = new;
+        }
+        Some(copy)
+    }
+
+    // alloc_for_copy(data, allocated, nallocated)
+    //    Allocates a page table page, or a data page if `data`, and appends
+    //    it to `allocated[..*nallocated]`. Returns None if memory or
+    //    `allocated` is full.
+
+    unsafe fn alloc_for_copy(&mut self, data: bool, allocated: &mut [PhysAddr], nallocated: &mut usize) -> Option<PhysAddr> {
+        if *nallocated == allocated.len() {
+            return None;
+        }
+        let pa = if data { self.mem.alloc_page() } else { self.mem.alloc_pagetable() }?;
+        allocated[*nallocated] = pa;
+        *nallocated += 1;
+        Some(pa)
+    }
+
+    // mark_cow(src, pt, level, base, policy)
+    //    Mark the writable pages of `src` that `policy` copies on write as
+    //    copy_level marked them in the copy, flushing them from the TLB.
+
+    unsafe fn mark_cow(&mut self, src: PhysAddr, pt: PhysAddr, level: usize, base: usize, policy: &CopyPolicy) {
+        for index in 0..NPAGETABLEENTRIES as usize {
+            let entry = &mut (*self.mem.pagetable(pt)).entry[index];
+            if !entry.is_present() || (level < 3 && entry.is_huge()) {
+                continue;
+            }
+            let va = level_base(base, level, index);
+            if level < 3 {
+                self.mark_cow(src, entry.addr(), level + 1, va, policy);
+            } else if let Some(va) = VirtAddr::new(va).filter(|&va| policy.action(va) == CopyAction::Cow) {
+                let old = core::mem::replace(entry, cow_entry(*entry));
+                if old != *entry {
+                    self.mem.flush_page(src, va);
+                }
+            }
+        }
+    }
+
+    // lookup_l1pagetable(pagetable, va, perm)
+    //    Helper function to find the last level of `va` in `pagetable`
+    //
+    //    Returns an x86_64_pagetable pointer to the last level pagetable
+    //    if it exists and can be accessed with the given permissions
+    //    Returns NULL otherwise, and for non-canonical `va`
+    //
+    //    If `perm & PTE_P`, missing page tables on the way are allocated and
+    //    linked in with `PTE_P | PTE_W | PTE_U`; the final mapping decides the
+    //    effective permissions. All addresses here are physical addresses.
+
+    /// # Safety
+    ///
+    /// `pagetable` must be valid as described at `KernelPageTables`.
+    pub unsafe fn lookup_l1pagetable(
+        &mut self,
+        pagetable: *mut x86_64_pagetable, // Pointer to the page table
+        va: usize,                        // Virtual address
+        perm: i32,                        // Permissions
+    ) -> *mut x86_64_pagetable {
+        let Some(va) = VirtAddr::new(va) else {
+            return core::ptr::null_mut();
+        };
+        let required = perm as X86_64PageentryT & (PTE_W | PTE_U);
+        let mut pt = table_addr(pagetable);
+
+        for level in 0..3 {
+            let index = va.index(level);
+            let mut entry = (*self.mem.pagetable(pt)).entry[index];
+
+            if !entry.is_present() {
+                if perm & PTE_P as i32 == 0 {
+                    return core::ptr::null_mut();
+                }
+                let next = match self.mem.alloc_pagetable() {
+                    Some(next) => next,
+                    None => return core::ptr::null_mut(),
+                };
+                entry = match PageTableEntry::new(next, PTE_P | PTE_W | PTE_U) {
+                    Some(entry) => entry,
+                    None => return core::ptr::null_mut(),
+                };
+                (*self.mem.pagetable(pt)).entry[index] = entry;
+            } else if entry.is_huge() || !entry.has(required) {
+                return core::ptr::null_mut();
+            }
+
+            pt = entry.addr();
+        }
+        pt.as_ptr()
+    }
+
+    // virtual_memory_lookup(pagetable, va)
+    //    Returns information about the mapping of the virtual address `va` in
+    //    `pagetable`. The information is returned as a `vamapping` object:
+    //    `pn` and `pa` are the physical page number and address (`pa` includes
+    //    the offset of `va` in its page), and `perm` the permissions. `PTE_W`
+    //    and `PTE_U` are only reported if every level grants them; `perm` is
+    //    an int, so `PTE_NX` is not reported. Unmapped and non-canonical
+    //    addresses return `pn == -1`, `pa == usize::MAX` and `perm == 0`.
+
+    /// # Safety
+    ///
+    /// `pagetable` must be valid as described at `KernelPageTables`.
+    pub unsafe fn virtual_memory_lookup(
+        &self,
+        pagetable: *mut x86_64_pagetable, // Pointer to the page table
+        va: usize,                        // Virtual address
+    ) -> VAMapping {
+        const UNMAPPED: VAMapping = VAMapping {
+            pn: -1,
+            pa: usize::MAX,
+            perm: 0,
+        };
+        let Some(va) = VirtAddr::new(va) else {
+            return UNMAPPED;
+        };
+        let mut pt = table_addr(pagetable);
+        let mut allowed = PTE_W | PTE_U;
+        let mut entry = PageTableEntry::EMPTY;
+
+        for level in 0..4 {
+            entry = (*self.mem.pagetable(pt)).entry[va.index(level)];
+            if !entry.is_present() {
+                return UNMAPPED;
+            }
+            if level < 3 {
+                allowed &= entry.bits();
+            }
+            pt = entry.addr();
+        }
+
+        let pa = entry.addr();
+        VAMapping {
+            pn: pa.page_number().as_usize() as i32,
+            pa: pa.as_usize() + va.page_offset(),
+            perm: (entry.flags() & (allowed | !(PTE_W | PTE_U))) as i32,
+        }
+    }
+
+    // mappings(pagetable)
+    //    Returns an iterator over the mappings of `pagetable`: runs of pages
+    //    mapped at consecutive virtual and physical addresses with the same
+    //    permissions (see `Mapping`), lowest virtual address first. Large
+    //    pages are part of runs like any other page. The page table must not
+    //    change while the iterator is in use.
+
+    /// # Safety
+    ///
+    /// `pagetable` must be valid as described at `KernelPageTables` and
+    /// must not change while the iterator is in use.
+    pub unsafe fn mappings(&self, pagetable: *mut x86_64_pagetable) -> Mappings<'_, M> {
+        Mappings {
+            mem: &self.mem,
+            tables: [table_addr(pagetable); 4],
+            index: [0; 4],
+            allowed: [PTE_W | PTE_U; 4],
+            level: 0,
+            pending: None,
+        }
+    }
+}
+
+// table_addr(pagetable)
+//    Returns the physical address of page table `pagetable`. Page table
+//    pointers handed to this module are physical addresses (see
+//    physmem.rs), so this is a conversion, not a translation.
+
+fn table_addr(pagetable: *mut x86_64_pagetable) -> PhysAddr {
+    PhysAddr::from_ptr(pagetable).unwrap_or_default()
+}
+
+impl Default for KernelPageTables {
+    // The kernel's page tables: each process's first two L1 tables, which
+    // cover the first 4MB, are preset.
+    fn default() -> Self {
+        KernelPageTables {
+            preset_end: 2 * NPAGETABLEENTRIES as usize * PAGESIZE as usize,
+            ..KernelPageTables::with_memory(IdentityMemory)
+        }
+    }
+}
+
+impl KernelPageTables {
+    pub fn new() -> Self {
+        KernelPageTables::default()
+    }
+
     // virtual_memory_init
     //    Initialize the virtual memory system, including an initial page table
     //    `kernel_pagetable`.
 
+    /// # Safety
+    ///
+    /// Must be called once, at boot, before any other page table function;
+    /// `self` must not move afterwards, since `kernel_pagetable` points
+    /// into it.
     pub unsafe fn virtual_memory_init(
         &mut self,
     ) {
         kernel_pagetable = &mut self.kernel_pagetables[0];
 
-        // connect the pagetable pages
-        self.kernel_pagetables[0].entry[0] =
-            (&self.kernel_pagetables[1] as *const _ as u64) | PTE_P | PTE_W | PTE_U;
-        self.kernel_pagetables[1].entry[0] =
-            (&self.kernel_pagetables[2] as *const _ as u64) | PTE_P | PTE_W | PTE_U;
-        self.kernel_pagetables[2].entry[0] =
-            (&self.kernel_pagetables[3] as *const _ as u64) | PTE_P | PTE_W | PTE_U;
-        self.kernel_pagetables[2].entry[1] =
-            (&self.kernel_pagetables[4] as *const _ as u64) | PTE_P | PTE_W | PTE_U;
-
-        // identity map the page table
-        self.virtual_memory_map(
+        // connect the pagetable pages (they are page-aligned, so the
+        // entries always exist)
+        let link: [PageTableEntry; 5] = core::array::from_fn(|i| {
+            let table = &mut self.kernel_pagetables[i] as *mut x86_64_pagetable;
+            PageTableEntry::new(table_addr(table), PTE_P | PTE_W | PTE_U).unwrap_or_default()
+        });
+        self.kernel_pagetables[0].entry[0] = link[1];
+        self.kernel_pagetables[1].entry[0] = link[2];
+        self.kernel_pagetables[2].entry[0] = link[3];
+        self.kernel_pagetables[2].entry[1] = link[4];
+
+        // identity map physical memory; page tables beyond the first 4MB
+        // come from the kernel's page allocator
+        let memsize = physical_memory_size();
+        if self.virtual_memory_map(
             kernel_pagetable,
             0,
             0,
-            MEMSIZE_PHYSICAL as usize,
-            (PTE_P | PTE_W | PTE_U) as i32,
-        );
+            memsize,
+            PTE_P | PTE_W | PTE_U,
+        ) != 0 {
+            c_panic(c"(virtual_memory_init) out of memory for page tables".as_ptr());
+        }
 
         // Verify the identity mapping
-        for addr in (0..MEMSIZE_PHYSICAL).step_by(PAGESIZE as usize) {
-            let vmap = self.virtual_memory_lookup(kernel_pagetable, addr as usize);
+        for addr in (0..memsize).step_by(PAGESIZE as usize) {
+            let vmap = self.virtual_memory_lookup(kernel_pagetable, addr);
             // this assert will probably fail initially!
             // have you implemented virtual_memory_map and lookup_l1pagetable ?
-            if !(vmap.pa == addr as usize) {
+            if vmap.pa != addr {
                 c_panic("(virtual_memory_init) identity mapping failed".as_ptr() as *const i8);
             }
-            if !((vmap.perm & (PTE_P | PTE_W) as i32) == (PTE_P | PTE_W) as i32) {
+            if (vmap.perm & (PTE_P | PTE_W) as i32) != (PTE_P | PTE_W) as i32 {
                 c_panic("(virtual_memory_init) (vmap.perm & (PTE_P | PTE_W)) == (PTE_P | PTE_W) failed".as_ptr() as *const i8);
             }
         }
@@ -83,6 +795,10 @@ impl KernelPageTables {
     //    set_pagetable() additionally checks that important kernel procedures are
     //    mappable in `pagetable`, and calls panic() if they aren't.
 
+    /// # Safety
+    ///
+    /// `pagetable` must be valid as described at `KernelPageTables` and
+    /// map everything the kernel uses after the switch.
     pub unsafe fn set_pagetable(
         &self, 
         pagetable: *mut x86_64_pagetable,
@@ -92,7 +808,8 @@ impl KernelPageTables {
         }
 
         // Check for kernel space being mapped in the pagetable
-        if self.virtual_memory_lookup(pagetable, default_int_handler as usize).pa != default_int_handler as usize {
+        let handler = default_int_handler as *const () as usize;
+        if self.virtual_memory_lookup(pagetable, handler).pa != handler {
             c_panic("default_int_handler is not mapped in the pagetable".as_ptr() as *const i8);
         }
 
@@ -111,65 +828,534 @@ impl KernelPageTables {
         // Set the page table in the CR3 register
         lcr3(pagetable as usize);
     }
+}
 
-    // virtual_memory_map(pagetable, va, pa, sz, perm)
-    //    Map virtual address range `[va, va+sz)` in `pagetable`.
-    //    When `X >= 0 && X < sz`, the new pagetable will map virtual address
-    //    `va+X` to physical address `pa+X` with permissions `perm`.
-    //
-    //    Precondition: `va`, `pa`, and `sz` must be multiples of PAGESIZE
-    //    (4096).
-    //
-    //    Typically `perm` is a combination of `PTE_P` (the memory is Present),
-    //    `PTE_W` (the memory is Writable), and `PTE_U` (the memory may be
-    //    accessed by User applications). If `!(perm & PTE_P)`, `pa` is ignored.
-    //
-    //    Returns 0 if the map succeeds, -1 if it fails (because a required
-    //    page table was not allocated).
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::UnsafeCell;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
 
-    pub unsafe fn virtual_memory_map(
-        &self,
-        pagetable: *mut x86_64_pagetable, // Pointer to the page table
-        va: usize,                        // Virtual address
-        pa: usize,                        // Physical address
-        sz: usize,                        // Size
-        perm: i32,                        // Permissions
-    ) -> i32 {
-        // Function body will go here
-        0 // Placeholder return value
-    }    
+    const PAGE: usize = PAGESIZE as usize;
+    const PTE_PWU: X86_64PageentryT = PTE_P | PTE_W | PTE_U;
 
-    // lookup_l1pagetable(pagetable, va, perm)
-    //    Helper function to find the last level of `va` in `pagetable`
-    //
-    //    Returns an x86_64_pagetable pointer to the last level pagetable
-    //    if it exists and can be accessed with the given permissions
-    //    Returns NULL otherwise
+    // ArenaMemory
+    //    Simulated physical memory: page `i` of the arena has physical address
+    //    `i * PAGESIZE`. Page 0 is never handed out, so a zero entry still
+    //    means "not present".
 
-    pub unsafe fn lookup_l1pagetable(
-        &self,
-        pagetable: *mut x86_64_pagetable, // Pointer to the page table
-        va: usize,                        // Virtual address
-        perm: i32,                        // Permissions
-    ) -> *mut x86_64_pagetable {
-        // Function body will go here
-        core::ptr::null_mut()
+    pub struct ArenaMemory {
+        pages: Vec<UnsafeCell<x86_64_pagetable>>,
+        next: usize,
+        pub freed: Vec<PhysAddr>,
+        pub flushed: Vec<(PhysAddr, usize)>,    // (page table, va)
+        pub shared: Vec<PhysAddr>,              // pages copy_on_write reports
     }
-    
-    // virtual_memory_lookup(pagetable, va)
-    //    Returns information about the mapping of the virtual address `va` in
-    //    `pagetable`. The information is returned as a `vamapping` object.
 
-    pub unsafe fn virtual_memory_lookup(
-        &self,
-        pagetable: *mut x86_64_pagetable, // Pointer to the page table
-        va: usize,                        // Virtual address
-    ) -> VAMapping {
-        // Function body will go here
-        VAMapping {
-            pn: 0,
-            pa: 0,
-            perm: 0,
+    impl ArenaMemory {
+        pub fn new(npages: usize) -> Self {
+            ArenaMemory {
+                pages: (0..npages).map(|_| UnsafeCell::new(x86_64_pagetable::new())).collect(),
+                next: 1,
+                freed: Vec::new(),
+                flushed: Vec::new(),
+                shared: Vec::new(),
+            }
+        }
+
+        pub fn allocated(&self) -> usize {
+            self.next - 1
+        }
+    }
+
+    impl PhysicalMemory for ArenaMemory {
+        fn pagetable(&self, pa: PhysAddr) -> *mut x86_64_pagetable {
+            assert!(pa.is_page_aligned() && pa.as_usize() != 0, "bad page table address {:#x}", pa.as_usize());
+            self.pages[pa.page_number().as_usize()].get()
+        }
+
+        fn alloc_pagetable(&mut self) -> Option<PhysAddr> {
+            if self.next == self.pages.len() {
+                return None;
+            }
+            self.next += 1;
+            PhysAddr::new((self.next - 1) * PAGE)
+        }
+
+        fn free_pagetable(&mut self, pa: PhysAddr) {
+            assert!(!self.freed.contains(&pa), "page table {:#x} freed twice", pa.as_usize());
+            self.freed.push(pa);
+        }
+
+        fn flush_page(&mut self, pagetable: PhysAddr, va: VirtAddr) {
+            self.flushed.push((pagetable, va.as_usize()));
+        }
+
+        fn copy_on_write(&mut self, pa: PhysAddr) -> bool {
+            self.shared.contains(&pa)
+        }
+    }
+
+    // new_vm(npages)
+    //    Page tables over an arena of `npages` pages, plus an empty L4 table.
+
+    pub fn new_vm(npages: usize) -> (KernelPageTables<ArenaMemory>, *mut x86_64_pagetable) {
+        let mut vm = KernelPageTables::with_memory(ArenaMemory::new(npages));
+        let l4 = vm.mem.alloc_pagetable().unwrap().as_ptr();
+        (vm, l4)
+    }
+
+    fn pa(addr: usize) -> PhysAddr {
+        PhysAddr::new(addr).unwrap()
+    }
+
+    fn lookup<M: PhysicalMemory>(vm: &KernelPageTables<M>, pt: *mut x86_64_pagetable, va: usize) -> (i32, usize, X86_64PageentryT) {
+        let vam = unsafe { vm.virtual_memory_lookup(pt, va) };
+        ({ vam.pn }, { vam.pa }, { vam.perm } as X86_64PageentryT)
+    }
+
+    // Xorshift64 generator, so the randomized tests need no dependencies
+    // and every failure can be replayed from its seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    #[test]
+    fn map_then_lookup_round_trips() {
+        let (mut vm, l4) = new_vm(8);
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, PAGE, PTE_PWU), 0);
+        }
+        assert_eq!(lookup(&vm, l4, 0x100000), (7, 0x7000, PTE_PWU));
+        assert_eq!(lookup(&vm, l4, 0x100123), (7, 0x7123, PTE_PWU));
+        // L4, L3, L2 and L1 tables were allocated on the way
+        assert_eq!(vm.mem.allocated(), 4);
+    }
+
+    #[test]
+    fn lookup_of_unmapped_address_fails() {
+        let (mut vm, l4) = new_vm(8);
+        assert_eq!(lookup(&vm, l4, 0x100000), (-1, usize::MAX, 0));
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, PAGE, PTE_PWU), 0);
+        }
+        assert_eq!(lookup(&vm, l4, 0x101000), (-1, usize::MAX, 0));
+        assert_eq!(lookup(&vm, l4, 0x40000000), (-1, usize::MAX, 0));
+    }
+
+    #[test]
+    fn map_range_maps_every_page() {
+        let (mut vm, l4) = new_vm(8);
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x1ff000, 0x20000, 3 * PAGE, PTE_PWU), 0);
+        }
+        // the range crosses a 2MB boundary, so it needs two L1 tables
+        assert_eq!(vm.mem.allocated(), 5);
+        for i in 0..3 {
+            assert_eq!(lookup(&vm, l4, 0x1ff000 + i * PAGE).1, 0x20000 + i * PAGE);
+        }
+    }
+
+    #[test]
+    fn map_fails_when_pagetables_run_out() {
+        let (mut vm, l4) = new_vm(3);
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, PAGE, PTE_PWU), -1);
+        }
+        assert_eq!(lookup(&vm, l4, 0x100000).0, -1);
+    }
+
+    #[test]
+    fn map_rejects_unaligned_arguments() {
+        let (mut vm, l4) = new_vm(8);
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100001, 0x7000, PAGE, PTE_PWU), -1);
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7001, PAGE, PTE_PWU), -1);
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, 100, PTE_PWU), -1);
+        }
+        assert_eq!(vm.mem.allocated(), 1);
+    }
+
+    #[test]
+    fn map_rejects_addresses_outside_the_address_space() {
+        let (mut vm, l4) = new_vm(8);
+        unsafe {
+            // the range runs from the lower half into the non-canonical hole
+            assert_eq!(vm.virtual_memory_map(l4, 0x7FFF_FFFF_F000, 0x7000, 2 * PAGE, PTE_PWU), -1);
+            assert_eq!(vm.virtual_memory_map(l4, 0x8000_0000_0000, 0x7000, PAGE, PTE_PWU), -1);
+            // a physical address wider than an entry can hold
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 1 << 52, PAGE, PTE_PWU), -1);
+            // flags that would land in the address bits
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, PAGE, 0x1000 | PTE_PWU), -1);
+            assert_eq!(vm.virtual_memory_map(l4, 0x7FFF_FFFF_F000, 0x7000, PAGE, PTE_PWU), 0);
+        }
+        assert_eq!(lookup(&vm, l4, 0x7FFF_FFFF_F123), (7, 0x7123, PTE_PWU));
+        assert_eq!(lookup(&vm, l4, 0x8000_0000_0000), (-1, usize::MAX, 0));
+        assert_eq!(lookup(&vm, l4, 0x100000).0, -1);
+    }
+
+    #[test]
+    fn map_without_present_bit_unmaps() {
+        let (mut vm, l4) = new_vm(8);
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, 2 * PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0, PAGE, 0), 0);
+            // unmapping where no page table exists is not an error
+            assert_eq!(vm.virtual_memory_map(l4, 0x40000000, 0, PAGE, 0), 0);
+        }
+        assert_eq!(lookup(&vm, l4, 0x100000).0, -1);
+        assert_eq!(lookup(&vm, l4, 0x101000).1, 0x8000);
+        assert_eq!(vm.mem.allocated(), 4);
+    }
+
+    #[test]
+    fn lookup_l1pagetable_shares_tables_within_2mb() {
+        let (mut vm, l4) = new_vm(8);
+        unsafe {
+            let a = vm.lookup_l1pagetable(l4, 0x200000, PTE_PWU as i32);
+            let b = vm.lookup_l1pagetable(l4, 0x3ff000, PTE_PWU as i32);
+            let c = vm.lookup_l1pagetable(l4, 0x400000, PTE_PWU as i32);
+            assert!(!a.is_null() && !c.is_null());
+            assert_eq!(a, b);
+            assert_ne!(a, c);
+            // lookups without PTE_P never allocate
+            assert!(vm.lookup_l1pagetable(l4, 0x80000000, 0).is_null());
+        }
+        assert_eq!(vm.mem.allocated(), 5);
+    }
+
+    #[test]
+    fn permissions_are_limited_by_every_level() {
+        let (mut vm, l4) = new_vm(8);
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x101000, 0x8000, PAGE, PTE_P | PTE_U), 0);
+            // take user access away in the L4 entry
+            let entry = &mut (*vm.mem.pagetable(pa(l4 as usize))).entry[0];
+            *entry = entry.with_flags(0, PTE_U);
+            assert!(vm.lookup_l1pagetable(l4, 0x100000, PTE_PWU as i32).is_null());
+            assert!(!vm.lookup_l1pagetable(l4, 0x100000, (PTE_P | PTE_W) as i32).is_null());
+        }
+        assert_eq!(lookup(&vm, l4, 0x100000).2, PTE_P | PTE_W);
+        assert_eq!(lookup(&vm, l4, 0x101000).2, PTE_P);
+    }
+
+    #[test]
+    fn protect_rewrites_permissions_of_mapped_pages() {
+        let (mut vm, l4) = new_vm(8);
+        let ro = PTE_P | PTE_U;
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, 2 * PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_protect(l4, 0x100000, 2 * PAGE, ro), 0);
+            assert_eq!(vm.virtual_memory_protect(l4, 0x100800, PAGE, ro), -1);
+            // the present bit comes from the old entry, not from `perm`
+            assert_eq!(vm.virtual_memory_protect(l4, 0x101000, PAGE, PTE_W), 0);
+            // unmapped pages stay unmapped, and no page table is allocated
+            assert_eq!(vm.virtual_memory_protect(l4, 0x40000000, 4 * PAGE, PTE_PWU), 0);
+        }
+        assert_eq!(lookup(&vm, l4, 0x100000), (7, 0x7000, ro));
+        assert_eq!(lookup(&vm, l4, 0x101000), (8, 0x8000, PTE_P | PTE_W));
+        assert_eq!(lookup(&vm, l4, 0x40000000).0, -1);
+        assert_eq!(vm.mem.allocated(), 4);
+    }
+
+    #[test]
+    fn protect_maps_shared_pages_copy_on_write() {
+        let (mut vm, l4) = new_vm(8);
+        let ro = PTE_P | PTE_U;
+        vm.mem.shared.push(pa(0x7000));
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, 2 * PAGE, ro), 0);
+            assert_eq!(vm.virtual_memory_protect(l4, 0x100000, 2 * PAGE, PTE_PWU), 0);
+        }
+        assert_eq!(lookup(&vm, l4, 0x100000), (7, 0x7000, ro | PTE_COW));
+        assert_eq!(lookup(&vm, l4, 0x101000), (8, 0x8000, PTE_PWU));
+
+        // once nothing else shares it, the page becomes plainly writable;
+        // taking write access away drops the copy-on-write bit too
+        vm.mem.shared.clear();
+        unsafe {
+            assert_eq!(vm.virtual_memory_protect(l4, 0x100000, PAGE, PTE_PWU), 0);
+            assert_eq!(lookup(&vm, l4, 0x100000), (7, 0x7000, PTE_PWU));
+            vm.mem.shared.push(pa(0x8000));
+            assert_eq!(vm.virtual_memory_protect(l4, 0x101000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_protect(l4, 0x101000, PAGE, ro), 0);
+        }
+        assert_eq!(lookup(&vm, l4, 0x101000), (8, 0x8000, ro));
+    }
+
+    #[test]
+    fn no_execute_bit_is_mapped_and_protected() {
+        let (mut vm, l4) = new_vm(8);
+        let entry = |vm: &KernelPageTables<ArenaMemory>, va: usize| unsafe {
+            let va = VirtAddr::new(va).unwrap();
+            let l1 = vm.mem.pagetable(vm.tables_on_path(l4, va).unwrap()[3]);
+            (*l1).entry[l1_index(va)]
+        };
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, 2 * PAGE, PTE_PWU | PTE_NX), 0);
+            assert_eq!(vm.virtual_memory_protect(l4, 0x101000, PAGE, PTE_P | PTE_U), 0);
+        }
+        assert_eq!(entry(&vm, 0x100000).flags(), PTE_PWU | PTE_NX);
+        assert_eq!(entry(&vm, 0x101000).flags(), PTE_P | PTE_U);
+        // lookups cannot report it, but the mappings can
+        assert_eq!(lookup(&vm, l4, 0x100000), (7, 0x7000, PTE_PWU));
+        let runs: Vec<_> = unsafe { vm.mappings(l4) }.map(|m| (m.va, m.perm)).collect();
+        assert_eq!(runs, [(0x100000..0x101000, PTE_PWU | PTE_NX), (0x101000..0x102000, PTE_P | PTE_U)]);
+    }
+
+    #[test]
+    fn unmap_releases_pages_and_empty_tables() {
+        let (mut vm, l4) = new_vm(8);
+        let mut released = [PhysAddr::default(); 4];
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, 2 * PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x200000, 0x9000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_unmap(l4, 0x100000, 4 * PAGE, &mut released[..3]), -1);
+            assert_eq!(vm.virtual_memory_unmap(l4, 0x100000, 4 * PAGE, &mut released), 2);
+        }
+        assert_eq!(released[..2], [pa(0x7000), pa(0x8000)]);
+        assert_eq!(lookup(&vm, l4, 0x100000).0, -1);
+        // the L1 table for 0x100000 emptied; the L3 and L2 tables still
+        // lead to the one for 0x200000
+        assert_eq!(vm.mem.freed, [pa(4 * PAGE)]);
+        assert_eq!(lookup(&vm, l4, 0x200000).1, 0x9000);
+
+        unsafe {
+            assert_eq!(vm.virtual_memory_unmap(l4, 0x200000, PAGE, &mut released), 1);
+            // nothing is mapped any more, and nothing is freed twice
+            assert_eq!(vm.virtual_memory_unmap(l4, 0x200000, PAGE, &mut released), 0);
+        }
+        assert_eq!(vm.mem.freed, [4, 5, 3, 2].map(|pn| pa(pn * PAGE)));
+        assert!(unsafe { (*vm.mem.pagetable(pa(l4 as usize))).entry.iter().all(|entry| entry.is_empty()) });
+    }
+
+    #[test]
+    fn unmap_clears_entries_that_are_not_present() {
+        let (mut vm, l4) = new_vm(8);
+        let mut released = [PhysAddr::default(); 1];
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, PAGE, PTE_PWU), 0);
+            // an entry the kernel keeps information in
+            let l1 = vm.lookup_l1pagetable(l4, 0x100000, 0);
+            let va = VirtAddr::new(0x101000).unwrap();
+            (*vm.mem.pagetable(pa(l1 as usize))).entry[l1_index(va)] = PageTableEntry::from_bits(0x5000);
+            assert_eq!(vm.virtual_memory_unmap(l4, 0x101000, PAGE, &mut released), 0);
+            assert!((*vm.mem.pagetable(pa(l1 as usize))).entry[l1_index(va)].is_empty());
+        }
+        assert!(vm.mem.freed.is_empty());
+    }
+
+    #[test]
+    fn unmap_keeps_preset_tables() {
+        let (mut vm, l4) = new_vm(8);
+        vm.preset_end = 0x200000;
+        let mut released = [PhysAddr::default(); 1];
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_unmap(l4, 0x100000, PAGE, &mut released), 1);
+        }
+        // the emptied tables stay linked, so mapping again allocates nothing
+        assert!(vm.mem.freed.is_empty());
+        let allocated = vm.mem.allocated();
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x101000, 0x7000, PAGE, PTE_PWU), 0);
+        }
+        assert_eq!(vm.mem.allocated(), allocated);
+        assert_eq!(lookup(&vm, l4, 0x101000).1, 0x7000);
+    }
+
+    #[test]
+    fn pagetable_free_frees_every_table() {
+        let (mut vm, l4) = new_vm(16);
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x40000000, 0x8000, PAGE, PTE_PWU), 0);
+            // the L4 table, one L3, two L2s and two L1s
+            assert_eq!(vm.pagetable_free(l4), 6);
+        }
+        let mut freed = vm.mem.freed.clone();
+        freed.sort();
+        assert_eq!(freed, (1..=6).map(|pn| pa(pn * PAGE)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn copy_pagetable_follows_the_policy() {
+        let (mut vm, l4) = new_vm(32);
+        let regions = [
+            CopyRegion { start: 0x100000, end: 0x200000, action: CopyAction::Copy },
+            CopyRegion { start: 0x200000, end: 0x300000, action: CopyAction::Cow },
+        ];
+        let policy = CopyPolicy { regions: &regions, default: CopyAction::Share };
+        let mut allocated = [PhysAddr::default(); 16];
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x40000, 0x17000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x18000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x200000, 0x19000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x201000, 0x1a000, PAGE, PTE_P | PTE_U), 0);
+            (*vm.mem.pagetable(pa(0x18000))).entry[3] = PageTableEntry::from_bits(0x1234);
+        }
+        let allocated_before = vm.mem.allocated();
+        let (copy, n) = unsafe { vm.copy_pagetable(l4, &policy, &mut allocated) }.unwrap();
+
+        // L4, L3, L2, two L1s and the copied page
+        assert_eq!(n, 6);
+        assert_eq!(vm.mem.allocated(), allocated_before + 6);
+        assert_eq!(allocated[0], pa(copy as usize));
+        assert_eq!(lookup(&vm, copy, 0x40000), (23, 0x17000, PTE_PWU));
+        let (_, page, perm) = lookup(&vm, copy, 0x100000);
+        assert!(page != 0x18000 && allocated[..n].contains(&pa(page)));
+        assert_eq!(perm, PTE_PWU);
+        assert_eq!(unsafe { (*vm.mem.pagetable(pa(page))).entry[3] }, PageTableEntry::from_bits(0x1234));
+
+        // writable pages become copy-on-write in both tables
+        for pt in [l4, copy] {
+            assert_eq!(lookup(&vm, pt, 0x200000), (25, 0x19000, PTE_P | PTE_U | PTE_COW));
+            assert_eq!(lookup(&vm, pt, 0x201000), (26, 0x1a000, PTE_P | PTE_U));
+        }
+        assert_eq!(lookup(&vm, l4, 0x100000).1, 0x18000);
+    }
+
+    #[test]
+    fn changed_present_entries_are_flushed() {
+        let (mut vm, l4) = new_vm(16);
+        let ro = PTE_P | PTE_U;
+        let mut released = [PhysAddr::default(); 4];
+        let root = pa(l4 as usize);
+        unsafe {
+            // new mappings replace nothing the TLB could hold
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x7000, 3 * PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x200000, 0, PAGE, 0), 0);
+            assert!(vm.mem.flushed.is_empty());
+
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0xa000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_protect(l4, 0x101000, 2 * PAGE, ro), 0);
+            // unchanged entries need no flush
+            assert_eq!(vm.virtual_memory_protect(l4, 0x101000, PAGE, ro), 0);
+            assert_eq!(vm.virtual_memory_unmap(l4, 0x102000, 2 * PAGE, &mut released), 1);
+        }
+        assert_eq!(vm.mem.flushed, [(root, 0x100000), (root, 0x101000), (root, 0x102000), (root, 0x102000)]);
+
+        // forking marks the source's writable pages copy-on-write
+        vm.mem.flushed.clear();
+        let policy = CopyPolicy { regions: &[], default: CopyAction::Cow };
+        let mut allocated = [PhysAddr::default(); 8];
+        let (copy, _) = unsafe { vm.copy_pagetable(l4, &policy, &mut allocated) }.unwrap();
+        assert_eq!(vm.mem.flushed, [(root, 0x100000)]);
+        assert!(vm.mem.flushed.iter().all(|&(pt, _)| pt != pa(copy as usize)));
+    }
+
+    #[test]
+    fn failed_copy_frees_its_pages_and_leaves_source_alone() {
+        let (mut vm, l4) = new_vm(32);
+        let policy = CopyPolicy { regions: &[], default: CopyAction::Cow };
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x100000, 0x18000, PAGE, PTE_PWU), 0);
+            // the copy needs four page tables
+            let mut allocated = [PhysAddr::default(); 3];
+            assert!(vm.copy_pagetable(l4, &policy, &mut allocated).is_none());
+        }
+        let mut freed = vm.mem.freed.clone();
+        freed.sort();
+        assert_eq!(freed, (5..=7).map(|pn| pa(pn * PAGE)).collect::<Vec<_>>());
+        assert_eq!(lookup(&vm, l4, 0x100000), (24, 0x18000, PTE_PWU));
+    }
+
+    #[test]
+    fn mappings_merge_contiguous_pages() {
+        let (mut vm, l4) = new_vm(32);
+        let ro = PTE_P | PTE_U;
+        unsafe {
+            assert_eq!(vm.virtual_memory_map(l4, 0x1ff000, 0x17000, 3 * PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x202000, 0x1b000, PAGE, PTE_PWU), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0x203000, 0x1c000, PAGE, ro), 0);
+            assert_eq!(vm.virtual_memory_map(l4, 0xFFFF_8000_0000_0000, 0x1d000, PAGE, PTE_PWU), 0);
+            // a 2MB page at 0x40000000, in place of the L1 table mapping it
+            assert_eq!(vm.virtual_memory_map(l4, 0x40000000, 0x1e000, PAGE, PTE_PWU), 0);
+            let va = VirtAddr::new(0x40000000).unwrap();
+            let l3 = (*vm.mem.pagetable(pa(l4 as usize))).entry[l4_index(va)].addr();
+            let l2 = (*vm.mem.pagetable(l3)).entry[l3_index(va)].addr();
+            (*vm.mem.pagetable(l2)).entry[l2_index(va)] =
+                PageTableEntry::new(pa(0x200000), PTE_P | PTE_W | PTE_PS).unwrap();
+        }
+
+        let runs: Vec<_> = unsafe { vm.mappings(l4) }.map(|m| (m.va, m.pa.as_usize(), m.perm)).collect();
+        let pwu = PTE_PWU as X86_64PageentryT;
+        assert_eq!(runs, [
+            // one run across the L1 tables, ending where the physical pages stop following
+            (0x1ff000..0x202000, 0x17000, pwu),
+            (0x202000..0x203000, 0x1b000, pwu),
+            (0x203000..0x204000, 0x1c000, ro as X86_64PageentryT),
+            (0x40000000..0x40200000, 0x200000, PTE_P | PTE_W),
+            (0xFFFF_8000_0000_0000..0xFFFF_8000_0000_1000, 0x1d000, pwu),
+        ]);
+        let empty = vm.mem.alloc_pagetable().unwrap().as_ptr();
+        assert_eq!(unsafe { vm.mappings(empty) }.count(), 0);
+    }
+
+    // Apply random map and unmap operations and compare every lookup with a
+    // model of the expected mappings.
+    #[test]
+    fn random_maps_match_model() {
+        for seed in 1..=64 {
+            let mut rng = Rng(seed * 0x9E3779B97F4A7C15);
+            let (mut vm, l4) = new_vm(256);
+            let mut model = BTreeMap::new();
+
+            for _ in 0..200 {
+                // cluster addresses so that page tables get shared
+                let region = rng.below(4) as usize * 0x40000000 + rng.below(4) as usize * 0x200000;
+                let va = region + rng.below(512) as usize * PAGE;
+                let npages = 1 + rng.below(4) as usize;
+                if rng.below(4) == 0 {
+                    unsafe {
+                        assert_eq!(vm.virtual_memory_map(l4, va, 0, npages * PAGE, 0), 0);
+                    }
+                    for i in 0..npages {
+                        model.remove(&(va + i * PAGE));
+                    }
+                } else {
+                    let pa = (1 + rng.below(0xFFFF) as usize) * PAGE;
+                    let perm = PTE_P | (rng.below(4) * PTE_W);
+                    unsafe {
+                        assert_eq!(vm.virtual_memory_map(l4, va, pa, npages * PAGE, perm), 0, "seed {}", seed);
+                    }
+                    for i in 0..npages {
+                        model.insert(va + i * PAGE, (pa + i * PAGE, perm));
+                    }
+                }
+            }
+
+            for (&va, &(pa, perm)) in model.iter() {
+                let offset = rng.below(PAGE as u64) as usize;
+                assert_eq!(lookup(&vm, l4, va + offset), ((pa / PAGE) as i32, pa + offset, perm), "seed {} va {:#x}", seed, va);
+            }
+            // the runs cover exactly the model's pages
+            let mut pages = BTreeMap::new();
+            for run in unsafe { vm.mappings(l4) } {
+                for va in run.va.clone().step_by(PAGE) {
+                    pages.insert(va, (run.pa.as_usize() + va - run.va.start, run.perm));
+                }
+            }
+            assert_eq!(pages, model, "seed {}", seed);
+            for _ in 0..200 {
+                let va = rng.below(4) as usize * 0x40000000 + rng.below(2048) as usize * PAGE;
+                if !model.contains_key(&va) {
+                    assert_eq!(lookup(&vm, l4, va).0, -1, "seed {} va {:#x}", seed, va);
+                }
+            }
         }
     }
 }
@@ -80,7 +80,7 @@ void memshow_virtual_animate(void);
 //    Initialize the hardware and processes and start running. The `command`
 //    string is an optional string passed from the boot loader.
 
-static void process_setup(pid_t pid, int program_number);
+static void process_setup(pid_t pid, const char* name);
 
 void kernel(const char* command) {
     hardware_init();
@@ -96,19 +96,19 @@ void kernel(const char* command) {
         processes[i].p_state = P_FREE;
     }
 
-    if (command && strcmp(command, "fork") == 0) {
-        process_setup(1, 4);
-    } else if (command && strcmp(command, "forkexit") == 0) {
-        process_setup(1, 5);
-    } else if (command && strcmp(command, "test") == 0) {
-        process_setup(1, 6);
-    } else if (command && strcmp(command, "test2") == 0) {
+    if (command && strcmp(command, "test2") == 0) {
         for (pid_t i = 1; i <= 2; ++i) {
-            process_setup(i, 6);
+            process_setup(i, "test");
         }
+    } else if (command && program_lookup(command, strlen(command)) >= 0) {
+        // any other program by name, such as "fork" or "test"
+        process_setup(1, command);
     } else {
+        static const char* const allocators[] = {
+            "allocator", "allocator2", "allocator3", "allocator4"
+        };
         for (pid_t i = 1; i <= 4; ++i) {
-            process_setup(i, i - 1);
+            process_setup(i, allocators[i - 1]);
         }
     }
 
@@ -117,12 +117,15 @@ void kernel(const char* command) {
 }
 
 
-// process_setup(pid, program_number)
-//    Load application program `program_number` as process number `pid`.
+// process_setup(pid, name)
+//    Load the application program called `name` as process number `pid`.
 //    This loads the application's code and data into memory, sets its
 //    %rip and %rsp, gives it a stack page, and marks it as runnable.
 
-void process_setup(pid_t pid, int program_number) {
+void process_setup(pid_t pid, const char* name) {
+    int program_number = program_lookup(name, strlen(name));
+    assert(program_number >= 0);
+
     process_init(&processes[pid], 0);
     processes[pid].p_pagetable = kernel_pagetable;
     ++pageinfo[PAGENUMBER(kernel_pagetable)].refcount; //increase refcount since kernel_pagetable was used
@@ -53,6 +53,14 @@ typedef struct elf_section {
     uint64_t s_entsize;
 } elf_section;
 
+// Values for elf_header::e_elf (the identification after the magic number)
+#define ELF_CLASS_64            2       // e_elf[0]: 64-bit objects
+#define ELF_DATA_LSB            1       // e_elf[1]: little endian
+
+// Values for elf_header::e_type and elf_header::e_machine
+#define ELF_TYPE_EXEC           2
+#define ELF_MACHINE_X86_64      62
+
 // Values for elf_program::p_type
 #define ELF_PTYPE_LOAD          1
 
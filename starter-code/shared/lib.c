@@ -40,6 +40,22 @@ void* memset(void* v, int c, size_t n) {
     return v;
 }
 
+int memcmp(const void* a, const void* b, size_t n) {
+    const unsigned char* sa = (const unsigned char*) a;
+    const unsigned char* sb = (const unsigned char*) b;
+    for (; n > 0; ++sa, ++sb, --n) {
+        if (*sa != *sb) {
+            return (*sa > *sb) - (*sa < *sb);
+        }
+    }
+    return 0;
+}
+
+// bcmp is memcmp for callers that only test equality (Rust emits it)
+int bcmp(const void* a, const void* b, size_t n) {
+    return memcmp(a, b, n);
+}
+
 size_t strlen(const char* s) {
     size_t n;
     for (n = 0; *s != '\0'; ++s) {
@@ -39,6 +39,8 @@ typedef int pid_t;                    // process IDs
 void* memcpy(void* dst, const void* src, size_t n);
 void* memmove(void* dst, const void* src, size_t n);
 void* memset(void* s, int c, size_t n);
+int memcmp(const void* a, const void* b, size_t n);
+int bcmp(const void* a, const void* b, size_t n);
 size_t strlen(const char* s);
 size_t strnlen(const char* s, size_t maxlen);
 char* strcpy(char* dst, const char* src);
@@ -106,6 +108,25 @@ void c_panic(const char* format, ...) __attribute__((noinline, noreturn));
 #define INT_SYS_MEM_TOG         (INT_SYS + 8)
 #define INT_SYS_BRK             (INT_SYS + 9)
 #define INT_SYS_SBRK            (INT_SYS + 10)
+#define INT_SYS_TEST_EXIT       (INT_SYS + 11)
+#define INT_SYS_SETRLIMIT       (INT_SYS + 12)
+#define INT_SYS_MEMSTATS        (INT_SYS + 13)
+#define INT_SYS_MMAP            (INT_SYS + 14)
+#define INT_SYS_MUNMAP          (INT_SYS + 15)
+#define INT_SYS_MPROTECT        (INT_SYS + 16)
+#define INT_SYS_SHM_CREATE      (INT_SYS + 17)
+#define INT_SYS_SHM_ATTACH      (INT_SYS + 18)
+#define INT_SYS_SHM_DETACH      (INT_SYS + 19)
+#define INT_SYS_MAPS            (INT_SYS + 20)
+
+// System call error numbers: a failing system call returns `-ENOMEM` etc.
+
+#define EPERM                   1       // not allowed
+#define ENOEXEC                 8       // not a valid program
+#define EAGAIN                  11      // no free process slot
+#define ENOMEM                  12      // out of memory
+#define EFAULT                  14      // bad user address
+#define EINVAL                  22      // invalid argument
 
 // Console printing
 
@@ -169,6 +190,51 @@ typedef struct vamapping {
 
 } vamapping;
 
-#define TEST_PASS()	panic(__FILE__ ": TEST PASS")
+// struct rlimits object
+// per-process resource limits, set with `sys_setrlimit`; a process starts
+// with its parent's limits
+typedef struct rlimits {
+    size_t resident_pages;  // RLIMIT_RSS: physical pages owned
+    size_t heap_bytes;      // RLIMIT_HEAP: size of the heap (see sys_brk)
+    size_t children;        // RLIMIT_NPROC: live child processes
+    size_t stack_bytes;     // RLIMIT_STACK: size of the stack
+} rlimits;
+
+#define RLIMIT_RSS              0
+#define RLIMIT_HEAP             1
+#define RLIMIT_NPROC            2
+#define RLIMIT_STACK            3
+#define RLIM_INFINITY           ((size_t) -1)
+
+// struct memstats object
+// a process's memory usage and limits, as returned by `sys_memstats`
+typedef struct memstats {
+    size_t resident_pages;  // physical pages owned
+    size_t heap_bytes;      // size of the heap
+    size_t children;        // live child processes
+    rlimits limits;         // the process's limits
+    size_t free_pages;      // free physical pages in the machine
+    size_t total_pages;     // physical pages in the machine
+    size_t swap_ins;        // pages read back from swap (machine-wide)
+    size_t swap_outs;       // pages written to swap (machine-wide)
+} memstats;
+
+// sys_mmap and sys_mprotect protections, sys_mmap flags
+#define PROT_NONE               0x0     // pages may not be accessed
+#define PROT_READ               0x1     // pages may be read
+#define PROT_WRITE              0x2     // pages may be written (and read)
+#define PROT_EXEC               0x4     // pages may be executed
+#define MAP_PRIVATE             0x02    // changes are private to the process
+#define MAP_ANONYMOUS           0x20    // memory is zero-filled, not a file
+#define MAP_FAILED              ((void*) -1)
+
+// sys_shm_create key that always makes a new segment
+#define SHM_PRIVATE             0
+
+// TEST_PASS(), TEST_FAIL(msg)
+//    End a test program (see `sys_test_exit` in process.h). Under
+//    `make check` QEMU exits with a status that tells pass from fail.
+#define TEST_PASS()	sys_test_exit(0, __FILE__ ": TEST PASS")
+#define TEST_FAIL(msg)	sys_test_exit(1, __FILE__ ": TEST FAIL: " msg)
 
 #endif /* !WEENSYOS_LIB_H */
@@ -51,6 +51,8 @@ static inline int pageindex(uintptr_t addr, int level) {
 #define PTE_A   ((x86_64_pageentry_t) 32)   // entry was Accessed (read/written)
 #define PTE_D   ((x86_64_pageentry_t) 64)   // entry was Dirtied (written)
 #define PTE_PS  ((x86_64_pageentry_t) 128)  // entry has a large Page Size
+#define PTE_NX  ((x86_64_pageentry_t) 1 << 63)  // entry is Not eXecutable
+                                            //   (needs EFER.NXE)
 // - There are other flags too!
 
 // Page fault error flags
@@ -61,6 +63,8 @@ static inline int pageindex(uintptr_t addr, int level) {
 #define PFERR_WRITE     0x2             // Fault happened on a write
 #define PFERR_USER      0x4             // Fault happened in an application
                                         //   (user mode) (rather than kernel)
+#define PFERR_FETCH     0x10            // Fault happened on an instruction
+                                        //   fetch (from a PTE_NX page)
 
 
 // struct x86_64_registers
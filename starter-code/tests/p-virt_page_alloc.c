@@ -18,14 +18,14 @@ void process_main(void) {
     for(int i = 0 ; i < N ; i++){
         int x = sys_page_alloc(heap_top);
         if(x != 0)
-            c_panic("Error, sys_page_alloc failed!");
+            TEST_FAIL("Error, sys_page_alloc failed!");
         // lets make sure we write to the page and are able to read from it
         *heap_top = p;
         assert(*heap_top == p);
         sys_mapping((uintptr_t)heap_top, &pmap);
 
         if(pmap.pa == (uintptr_t)heap_top)
-            c_panic("Error, sys page alloc not virtualized!");
+            TEST_FAIL("Error, sys page alloc not virtualized!");
 
         heap_top += PAGESIZE;
     }
@@ -42,7 +42,10 @@ static inline void sys_yield(void) {
 // sys_page_alloc(addr)
 //    Allocate a page of memory at address `addr` and allow process to
 //    write to it. `Addr` must be page-aligned (i.e., a multiple of
-//    PAGESIZE == 4096). Returns 0 on success and -1 on failure.
+//    PAGESIZE == 4096). The page is only reserved: the kernel backs it
+//    with a zeroed physical page when it is first touched, and kills the
+//    process if no page is available then. Returns 0 on success, -ENOMEM
+//    if the process is at its RLIMIT_RSS, and -1 on other failures.
 static inline int sys_page_alloc(void* addr) {
     int result;
     asm volatile ("int %1" : "=a" (result)
@@ -53,7 +56,10 @@ static inline int sys_page_alloc(void* addr) {
 
 // sys_fork()
 //    Fork the current process. On success, return the child's process ID to
-//    the parent, and return 0 to the child. On failure, return -1.
+//    the parent, and return 0 to the child. On failure, return -EAGAIN if no
+//    process slot is free or the parent is at its RLIMIT_NPROC, and -ENOMEM
+//    if the child would exceed its RLIMIT_RSS or memory is exhausted. The
+//    child inherits the parent's limits.
 static inline pid_t sys_fork(void) {
     pid_t result;
     asm volatile ("int %1" : "=a" (result)
@@ -81,6 +87,21 @@ static inline pid_t __attribute__((noreturn)) sys_panic(const char* msg) {
  loop: goto loop;
 }
 
+// sys_test_exit(status, msg)
+//    Report the result of a test program and stop the machine. `status` is
+//    0 if the test passed and non-zero if it failed; `msg` is printed to the
+//    console and to `log.txt`. When QEMU runs with the isa-debug-exit device
+//    (see `make check`) it exits with status 33 on a pass and 35 on a
+//    failure; otherwise the kernel waits for Control-C. Does not return.
+static inline void __attribute__((noreturn)) sys_test_exit(int status,
+                                                           const char* msg) {
+    asm volatile ("int %0" : /* no result */
+                  : "i" (INT_SYS_TEST_EXIT), "D" /* %rdi */ (status),
+                    "S" /* %rsi */ (msg)
+                  : "cc", "memory");
+ loop: goto loop;
+}
+
 // sys_mapping
 // looks up the virtual memory mapping for addr for the current process 
 // and stores it inside map. [map, sizeof(vampping)) address should be 
@@ -108,10 +129,11 @@ static inline void sys_mem_tog(pid_t p) {
 //     increasing the program break has the effect of allocating memory to the process
 //     decreasing the break deallocates memory
 //     on success, returns 0
-//     on failure, return -1
+//     on failure, returns -EINVAL (addr is outside the heap area) or -ENOMEM
+//     (the heap would exceed RLIMIT_HEAP or run into memory in use)
+//     like sys_page_alloc, new heap pages are backed when first touched
 //     brk cannot exceed MEMSIZE_VIRTUAL, and cannot be lower than data segment (loaded
 //     by the loader)
-//     Not expected to be implemented
 
 static inline int sys_brk(const void* addr) {
     static int result;
@@ -128,7 +150,6 @@ static inline int sys_brk(const void* addr) {
 //     On success, sbrk() returns the previous program break
 //     (If the break was increased, then this value is a pointer to the start of the newly allocated memory)
 //      On error, (void *) -1 is returned
-//     Not expected to be implemented
 static inline void * sys_sbrk(const intptr_t increment) {
     static void * result;
     asm volatile ("int %1" :  "=a" (result)
@@ -137,6 +158,145 @@ static inline void * sys_sbrk(const intptr_t increment) {
     return result;
 }
 
+// sys_setrlimit(pid, resource, limit)
+//    Set the RLIMIT_* `resource` limit of process `pid` (0 means the
+//    current process) to `limit`, or to no limit if `limit` is
+//    RLIM_INFINITY. A process may lower its own limits and set its
+//    children's, but never above its own. Limits are checked when memory or
+//    children are next requested; nothing already held is taken away.
+//    Returns 0 on success, -EINVAL for an unknown resource or pid, and
+//    -EPERM otherwise.
+static inline int sys_setrlimit(pid_t pid, int resource, size_t limit) {
+    int result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_SETRLIMIT), "D" /* %rdi */ (pid),
+                    "S" /* %rsi */ (resource), "d" /* %rdx */ (limit)
+                  : "cc", "memory");
+    return result;
+}
+
+// sys_memstats(stats)
+//    Fill in `*stats` with the current process's memory usage and limits.
+//    Returns 0 on success and -EINVAL if `stats` is not writable.
+static inline int sys_memstats(memstats* stats) {
+    int result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_MEMSTATS), "D" /* %rdi */ (stats)
+                  : "cc", "memory");
+    return result;
+}
+
+// sys_mmap(addr, len, prot, flags)
+//    Map `len` bytes (rounded up to whole pages) of anonymous,
+//    zero-filled memory with protection `prot` (PROT_NONE, or PROT_READ,
+//    PROT_WRITE and/or PROT_EXEC; only PROT_EXEC memory may be run as
+//    code). `flags` must be MAP_PRIVATE | MAP_ANONYMOUS. The
+//    mapping goes at `addr` if that is page-aligned and free, and otherwise
+//    wherever the kernel finds room between the heap and the stack. Pages
+//    are backed when first touched. Returns the mapping's address, or
+//    MAP_FAILED if the arguments are invalid or there is no room.
+static inline void* sys_mmap(void* addr, size_t len, int prot, int flags) {
+    void* result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_MMAP), "D" /* %rdi */ (addr),
+                    "S" /* %rsi */ (len), "d" /* %rdx */ (prot),
+                    "c" /* %rcx */ (flags)
+                  : "cc", "memory");
+    return result;
+}
+
+// sys_munmap(addr, len)
+//    Unmap the pages of mapped memory in `[addr, addr + len)`, freeing
+//    them once no other process shares them. `addr` must be page-aligned.
+//    Pages that were never mapped with sys_mmap, sys_page_alloc or sys_brk
+//    are left alone. Returns 0 on success, -EINVAL for a bad range, and
+//    -ENOMEM if splitting a mapping needs more areas than the process may
+//    have.
+static inline int sys_munmap(void* addr, size_t len) {
+    int result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_MUNMAP), "D" /* %rdi */ (addr),
+                    "S" /* %rsi */ (len)
+                  : "cc", "memory");
+    return result;
+}
+
+// sys_mprotect(addr, len, prot)
+//    Change the protection of the pages in `[addr, addr + len)` to `prot`
+//    (PROT_NONE, or PROT_READ, PROT_WRITE and/or PROT_EXEC). `addr` must be
+//    page-aligned and every page must be the process's own: mapped with
+//    sys_mmap, sys_page_alloc or sys_brk, or loaded with the program.
+//    Accessing a page in a way its protection does not allow kills the
+//    process. Returns 0 on success, -EINVAL for bad arguments, -EPERM if a
+//    page is not the process's, and -ENOMEM if a mapping cannot be split.
+static inline int sys_mprotect(void* addr, size_t len, int prot) {
+    int result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_MPROTECT), "D" /* %rdi */ (addr),
+                    "S" /* %rsi */ (len), "d" /* %rdx */ (prot)
+                  : "cc", "memory");
+    return result;
+}
+
+// sys_shm_create(key, size)
+//    Return the id of the shared memory segment named `key`, creating one
+//    of `size` bytes (rounded up to whole pages, at most 16 pages) if there
+//    is none. SHM_PRIVATE always creates a new segment. Returns the id on
+//    success, -EINVAL if `size` is 0 or too large or the existing segment is
+//    smaller, and -ENOMEM if too many segments exist.
+static inline int sys_shm_create(int key, size_t size) {
+    int result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_SHM_CREATE), "D" /* %rdi */ (key),
+                    "S" /* %rsi */ (size)
+                  : "cc", "memory");
+    return result;
+}
+
+// sys_shm_attach(id, addr)
+//    Map shared memory segment `id` into this process, readable and
+//    writable. Every process that attaches the segment sees the same
+//    memory, which starts out zeroed. The segment goes at `addr` if that is
+//    page-aligned and free, and otherwise where sys_mmap would put it.
+//    Children created by sys_fork share their parent's segments. Returns
+//    the segment's address, or MAP_FAILED if `id` is invalid, the segment
+//    is already attached, or there is no room or memory.
+static inline void* sys_shm_attach(int id, void* addr) {
+    void* result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_SHM_ATTACH), "D" /* %rdi */ (id),
+                    "S" /* %rsi */ (addr)
+                  : "cc", "memory");
+    return result;
+}
+
+// sys_shm_detach(addr)
+//    Unmap the shared memory segment attached at `addr`. Exiting detaches
+//    every segment; a segment and its memory go away when the last process
+//    detaches it. (sys_munmap refuses to unmap attached segments.) Returns
+//    0 on success and -EINVAL if no segment is attached at `addr`.
+static inline int sys_shm_detach(void* addr) {
+    int result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_SHM_DETACH), "D" /* %rdi */ (addr)
+                  : "cc", "memory");
+    return result;
+}
+
+// sys_maps(pid)
+//    Log the memory mapped by process `pid` (0 means the current process)
+//    to `log.txt`, one line per run of pages in the style of Linux's
+//    /proc/PID/maps: virtual address range, permissions, first physical
+//    address, and [program], [heap], [stack], [shm], [anon] or [kernel].
+//    Returns 0 on success and -EINVAL if there is no such process.
+static inline int sys_maps(pid_t pid) {
+    int result;
+    asm volatile ("int %1" : "=a" (result)
+                  : "i" (INT_SYS_MAPS), "D" /* %rdi */ (pid)
+                  : "cc", "memory");
+    return result;
+}
+
 // OTHER HELPER FUNCTIONS
 
 // app_printf(format, ...)